@@ -3,15 +3,15 @@ extern crate log;
 use std::ops::Add;
 use std::time::{Duration, Instant};
 
-use lld_common::{get_current_time, tcp_request_leasing, Environment, LldResult};
+use lld_common::{get_current_time, Environment, LldResult, TcpConnection};
 use tokio::time::timeout;
 use tokio::{spawn, task::JoinHandle};
 
-async fn request(environment: &Environment, application_id: u64, instance_id: u64) -> LoopResult {
+async fn request(connection: &TcpConnection, application_id: u64, instance_id: u64) -> LoopResult {
     let instant = Instant::now();
     let result = timeout(
         Duration::from_secs(1),
-        tcp_request_leasing(environment, application_id, instance_id, 5000),
+        connection.request_leasing(&application_id.to_string(), &instance_id.to_string(), 5000),
     )
     .await;
     let time = instant.elapsed().as_millis();
@@ -33,7 +33,7 @@ async fn request(environment: &Environment, application_id: u64, instance_id: u6
         }
     };
 
-    if result.is_some() {
+    if result.grant().is_some() {
         LoopResult::new_granted(time)
     } else {
         LoopResult::new_rejected(time)
@@ -53,6 +53,7 @@ pub struct LoopResult {
 }
 
 impl LoopResult {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             granted_time: 0,
@@ -143,9 +144,27 @@ async fn loop_requests(
     stop_at: u64,
 ) -> LldResult<LoopResult> {
     let mut result = LoopResult::new();
+    let mut connection: Option<TcpConnection> = None;
 
     while get_current_time() < stop_at {
-        result = result + request(environment, application_id, instance_id).await;
+        // Reuse the connection until it fails, then reconnect
+        let current = match connection.take().filter(|c| !c.is_closed()) {
+            Some(current) => current,
+            None => match TcpConnection::connect(environment).await {
+                Ok(current) => current,
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    result = result + LoopResult::new_error(0);
+                    continue;
+                }
+            },
+        };
+
+        let step = request(&current, application_id, instance_id).await;
+        if step.error_count == 0 && step.timeout_count == 0 {
+            connection = Some(current);
+        }
+        result = result + step;
     }
 
     Ok(result)
@@ -190,6 +209,11 @@ async fn main() -> LldResult<()> {
         http_request_uri: "http://localhost:3030/request".to_string(),
         tcp_request_uri: "127.0.0.1:3040".to_string(),
         ssl_cert_file: None,
+        ssl_client_cert_file: None,
+        ssl_client_key_file: None,
+        accept_invalid_hostnames: false,
+        namespace: None,
+        api_key: None,
     };
 
     let stop_at = get_current_time() + 5000;
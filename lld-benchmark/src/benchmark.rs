@@ -1,15 +1,15 @@
 use std::ops::Add;
 use std::time::{Duration, Instant};
 
-use lld_common::{get_current_time, tcp_request_leasing, Environment, LldResult};
+use lld_common::{get_current_time, Environment, LldResult, TcpConnection};
 use tokio::time::timeout;
 use tokio::{spawn, task::JoinHandle};
 
-async fn request(environment: &Environment, application_id: u64, instance_id: u64) -> LoopResult {
+async fn request(connection: &TcpConnection, application_id: u64, instance_id: u64) -> LoopResult {
     let instant = Instant::now();
     let result = timeout(
         Duration::from_secs(1),
-        tcp_request_leasing(environment, application_id, instance_id, 5000),
+        connection.request_leasing(&application_id.to_string(), &instance_id.to_string(), 5000),
     )
     .await;
     let time = instant.elapsed().as_millis();
@@ -31,7 +31,7 @@ async fn request(environment: &Environment, application_id: u64, instance_id: u6
         }
     };
 
-    if result.is_some() {
+    if result.grant().is_some() {
         LoopResult::new_granted(time)
     } else {
         LoopResult::new_rejected(time)
@@ -51,6 +51,7 @@ pub struct LoopResult {
 }
 
 impl LoopResult {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
             granted_time: 0,
@@ -141,9 +142,27 @@ async fn loop_requests(
     stop_at: u64,
 ) -> LldResult<LoopResult> {
     let mut result = LoopResult::new();
+    let mut connection: Option<TcpConnection> = None;
 
     while get_current_time() < stop_at {
-        result = result + request(environment, application_id, instance_id).await;
+        // Reuse the connection until it fails, then reconnect
+        let current = match connection.take().filter(|c| !c.is_closed()) {
+            Some(current) => current,
+            None => match TcpConnection::connect(environment).await {
+                Ok(current) => current,
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    result = result + LoopResult::new_error(0);
+                    continue;
+                }
+            },
+        };
+
+        let step = request(&current, application_id, instance_id).await;
+        if step.error_count == 0 && step.timeout_count == 0 {
+            connection = Some(current);
+        }
+        result = result + step;
     }
 
     Ok(result)
@@ -28,9 +28,9 @@ arg_enum! {
     }
 }
 
-impl Into<DockerComposeFile> for LldContainer {
-    fn into(self) -> DockerComposeFile {
-        match self {
+impl From<LldContainer> for DockerComposeFile {
+    fn from(val: LldContainer) -> Self {
+        match val {
             LldContainer::NativeSqliteNaive => DockerComposeFile::NativeSqliteNaive,
             LldContainer::NativeSqliteCaching => DockerComposeFile::NativeSqliteCaching,
             LldContainer::NativeSqliteBatching => DockerComposeFile::NativeSqliteBatching,
@@ -150,7 +150,7 @@ async fn main() -> LldResult<()> {
 
     let m = App::new(crate_name!())
         .version(crate_version!())
-        .author(crate_authors!("\n"))
+        .author(env!("CARGO_PKG_AUTHORS"))
         .arg(
             Arg::with_name("http_uri")
                 .long("http_uri")
@@ -225,6 +225,11 @@ async fn main() -> LldResult<()> {
             http_request_uri: http_uri.to_owned(),
             tcp_request_uri: tcp_uri.to_owned(),
             ssl_cert_file: ssl_cert_file.map(str::to_string),
+            ssl_client_cert_file: None,
+            ssl_client_key_file: None,
+            accept_invalid_hostnames: false,
+            namespace: None,
+            api_key: None,
         };
 
         let mut count = 1;
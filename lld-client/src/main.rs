@@ -11,8 +11,10 @@ use reqwest::Client;
 use tokio::{spawn, sync::mpsc, time::sleep};
 
 use lld_common::{
-    generate_random_id, generate_random_u64, get_current_time, http_request_client,
-    http_request_leasing, tcp_request_leasing, Environment, LldResult,
+    generate_random_id, get_current_time, http_query_leasing, http_release_leasing,
+    http_request_client, http_request_leasing_with_options, http_wait_for_leasing, Environment,
+    LeasingGrant, LeasingOptions, LeasingRejection, LeasingResult, LeasingStatus, LldResult,
+    TcpConnection, WatchFilter,
 };
 
 enum RequestId {
@@ -22,37 +24,48 @@ enum RequestId {
         client: Client,
     },
     Tcp {
-        application_id: u64,
-        instance_id: u64,
+        application_id: String,
+        instance_id: String,
+        connection: TcpConnection,
     },
 }
 impl RequestId {
     fn get_application_id(&self) -> String {
         match self {
-            RequestId::Http { application_id, .. } => format!("{}", application_id),
-            RequestId::Tcp { application_id, .. } => format!("{}", application_id),
+            RequestId::Http { application_id, .. } | RequestId::Tcp { application_id, .. } => {
+                application_id.to_string()
+            }
         }
     }
 
     fn get_instance_id(&self) -> String {
         match self {
-            RequestId::Http { instance_id, .. } => format!("{}", instance_id),
-            RequestId::Tcp { instance_id, .. } => format!("{}", instance_id),
+            RequestId::Http { instance_id, .. } | RequestId::Tcp { instance_id, .. } => {
+                instance_id.to_string()
+            }
         }
     }
 }
 
-async fn run_background_task(mut rx: mpsc::Receiver<u64>) -> LldResult<()> {
-    let mut validity = rx.recv().await.unwrap_or_else(get_current_time);
+async fn run_background_task(mut rx: mpsc::Receiver<LeasingGrant>) -> LldResult<()> {
+    let mut grant = rx.recv().await.unwrap_or(LeasingGrant {
+        validity: get_current_time(),
+        token: 0,
+        duration: 0,
+    });
 
     loop {
-        if let Ok(v) = rx.try_recv() {
-            validity = v;
+        if let Ok(g) = rx.try_recv() {
+            grant = g;
         }
 
         let now = get_current_time();
 
-        print!("\rThread is valid for {} ms    ", validity - now);
+        print!(
+            "\rThread is valid for {} ms (token {})    ",
+            grant.validity.saturating_sub(now),
+            grant.token
+        );
         io::stdout().flush()?;
         sleep(Duration::from_millis(50)).await;
     }
@@ -62,19 +75,122 @@ async fn request_leasing(
     environment: &Environment,
     request: &RequestId,
     duration: u64,
-) -> LldResult<Option<u64>> {
+    options: &LeasingOptions,
+) -> LldResult<LeasingResult> {
+    match request {
+        RequestId::Http {
+            application_id,
+            instance_id,
+            client,
+        } => {
+            http_request_leasing_with_options(
+                client,
+                environment,
+                application_id,
+                instance_id,
+                duration,
+                options,
+            )
+            .await
+        }
+        RequestId::Tcp {
+            application_id,
+            instance_id,
+            connection,
+        } => {
+            connection
+                .request_leasing_with_options(application_id, instance_id, duration, options)
+                .await
+        }
+    }
+}
+
+async fn wait_for_leasing(
+    environment: &Environment,
+    request: &RequestId,
+    duration: u64,
+    options: &LeasingOptions,
+    timeout: u64,
+) -> LldResult<LeasingResult> {
     match request {
         RequestId::Http {
             application_id,
             instance_id,
             client,
         } => {
-            http_request_leasing(client, environment, &application_id, &instance_id, duration).await
+            http_wait_for_leasing(
+                client,
+                environment,
+                application_id,
+                instance_id,
+                duration,
+                options,
+                timeout,
+            )
+            .await
+        }
+        RequestId::Tcp {
+            application_id,
+            instance_id,
+            connection,
+        } => {
+            connection
+                .wait_for_leasing(application_id, instance_id, duration, options, timeout)
+                .await
         }
+    }
+}
+
+async fn release_leasing(environment: &Environment, request: &RequestId) -> LldResult<bool> {
+    match request {
+        RequestId::Http {
+            application_id,
+            instance_id,
+            client,
+        } => http_release_leasing(client, environment, application_id, instance_id).await,
         RequestId::Tcp {
             application_id,
             instance_id,
-        } => tcp_request_leasing(environment, *application_id, *instance_id, duration).await,
+            connection,
+        } => {
+            connection
+                .release_leasing(application_id, instance_id)
+                .await
+        }
+    }
+}
+
+async fn query_leasing(
+    environment: &Environment,
+    request: &RequestId,
+) -> LldResult<Option<LeasingStatus>> {
+    match request {
+        RequestId::Http {
+            application_id,
+            client,
+            ..
+        } => http_query_leasing(client, environment, application_id).await,
+        RequestId::Tcp {
+            application_id,
+            connection,
+            ..
+        } => connection.query_leasing(application_id).await,
+    }
+}
+
+fn log_rejection(rejection: &LeasingRejection) {
+    match &rejection.instance_id {
+        Some(instance_id) => error!(
+            "Could not get leasing, held by '{}' for {} ms, aborting!",
+            instance_id, rejection.remaining
+        ),
+        None => error!(
+            "Could not get leasing, held for {} ms, aborting!",
+            rejection.remaining
+        ),
+    }
+    if let Some(metadata) = &rejection.metadata {
+        error!("Metadata of the holder: '{}'", metadata);
     }
 }
 
@@ -82,11 +198,40 @@ async fn run_single_leasing_client(
     environment: &Environment,
     request: &RequestId,
     duration: u64,
-) -> LldResult<u64> {
-    match request_leasing(environment, request, duration).await? {
-        Some(validity) => Ok(validity),
-        None => {
-            error!("Could not get leasing, aborting!");
+    options: &LeasingOptions,
+    wait: Option<u64>,
+) -> LldResult<LeasingGrant> {
+    let result = match wait {
+        Some(timeout) => {
+            info!("Wait up to {} ms for the leasing", timeout);
+            wait_for_leasing(environment, request, duration, options, timeout).await?
+        }
+        None => request_leasing(environment, request, duration, options).await?,
+    };
+
+    match result {
+        LeasingResult::Granted(grant) => {
+            if grant.duration != duration {
+                warn!(
+                    "Server granted {} ms instead of {} ms",
+                    grant.duration, duration
+                );
+            }
+            Ok(grant)
+        }
+        LeasingResult::Rejected(rejection) => {
+            log_rejection(&rejection);
+            exit(1);
+        }
+        LeasingResult::Preempted => {
+            error!("Leasing was preempted, aborting!");
+            exit(1);
+        }
+        LeasingResult::Unavailable { retry_after } => {
+            error!(
+                "Leasing server is unavailable for {} ms, aborting!",
+                retry_after
+            );
             exit(1);
         }
     }
@@ -96,8 +241,9 @@ async fn run_leasing_client_task(
     environment: &Environment,
     request: &RequestId,
     duration: u64,
+    options: &LeasingOptions,
     threshold: u64,
-    tx: mpsc::Sender<u64>,
+    tx: mpsc::Sender<LeasingGrant>,
     init_validity: u64,
 ) -> LldResult<i32> {
     let now = get_current_time();
@@ -105,16 +251,30 @@ async fn run_leasing_client_task(
     sleep(Duration::from_millis(runtime as u64)).await;
 
     loop {
-        match request_leasing(environment, request, duration).await {
-            Ok(Some(validity)) => {
+        match request_leasing(environment, request, duration, options).await {
+            Ok(LeasingResult::Granted(grant)) => {
                 let now = get_current_time();
 
-                tx.send(validity).await?;
-                let runtime = (validity - now) * threshold / 100;
+                tx.send(grant).await?;
+                let runtime = (grant.validity - now) * threshold / 100;
                 sleep(Duration::from_millis(runtime as u64)).await;
             }
-            Ok(None) => {
-                error!("Could not get leasing, aborting!");
+            Ok(LeasingResult::Rejected(rejection)) => {
+                println!();
+                log_rejection(&rejection);
+                exit(1);
+            }
+            Ok(LeasingResult::Preempted) => {
+                println!();
+                error!("Leasing was preempted, aborting!");
+                exit(1);
+            }
+            Ok(LeasingResult::Unavailable { retry_after }) => {
+                println!();
+                error!(
+                    "Leasing server is unavailable for {} ms, aborting!",
+                    retry_after
+                );
                 exit(1);
             }
             Err(_) => {
@@ -132,7 +292,7 @@ async fn main() {
 
     let m = App::new(crate_name!())
         .version(crate_version!())
-        .author(crate_authors!("\n"))
+        .author(env!("CARGO_PKG_AUTHORS"))
         .arg(
             Arg::with_name("http_uri")
                 .long("http_uri")
@@ -157,7 +317,47 @@ async fn main() {
                 .long("ssl_cert_file")
                 .env("LLD_CERT_FILE"),
         )
+        .arg(
+            Arg::with_name("ssl_client_cert_file")
+                .long("ssl_client_cert_file")
+                .env("LLD_CLIENT_CERT_FILE")
+                .requires("ssl_client_key_file"),
+        )
+        .arg(
+            Arg::with_name("ssl_client_key_file")
+                .long("ssl_client_key_file")
+                .env("LLD_CLIENT_KEY_FILE")
+                .requires("ssl_client_cert_file"),
+        )
+        .arg(Arg::with_name("ssl_accept_invalid_hostnames").long("ssl_accept_invalid_hostnames"))
         .arg(Arg::with_name("tcp").long("tcp"))
+        .arg(Arg::with_name("status").long("status"))
+        .arg(Arg::with_name("watch").long("watch"))
+        .arg(Arg::with_name("prefix").long("prefix").requires("watch"))
+        .arg(
+            Arg::with_name("wait")
+                .short("w")
+                .long("wait")
+                .env("LLD_WAIT"),
+        )
+        .arg(
+            Arg::with_name("priority")
+                .short("p")
+                .long("priority")
+                .env("LLD_PRIORITY"),
+        )
+        .arg(
+            Arg::with_name("metadata")
+                .short("m")
+                .long("metadata")
+                .env("LLD_METADATA"),
+        )
+        .arg(
+            Arg::with_name("namespace")
+                .long("namespace")
+                .env("LLD_NAMESPACE"),
+        )
+        .arg(Arg::with_name("api_key").long("api_key").env("LLD_API_KEY"))
         .get_matches();
 
     let ssl_cert_file = m.value_of("ssl_cert_file").unwrap_or("cacert.pem");
@@ -185,26 +385,105 @@ async fn main() {
         http_request_uri: http_uri.to_owned(),
         tcp_request_uri: tcp_uri.to_owned(),
         ssl_cert_file: ssl_cert_file.map(str::to_string),
+        ssl_client_cert_file: m.value_of("ssl_client_cert_file").map(str::to_string),
+        ssl_client_key_file: m.value_of("ssl_client_key_file").map(str::to_string),
+        accept_invalid_hostnames: m.is_present("ssl_accept_invalid_hostnames"),
+        namespace: m.value_of("namespace").map(str::to_string),
+        api_key: m.value_of("api_key").map(str::to_string),
     };
 
     let application_id = m.value_of("id").unwrap_or_default();
     let duration = value_t!(m, "duration", u64).unwrap_or(5000);
     let threshold = value_t!(m, "threshold", u64).unwrap_or(50);
+    let wait = value_t!(m, "wait", u64).ok();
+    let options = LeasingOptions {
+        priority: value_t!(m, "priority", u32).ok(),
+        metadata: m.value_of("metadata").map(str::to_owned),
+    };
 
     let request = if use_tcp {
+        let connection = match TcpConnection::connect(&environment).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("{:?}", e);
+                exit(1);
+            }
+        };
+
         RequestId::Tcp {
-            application_id: application_id.parse().unwrap(),
-            instance_id: generate_random_u64(),
+            application_id: application_id.to_owned(),
+            instance_id: generate_random_id::<64>(),
+            connection,
         }
     } else {
         RequestId::Http {
             application_id: application_id.to_owned(),
             instance_id: generate_random_id::<64>(),
-            client: http_request_client(ssl_cert_file.as_deref()).unwrap(),
+            client: http_request_client(&environment).unwrap(),
         }
     };
 
-    let (tx, rx) = mpsc::channel::<u64>(8);
+    let (tx, rx) = mpsc::channel::<LeasingGrant>(8);
+
+    if m.is_present("watch") {
+        let connection = match &request {
+            RequestId::Tcp { connection, .. } => connection,
+            RequestId::Http { .. } => {
+                error!("Watching leasings is only supported over tcp, use '--tcp'");
+                exit(1);
+            }
+        };
+
+        let filter = if m.is_present("prefix") {
+            WatchFilter::Prefix(application_id.to_owned())
+        } else {
+            WatchFilter::Application(application_id.to_owned())
+        };
+
+        match connection.watch_leasings(filter).await {
+            Ok(mut events) => {
+                while let Some(event) = events.recv().await {
+                    println!(
+                        "{} '{}' by '{}' (valid until {}, token {})",
+                        event.kind.name(),
+                        event.application_id,
+                        event.instance_id,
+                        event.validity,
+                        event.token
+                    );
+                }
+                error!("Connection closed");
+                exit(1);
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if m.is_present("status") {
+        match query_leasing(&environment, &request).await {
+            Ok(Some(status)) => {
+                println!(
+                    "Leased by '{}' for {} ms (valid until {}, token {})",
+                    status.instance_id, status.remaining, status.validity, status.token
+                );
+                if let Some(metadata) = status.metadata {
+                    println!("Metadata: '{}'", metadata);
+                }
+                exit(0);
+            }
+            Ok(None) => {
+                println!("Not leased");
+                exit(0);
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                exit(1);
+            }
+        }
+    }
 
     info!("Configuration:");
     info!("    application_id: '{}'", request.get_application_id());
@@ -213,16 +492,14 @@ async fn main() {
     info!("    threshold: '{}'", threshold);
     info!("");
 
-    let init_validity;
-    match run_single_leasing_client(&environment, &request, duration).await {
-        Ok(validity) => {
-            init_validity = validity;
-        }
-        Err(e) => {
-            error!("{:?}", e);
-            exit(1);
-        }
-    }
+    let init_grant =
+        match run_single_leasing_client(&environment, &request, duration, &options, wait).await {
+            Ok(grant) => grant,
+            Err(e) => {
+                error!("{:?}", e);
+                exit(1);
+            }
+        };
 
     spawn(async move {
         match run_background_task(rx).await {
@@ -237,20 +514,33 @@ async fn main() {
         }
     });
 
-    if tx.send(init_validity).await.is_err() {
+    if tx.send(init_grant).await.is_err() {
         error!("Could not initiate background task!");
         exit(1)
     }
-    match run_leasing_client_task(
-        &environment,
-        &request,
-        duration,
-        threshold,
-        tx,
-        init_validity,
-    )
-    .await
-    {
+
+    let result = tokio::select! {
+        result = run_leasing_client_task(
+            &environment,
+            &request,
+            duration,
+            &options,
+            threshold,
+            tx,
+            init_grant.validity,
+        ) => result,
+        _ = tokio::signal::ctrl_c() => {
+            println!();
+            match release_leasing(&environment, &request).await {
+                Ok(true) => info!("Leasing released"),
+                Ok(false) => error!("Leasing could not be released!"),
+                Err(e) => error!("{:?}", e),
+            }
+            Ok(0)
+        }
+    };
+
+    match result {
         Ok(code) => {
             info!("Leasing task finished!");
             exit(code);
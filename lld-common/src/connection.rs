@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{error, warn};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_openssl::SslStream;
+
+use crate::{
+    get_current_time, read_tcp_response, Environment, LeasingEvent, LeasingGrant, LeasingOptions,
+    LeasingResult, LeasingStatus, LldError, LldResult, MultiLeasingGrant, MultiLeasingResult,
+    TcpRequest, TcpResponse, WatchFilter,
+};
+
+/// Receivers of the responses to requests in flight.
+#[derive(Debug)]
+enum Pending {
+    Response(oneshot::Sender<TcpResponse>),
+    /// Subscriptions receive events until the connection is closed.
+    Events(mpsc::UnboundedSender<LeasingEvent>),
+}
+
+type PendingMap = HashMap<u32, Pending>;
+
+/// A persistent connection to the tcp endpoint of a leasing server.
+///
+/// Requests are tagged with a request id, so multiple requests can be in flight at the same time
+/// and the server may answer them in any order. The connection can be cloned and shared between
+/// tasks.
+#[derive(Debug, Clone)]
+pub struct TcpConnection {
+    /// Namespace of all requests, see `Environment::namespace`.
+    namespace: Option<String>,
+    /// Key of all requests, see `Environment::api_key`.
+    api_key: Option<String>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    pending: Arc<Mutex<PendingMap>>,
+    next_request_id: Arc<AtomicU32>,
+    closed: Arc<AtomicBool>,
+}
+
+impl TcpConnection {
+    pub async fn connect(environment: &Environment) -> LldResult<Self> {
+        let stream = TcpStream::connect(&environment.tcp_request_uri)
+            .await
+            .map_err(|error| {
+                LldError::WrappedError("tcp connection - connect error", format!("{}", error))
+            })?;
+        stream.set_nodelay(true)?;
+
+        if let Some(ref certificate_file) = environment.ssl_cert_file {
+            let mut connector = SslConnector::builder(SslMethod::tls())?;
+            connector.set_ca_file(certificate_file)?;
+            if let (Some(cert_file), Some(key_file)) = (
+                &environment.ssl_client_cert_file,
+                &environment.ssl_client_key_file,
+            ) {
+                connector.set_certificate_chain_file(cert_file)?;
+                connector.set_private_key_file(key_file, SslFiletype::PEM)?;
+            }
+            let ssl = connector
+                .build()
+                .configure()?
+                .verify_hostname(!environment.accept_invalid_hostnames)
+                .into_ssl("localhost")?;
+
+            let mut stream = SslStream::new(ssl, stream)?;
+
+            Pin::new(&mut stream).connect().await?;
+
+            Ok(Self::start(stream, environment))
+        } else {
+            Ok(Self::start(stream, environment))
+        }
+    }
+
+    fn start<T>(stream: T, environment: &Environment) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let pending = Arc::new(Mutex::new(PendingMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let writer_pending = pending.clone();
+        let writer_closed = closed.clone();
+        tokio::spawn(async move {
+            while let Some(packet) = rx.recv().await {
+                if let Err(e) = writer.write_all(&packet).await {
+                    error!("Cannot send tcp request: {:?}", e);
+                    break;
+                }
+            }
+            close(&writer_pending, &writer_closed);
+        });
+
+        let reader_pending = pending.clone();
+        let reader_closed = closed.clone();
+        tokio::spawn(async move {
+            loop {
+                let (header, response) = match read_tcp_response(&mut reader).await {
+                    Ok(result) => result,
+                    Err(_) => break,
+                };
+
+                let mut pending = reader_pending.lock().unwrap();
+                match pending.remove(&header.request_id) {
+                    Some(Pending::Response(tx)) => {
+                        // The requesting task may have given up waiting
+                        let _ = tx.send(response);
+                    }
+                    Some(Pending::Events(tx)) => match response {
+                        TcpResponse::Event(event) => {
+                            if tx.send(event).is_ok() {
+                                pending.insert(header.request_id, Pending::Events(tx));
+                            }
+                        }
+                        TcpResponse::Subscribed => {
+                            pending.insert(header.request_id, Pending::Events(tx));
+                        }
+                        TcpResponse::Lagged { missed } => {
+                            warn!("Subscription ended after missing {} events", missed)
+                        }
+                        response => error!("Subscription failed: {:?}", response),
+                    },
+                    None => error!("Receive response for unknown request {}", header.request_id),
+                }
+            }
+
+            close(&reader_pending, &reader_closed);
+        });
+
+        Self {
+            namespace: environment.namespace.clone(),
+            api_key: environment.api_key.clone(),
+            tx,
+            pending,
+            next_request_id: Arc::new(AtomicU32::new(1)),
+            closed,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub async fn request(&self, request: &TcpRequest) -> LldResult<TcpResponse> {
+        Ok(self.request_timed(request).await?.1)
+    }
+
+    /// Like `request`, but also returns the time in ms at which the request was sent.
+    async fn request_timed(&self, request: &TcpRequest) -> LldResult<(u64, TcpResponse)> {
+        let (tx, rx) = oneshot::channel();
+        let sent = get_current_time();
+        self.send(request, Pending::Response(tx))?;
+
+        Ok((sent, rx.await?))
+    }
+
+    /// Stream the events of all leasings matched by `filter`, until the connection is closed.
+    pub async fn watch_leasings(
+        &self,
+        filter: WatchFilter,
+    ) -> LldResult<mpsc::UnboundedReceiver<LeasingEvent>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let request = TcpRequest::Subscribe {
+            filter,
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+        self.send(&request, Pending::Events(tx))?;
+
+        Ok(rx)
+    }
+
+    fn send(&self, request: &TcpRequest, pending: Pending) -> LldResult<()> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let packet = request.pack(request_id)?;
+        {
+            // `close` takes the same lock, so the request is either refused here or woken up there
+            let mut pending_map = self.pending.lock().unwrap();
+            if self.is_closed() {
+                return Err(LldError::WrappedError(
+                    "tcp connection - closed",
+                    "The connection was closed by the server".to_owned(),
+                ));
+            }
+            pending_map.insert(request_id, pending);
+        }
+
+        if let Err(e) = self.tx.send(packet) {
+            self.pending.lock().unwrap().remove(&request_id);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    pub async fn request_leasing(
+        &self,
+        application_id: &str,
+        instance_id: &str,
+        duration: u64,
+    ) -> LldResult<LeasingResult> {
+        let request = TcpRequest::Acquire {
+            application_id: application_id.to_owned(),
+            instance_id: instance_id.to_owned(),
+            duration,
+            priority: None,
+            metadata: None,
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+
+        let (sent, response) = self.request_timed(&request).await?;
+        to_leasing_result(sent, response)
+    }
+
+    /// Like `request_leasing`, but the instance may preempt holders with a lower `priority`.
+    pub async fn request_leasing_with_priority(
+        &self,
+        application_id: &str,
+        instance_id: &str,
+        duration: u64,
+        priority: u32,
+    ) -> LldResult<LeasingResult> {
+        let options = LeasingOptions {
+            priority: Some(priority),
+            ..LeasingOptions::default()
+        };
+
+        self.request_leasing_with_options(application_id, instance_id, duration, &options)
+            .await
+    }
+
+    /// Like `request_leasing`, with a priority and metadata from `options`.
+    pub async fn request_leasing_with_options(
+        &self,
+        application_id: &str,
+        instance_id: &str,
+        duration: u64,
+        options: &LeasingOptions,
+    ) -> LldResult<LeasingResult> {
+        let request = TcpRequest::Acquire {
+            application_id: application_id.to_owned(),
+            instance_id: instance_id.to_owned(),
+            duration,
+            priority: options.priority,
+            metadata: options.metadata.clone(),
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+
+        let (sent, response) = self.request_timed(&request).await?;
+        to_leasing_result(sent, response)
+    }
+
+    /// Like `request_leasing`, but the server waits up to `timeout` ms for the leasing to become
+    /// free before it rejects the request.
+    pub async fn wait_for_leasing(
+        &self,
+        application_id: &str,
+        instance_id: &str,
+        duration: u64,
+        options: &LeasingOptions,
+        timeout: u64,
+    ) -> LldResult<LeasingResult> {
+        let request = TcpRequest::AcquireWait {
+            application_id: application_id.to_owned(),
+            instance_id: instance_id.to_owned(),
+            duration,
+            timeout,
+            priority: options.priority,
+            metadata: options.metadata.clone(),
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+
+        let (sent, response) = self.request_timed(&request).await?;
+        to_leasing_result(sent, response)
+    }
+
+    /// Like `request_leasing`, but rejected if the instance does not hold the leasing already.
+    pub async fn renew_leasing(
+        &self,
+        application_id: &str,
+        instance_id: &str,
+        duration: u64,
+    ) -> LldResult<LeasingResult> {
+        let request = TcpRequest::Renew {
+            application_id: application_id.to_owned(),
+            instance_id: instance_id.to_owned(),
+            duration,
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+
+        let (sent, response) = self.request_timed(&request).await?;
+        to_leasing_result(sent, response)
+    }
+
+    /// Acquire all `application_ids` with a single validity, or none of them.
+    pub async fn request_leasings(
+        &self,
+        application_ids: &[String],
+        instance_id: &str,
+        duration: u64,
+    ) -> LldResult<MultiLeasingResult> {
+        let request = TcpRequest::AcquireAll {
+            application_ids: application_ids.to_vec(),
+            instance_id: instance_id.to_owned(),
+            duration,
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+
+        let (sent, response) = self.request_timed(&request).await?;
+        match response {
+            TcpResponse::GrantedAll {
+                remaining,
+                duration,
+                tokens,
+                ..
+            } => Ok(MultiLeasingResult::Granted(MultiLeasingGrant {
+                validity: sent + remaining,
+                duration,
+                tokens,
+            })),
+            TcpResponse::RejectedAll {
+                application_id,
+                rejection,
+            } => Ok(MultiLeasingResult::Rejected {
+                application_id,
+                rejection,
+            }),
+            TcpResponse::Unavailable { retry_after } => {
+                Ok(MultiLeasingResult::Unavailable { retry_after })
+            }
+            TcpResponse::Invalid(reason) => Err(LldError::WrappedError(
+                "tcp connection - invalid request",
+                reason,
+            )),
+            TcpResponse::Unauthorized(reason) => Err(LldError::Unauthorized(reason)),
+            response => Err(LldError::WrappedError(
+                "tcp connection - unexpected response",
+                format!("{:?}", response),
+            )),
+        }
+    }
+
+    pub async fn release_leasing(
+        &self,
+        application_id: &str,
+        instance_id: &str,
+    ) -> LldResult<bool> {
+        let request = TcpRequest::Release {
+            application_id: application_id.to_owned(),
+            instance_id: instance_id.to_owned(),
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+
+        match self.request(&request).await? {
+            TcpResponse::Released => Ok(true),
+            TcpResponse::Unauthorized(reason) => Err(LldError::Unauthorized(reason)),
+            _ => Ok(false),
+        }
+    }
+
+    pub async fn query_leasing(&self, application_id: &str) -> LldResult<Option<LeasingStatus>> {
+        let request = TcpRequest::Status {
+            application_id: application_id.to_owned(),
+            namespace: self.namespace.clone(),
+            api_key: self.api_key.clone(),
+        };
+
+        match self.request(&request).await? {
+            TcpResponse::Leased(status) => Ok(Some(status)),
+            TcpResponse::Free => Ok(None),
+            TcpResponse::Unauthorized(reason) => Err(LldError::Unauthorized(reason)),
+            TcpResponse::Error => Err(LldError::WrappedError(
+                "tcp connection - server error",
+                "Receive error response!".to_owned(),
+            )),
+            response => Err(LldError::WrappedError(
+                "tcp connection - unexpected response",
+                format!("{:?}", response),
+            )),
+        }
+    }
+}
+
+/// Mark the connection as closed and drop the receivers of the requests in flight, which wakes up
+/// every request that is still waiting.
+fn close(pending: &Mutex<PendingMap>, closed: &AtomicBool) {
+    let mut pending = pending.lock().unwrap();
+    closed.store(true, Ordering::SeqCst);
+    pending.clear();
+}
+
+/// The validity of a grant counts from `sent`, the time the request was sent. The server granted
+/// it later, so the client never assumes to hold the leasing longer than the server does,
+/// regardless of how the clocks differ.
+fn to_leasing_result(sent: u64, response: TcpResponse) -> LldResult<LeasingResult> {
+    match response {
+        TcpResponse::Granted {
+            remaining,
+            token,
+            duration,
+            ..
+        } => Ok(LeasingResult::Granted(LeasingGrant {
+            validity: sent + remaining,
+            token,
+            duration,
+        })),
+        TcpResponse::Rejected(rejection) => Ok(LeasingResult::Rejected(rejection)),
+        TcpResponse::Preempted => Ok(LeasingResult::Preempted),
+        TcpResponse::Unavailable { retry_after } => Ok(LeasingResult::Unavailable { retry_after }),
+        TcpResponse::Invalid(reason) => Err(LldError::WrappedError(
+            "tcp connection - invalid request",
+            reason,
+        )),
+        TcpResponse::Unauthorized(reason) => Err(LldError::Unauthorized(reason)),
+        response => Err(LldError::WrappedError(
+            "tcp connection - unexpected response",
+            format!("{:?}", response),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validity_counts_from_the_send_time() {
+        let response = TcpResponse::Granted {
+            validity: 99_999,
+            remaining: 500,
+            token: 1,
+            duration: 1000,
+        };
+
+        match to_leasing_result(1000, response).unwrap() {
+            LeasingResult::Granted(grant) => assert_eq!(grant.validity, 1500),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
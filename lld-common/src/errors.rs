@@ -1,6 +1,8 @@
 #[derive(Debug, Clone)]
 pub enum LldError {
     WrappedError(&'static str, String),
+    /// The request lacks a key or client certificate that is valid for the leasing.
+    Unauthorized(String),
     DatabaseError {
         code: Option<isize>,
         message: Option<String>,
@@ -1,8 +1,14 @@
 #[macro_use]
 extern crate clap;
 
+mod connection;
+pub use connection::*;
+
 mod errors;
 pub use errors::*;
 
+mod protocol;
+pub use protocol::*;
+
 mod utils;
 pub use utils::*;
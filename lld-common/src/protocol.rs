@@ -0,0 +1,1168 @@
+use std::convert::TryFrom;
+use std::io::{Cursor, ErrorKind, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use tokio::io::AsyncRead;
+
+use crate::{
+    LeasingEvent, LeasingEventKind, LeasingRejection, LeasingStatus, LldError, LldResult,
+    WatchFilter,
+};
+
+/// Every versioned tcp frame starts with these bytes.
+///
+/// Packets of protocol version 0 have no header and start directly with the `application_id`.
+pub const TCP_MAGIC: [u8; 4] = *b"LLDP";
+pub const TCP_PROTOCOL_VERSION: u8 = 1;
+pub const TCP_HEADER_LENGTH: usize = 14;
+pub const TCP_MAX_BODY_LENGTH: u32 = 1024 * 16;
+
+/// A versioned acquire or renew request with this duration gets the default duration.
+pub const TCP_DURATION_DEFAULT: u64 = 0;
+
+pub const TCP_RESPONSE_SUCCESS: u8 = 48;
+pub const TCP_RESPONSE_REJECTED: u8 = 49;
+pub const TCP_RESPONSE_ERROR: u8 = 50;
+
+pub const TCP_OPCODE_ACQUIRE: u8 = 0x01;
+pub const TCP_OPCODE_RENEW: u8 = 0x02;
+pub const TCP_OPCODE_RELEASE: u8 = 0x03;
+pub const TCP_OPCODE_STATUS: u8 = 0x04;
+pub const TCP_OPCODE_ACQUIRE_WAIT: u8 = 0x05;
+pub const TCP_OPCODE_SUBSCRIBE: u8 = 0x06;
+pub const TCP_OPCODE_ACQUIRE_ALL: u8 = 0x07;
+
+pub const TCP_OPCODE_GRANTED: u8 = 0x81;
+pub const TCP_OPCODE_RELEASED: u8 = 0x82;
+pub const TCP_OPCODE_LEASED: u8 = 0x83;
+pub const TCP_OPCODE_FREE: u8 = 0x84;
+pub const TCP_OPCODE_REJECTED: u8 = 0x85;
+pub const TCP_OPCODE_ERROR: u8 = 0x86;
+pub const TCP_OPCODE_INVALID: u8 = 0x87;
+pub const TCP_OPCODE_EVENT: u8 = 0x88;
+pub const TCP_OPCODE_SUBSCRIBED: u8 = 0x89;
+pub const TCP_OPCODE_GRANTED_ALL: u8 = 0x8A;
+pub const TCP_OPCODE_REJECTED_ALL: u8 = 0x8B;
+pub const TCP_OPCODE_PREEMPTED: u8 = 0x8C;
+pub const TCP_OPCODE_UNAUTHORIZED: u8 = 0x8D;
+pub const TCP_OPCODE_UNAVAILABLE: u8 = 0x8E;
+pub const TCP_OPCODE_LAGGED: u8 = 0x8F;
+
+/// Header of a versioned tcp frame.
+///
+/// | magic | version | opcode | request id | body length |
+/// |-------|---------|--------|------------|-------------|
+/// | 4     | 1       | 1      | 4          | 4           |
+///
+/// All numbers are big endian. Strings within the body are prefixed with their length as `u16`.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpHeader {
+    pub version: u8,
+    pub opcode: u8,
+    pub request_id: u32,
+    pub length: u32,
+}
+
+/// Every request ends with the optional `namespace` of its applications, the default namespace of
+/// the server is used if it is absent, and the optional `api_key` that authenticates it.
+#[derive(Debug, Clone)]
+pub enum TcpRequest {
+    /// The `priority` and the `metadata` are optional, an absent `priority` keeps the one of the
+    /// holder.
+    Acquire {
+        application_id: String,
+        instance_id: String,
+        duration: u64,
+        priority: Option<u32>,
+        metadata: Option<String>,
+        namespace: Option<String>,
+        api_key: Option<String>,
+    },
+    Renew {
+        application_id: String,
+        instance_id: String,
+        duration: u64,
+        namespace: Option<String>,
+        api_key: Option<String>,
+    },
+    Release {
+        application_id: String,
+        instance_id: String,
+        namespace: Option<String>,
+        api_key: Option<String>,
+    },
+    Status {
+        application_id: String,
+        namespace: Option<String>,
+        api_key: Option<String>,
+    },
+    /// Acquire, waiting up to `timeout` ms for the leasing to become free.
+    AcquireWait {
+        application_id: String,
+        instance_id: String,
+        duration: u64,
+        timeout: u64,
+        priority: Option<u32>,
+        metadata: Option<String>,
+        namespace: Option<String>,
+        api_key: Option<String>,
+    },
+    /// Stream events of the matching leasings. Every event is sent as a separate response with
+    /// the request id of the subscription.
+    Subscribe {
+        filter: WatchFilter,
+        namespace: Option<String>,
+        api_key: Option<String>,
+    },
+    /// Acquire all applications with a single validity, or none of them. The body holds the
+    /// number of applications as `u16`, followed by the ids.
+    AcquireAll {
+        application_ids: Vec<String>,
+        instance_id: String,
+        duration: u64,
+        namespace: Option<String>,
+        api_key: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum TcpResponse {
+    /// `remaining` is measured when the response is sent, so clients do not depend on the
+    /// clock of the server to renew in time. `duration` is the effective duration granted.
+    Granted {
+        validity: u64,
+        remaining: u64,
+        token: u64,
+        duration: u64,
+    },
+    Released,
+    Leased(LeasingStatus),
+    Free,
+    Rejected(LeasingRejection),
+    Error,
+    /// The request violates the duration or metadata limits of the server.
+    Invalid(String),
+    Event(LeasingEvent),
+    Subscribed,
+    /// The holder lost the leasing to an instance with a higher priority.
+    Preempted,
+    /// The request has no valid api key for the leasing.
+    Unauthorized(String),
+    GrantedAll {
+        validity: u64,
+        remaining: u64,
+        duration: u64,
+        tokens: Vec<(String, u64)>,
+    },
+    /// None of the leasings were granted, `application_id` is the first one that was rejected.
+    RejectedAll {
+        application_id: String,
+        rejection: LeasingRejection,
+    },
+    /// The server lost its leasings and cannot grant the leasings for `retry_after` ms.
+    Unavailable {
+        retry_after: u64,
+    },
+    /// The watcher missed `missed` events and its subscription ended, it has to query the
+    /// leasings again and subscribe anew.
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl TcpHeader {
+    fn unpack(buffer: &[u8]) -> LldResult<Self> {
+        let mut buffer = Cursor::new(buffer);
+
+        let mut magic = [0u8; 4];
+        buffer.read_exact(&mut magic)?;
+        if magic != TCP_MAGIC {
+            return Err(LldError::WrappedError(
+                "tcp protocol error",
+                "Invalid magic bytes".to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            version: buffer.read_u8()?,
+            opcode: buffer.read_u8()?,
+            request_id: buffer.read_u32::<BigEndian>()?,
+            length: buffer.read_u32::<BigEndian>()?,
+        })
+    }
+}
+
+impl TcpRequest {
+    pub fn pack(&self, request_id: u32) -> LldResult<Vec<u8>> {
+        let mut body = Vec::new();
+
+        let opcode = match self {
+            TcpRequest::Acquire {
+                application_id,
+                instance_id,
+                duration,
+                priority,
+                metadata,
+                namespace,
+                api_key,
+            } => {
+                write_string(&mut body, application_id)?;
+                write_string(&mut body, instance_id)?;
+                write_u64(&mut body, *duration);
+                write_optional_u32(&mut body, *priority);
+                write_optional_string(&mut body, metadata.as_deref())?;
+                write_optional_string(&mut body, namespace.as_deref())?;
+                write_optional_string(&mut body, api_key.as_deref())?;
+                TCP_OPCODE_ACQUIRE
+            }
+            TcpRequest::Renew {
+                application_id,
+                instance_id,
+                duration,
+                namespace,
+                api_key,
+            } => {
+                write_string(&mut body, application_id)?;
+                write_string(&mut body, instance_id)?;
+                write_u64(&mut body, *duration);
+                write_optional_string(&mut body, namespace.as_deref())?;
+                write_optional_string(&mut body, api_key.as_deref())?;
+                TCP_OPCODE_RENEW
+            }
+            TcpRequest::Release {
+                application_id,
+                instance_id,
+                namespace,
+                api_key,
+            } => {
+                write_string(&mut body, application_id)?;
+                write_string(&mut body, instance_id)?;
+                write_optional_string(&mut body, namespace.as_deref())?;
+                write_optional_string(&mut body, api_key.as_deref())?;
+                TCP_OPCODE_RELEASE
+            }
+            TcpRequest::Status {
+                application_id,
+                namespace,
+                api_key,
+            } => {
+                write_string(&mut body, application_id)?;
+                write_optional_string(&mut body, namespace.as_deref())?;
+                write_optional_string(&mut body, api_key.as_deref())?;
+                TCP_OPCODE_STATUS
+            }
+            TcpRequest::AcquireWait {
+                application_id,
+                instance_id,
+                duration,
+                timeout,
+                priority,
+                metadata,
+                namespace,
+                api_key,
+            } => {
+                write_string(&mut body, application_id)?;
+                write_string(&mut body, instance_id)?;
+                write_u64(&mut body, *duration);
+                write_u64(&mut body, *timeout);
+                write_optional_u32(&mut body, *priority);
+                write_optional_string(&mut body, metadata.as_deref())?;
+                write_optional_string(&mut body, namespace.as_deref())?;
+                write_optional_string(&mut body, api_key.as_deref())?;
+                TCP_OPCODE_ACQUIRE_WAIT
+            }
+            TcpRequest::Subscribe {
+                filter,
+                namespace,
+                api_key,
+            } => {
+                let (kind, value) = match filter {
+                    WatchFilter::Application(id) => (0, id),
+                    WatchFilter::Prefix(prefix) => (1, prefix),
+                };
+                body.push(kind);
+                write_string(&mut body, value)?;
+                write_optional_string(&mut body, namespace.as_deref())?;
+                write_optional_string(&mut body, api_key.as_deref())?;
+                TCP_OPCODE_SUBSCRIBE
+            }
+            TcpRequest::AcquireAll {
+                application_ids,
+                instance_id,
+                duration,
+                namespace,
+                api_key,
+            } => {
+                write_count(&mut body, application_ids.len())?;
+                for application_id in application_ids {
+                    write_string(&mut body, application_id)?;
+                }
+                write_string(&mut body, instance_id)?;
+                write_u64(&mut body, *duration);
+                write_optional_string(&mut body, namespace.as_deref())?;
+                write_optional_string(&mut body, api_key.as_deref())?;
+                TCP_OPCODE_ACQUIRE_ALL
+            }
+        };
+
+        pack_tcp_frame(opcode, request_id, &body)
+    }
+
+    pub fn unpack(opcode: u8, body: &[u8]) -> LldResult<Self> {
+        let mut body = Cursor::new(body);
+
+        Ok(match opcode {
+            TCP_OPCODE_ACQUIRE => TcpRequest::Acquire {
+                application_id: read_string(&mut body)?,
+                instance_id: read_string(&mut body)?,
+                duration: body.read_u64::<BigEndian>()?,
+                priority: read_optional_u32(&mut body)?,
+                metadata: read_optional_string(&mut body)?,
+                namespace: read_optional_string(&mut body)?,
+                api_key: read_optional_string(&mut body)?,
+            },
+            TCP_OPCODE_RENEW => TcpRequest::Renew {
+                application_id: read_string(&mut body)?,
+                instance_id: read_string(&mut body)?,
+                duration: body.read_u64::<BigEndian>()?,
+                namespace: read_optional_string(&mut body)?,
+                api_key: read_optional_string(&mut body)?,
+            },
+            TCP_OPCODE_RELEASE => TcpRequest::Release {
+                application_id: read_string(&mut body)?,
+                instance_id: read_string(&mut body)?,
+                namespace: read_optional_string(&mut body)?,
+                api_key: read_optional_string(&mut body)?,
+            },
+            TCP_OPCODE_STATUS => TcpRequest::Status {
+                application_id: read_string(&mut body)?,
+                namespace: read_optional_string(&mut body)?,
+                api_key: read_optional_string(&mut body)?,
+            },
+            TCP_OPCODE_ACQUIRE_WAIT => TcpRequest::AcquireWait {
+                application_id: read_string(&mut body)?,
+                instance_id: read_string(&mut body)?,
+                duration: body.read_u64::<BigEndian>()?,
+                timeout: body.read_u64::<BigEndian>()?,
+                priority: read_optional_u32(&mut body)?,
+                metadata: read_optional_string(&mut body)?,
+                namespace: read_optional_string(&mut body)?,
+                api_key: read_optional_string(&mut body)?,
+            },
+            TCP_OPCODE_SUBSCRIBE => {
+                let filter = match body.read_u8()? {
+                    0 => WatchFilter::Application(read_string(&mut body)?),
+                    1 => WatchFilter::Prefix(read_string(&mut body)?),
+                    kind => {
+                        return Err(LldError::WrappedError(
+                            "tcp protocol error",
+                            format!("Unknown subscription kind {}", kind),
+                        ))
+                    }
+                };
+
+                TcpRequest::Subscribe {
+                    filter,
+                    namespace: read_optional_string(&mut body)?,
+                    api_key: read_optional_string(&mut body)?,
+                }
+            }
+            TCP_OPCODE_ACQUIRE_ALL => {
+                let count = body.read_u16::<BigEndian>()?;
+                let application_ids = (0..count)
+                    .map(|_| read_string(&mut body))
+                    .collect::<LldResult<Vec<_>>>()?;
+
+                TcpRequest::AcquireAll {
+                    application_ids,
+                    instance_id: read_string(&mut body)?,
+                    duration: body.read_u64::<BigEndian>()?,
+                    namespace: read_optional_string(&mut body)?,
+                    api_key: read_optional_string(&mut body)?,
+                }
+            }
+            opcode => {
+                return Err(LldError::WrappedError(
+                    "tcp protocol error",
+                    format!("Unknown request opcode {}", opcode),
+                ))
+            }
+        })
+    }
+
+    /// Version 0 packets only acquire leasings, releases and status queries need version 1.
+    fn from_v0_packet(packet: [u8; 24]) -> Self {
+        let (application_id, instance_id, duration) = unpack_tcp_packet(packet);
+
+        TcpRequest::Acquire {
+            application_id,
+            instance_id,
+            duration,
+            priority: None,
+            metadata: None,
+            namespace: None,
+            api_key: None,
+        }
+    }
+}
+
+impl TcpResponse {
+    /// Encode the response in the protocol version of the request it answers.
+    pub fn pack(&self, version: u8, request_id: u32) -> LldResult<Vec<u8>> {
+        if version == 0 {
+            return Ok(self.pack_v0());
+        }
+
+        let mut body = Vec::new();
+
+        let opcode = match self {
+            TcpResponse::Granted {
+                validity,
+                remaining,
+                token,
+                duration,
+            } => {
+                write_u64(&mut body, *validity);
+                write_u64(&mut body, *remaining);
+                write_u64(&mut body, *token);
+                write_u64(&mut body, *duration);
+                TCP_OPCODE_GRANTED
+            }
+            TcpResponse::Released => TCP_OPCODE_RELEASED,
+            TcpResponse::Leased(status) => {
+                write_status(&mut body, status)?;
+                write_optional_string(&mut body, status.metadata.as_deref())?;
+                TCP_OPCODE_LEASED
+            }
+            TcpResponse::Free => TCP_OPCODE_FREE,
+            TcpResponse::Rejected(rejection) => {
+                write_rejection(&mut body, rejection)?;
+                TCP_OPCODE_REJECTED
+            }
+            TcpResponse::Error => TCP_OPCODE_ERROR,
+            TcpResponse::Invalid(reason) => {
+                write_string(&mut body, reason)?;
+                TCP_OPCODE_INVALID
+            }
+            TcpResponse::Event(event) => {
+                write_event(&mut body, event)?;
+                TCP_OPCODE_EVENT
+            }
+            TcpResponse::Subscribed => TCP_OPCODE_SUBSCRIBED,
+            TcpResponse::Preempted => TCP_OPCODE_PREEMPTED,
+            TcpResponse::Unauthorized(reason) => {
+                write_string(&mut body, reason)?;
+                TCP_OPCODE_UNAUTHORIZED
+            }
+            TcpResponse::GrantedAll {
+                validity,
+                remaining,
+                duration,
+                tokens,
+            } => {
+                write_u64(&mut body, *validity);
+                write_u64(&mut body, *remaining);
+                write_u64(&mut body, *duration);
+                write_count(&mut body, tokens.len())?;
+                for (application_id, token) in tokens {
+                    write_string(&mut body, application_id)?;
+                    write_u64(&mut body, *token);
+                }
+                TCP_OPCODE_GRANTED_ALL
+            }
+            TcpResponse::RejectedAll {
+                application_id,
+                rejection,
+            } => {
+                write_string(&mut body, application_id)?;
+                write_rejection(&mut body, rejection)?;
+                TCP_OPCODE_REJECTED_ALL
+            }
+            TcpResponse::Unavailable { retry_after } => {
+                write_u64(&mut body, *retry_after);
+                TCP_OPCODE_UNAVAILABLE
+            }
+            TcpResponse::Lagged { missed } => {
+                write_u64(&mut body, *missed);
+                TCP_OPCODE_LAGGED
+            }
+        };
+
+        pack_tcp_frame(opcode, request_id, &body)
+    }
+
+    /// Version 0 responses are a single byte, clients of that version read nothing else.
+    fn pack_v0(&self) -> Vec<u8> {
+        match self {
+            TcpResponse::Granted { .. } | TcpResponse::Released | TcpResponse::Leased(_) => {
+                vec![TCP_RESPONSE_SUCCESS]
+            }
+            TcpResponse::Free
+            | TcpResponse::Rejected(_)
+            | TcpResponse::Preempted
+            | TcpResponse::Unavailable { .. } => vec![TCP_RESPONSE_REJECTED],
+            TcpResponse::Error
+            | TcpResponse::Invalid(_)
+            | TcpResponse::Unauthorized(_)
+            | TcpResponse::Event(_)
+            | TcpResponse::Subscribed
+            | TcpResponse::Lagged { .. }
+            | TcpResponse::GrantedAll { .. }
+            | TcpResponse::RejectedAll { .. } => vec![TCP_RESPONSE_ERROR],
+        }
+    }
+
+    pub fn unpack(opcode: u8, body: &[u8]) -> LldResult<Self> {
+        let mut body = Cursor::new(body);
+
+        Ok(match opcode {
+            TCP_OPCODE_GRANTED => TcpResponse::Granted {
+                validity: body.read_u64::<BigEndian>()?,
+                remaining: body.read_u64::<BigEndian>()?,
+                token: body.read_u64::<BigEndian>()?,
+                duration: body.read_u64::<BigEndian>()?,
+            },
+            TCP_OPCODE_RELEASED => TcpResponse::Released,
+            TCP_OPCODE_LEASED => {
+                let status = read_status(&mut body)?;
+                TcpResponse::Leased(LeasingStatus {
+                    metadata: read_optional_string(&mut body)?,
+                    ..status
+                })
+            }
+            TCP_OPCODE_FREE => TcpResponse::Free,
+            TCP_OPCODE_REJECTED => TcpResponse::Rejected(read_rejection(&mut body)?),
+            TCP_OPCODE_ERROR => TcpResponse::Error,
+            TCP_OPCODE_INVALID => TcpResponse::Invalid(read_string(&mut body)?),
+            TCP_OPCODE_EVENT => TcpResponse::Event(read_event(&mut body)?),
+            TCP_OPCODE_SUBSCRIBED => TcpResponse::Subscribed,
+            TCP_OPCODE_PREEMPTED => TcpResponse::Preempted,
+            TCP_OPCODE_UNAUTHORIZED => TcpResponse::Unauthorized(read_string(&mut body)?),
+            TCP_OPCODE_GRANTED_ALL => {
+                let validity = body.read_u64::<BigEndian>()?;
+                let remaining = body.read_u64::<BigEndian>()?;
+                let duration = body.read_u64::<BigEndian>()?;
+                let count = body.read_u16::<BigEndian>()?;
+                let tokens = (0..count)
+                    .map(|_| Ok((read_string(&mut body)?, body.read_u64::<BigEndian>()?)))
+                    .collect::<LldResult<Vec<_>>>()?;
+
+                TcpResponse::GrantedAll {
+                    validity,
+                    remaining,
+                    duration,
+                    tokens,
+                }
+            }
+            TCP_OPCODE_REJECTED_ALL => TcpResponse::RejectedAll {
+                application_id: read_string(&mut body)?,
+                rejection: read_rejection(&mut body)?,
+            },
+            TCP_OPCODE_UNAVAILABLE => TcpResponse::Unavailable {
+                retry_after: body.read_u64::<BigEndian>()?,
+            },
+            TCP_OPCODE_LAGGED => TcpResponse::Lagged {
+                missed: body.read_u64::<BigEndian>()?,
+            },
+            opcode => {
+                return Err(LldError::WrappedError(
+                    "tcp protocol error",
+                    format!("Unknown response opcode {}", opcode),
+                ))
+            }
+        })
+    }
+}
+
+fn pack_tcp_frame(opcode: u8, request_id: u32, body: &[u8]) -> LldResult<Vec<u8>> {
+    if body.len() > TCP_MAX_BODY_LENGTH as usize {
+        return Err(LldError::WrappedError(
+            "tcp protocol error",
+            format!("Frame body of {} bytes is too large", body.len()),
+        ));
+    }
+
+    let mut frame = Vec::with_capacity(TCP_HEADER_LENGTH + body.len());
+
+    frame.extend_from_slice(&TCP_MAGIC);
+    frame.push(TCP_PROTOCOL_VERSION);
+    frame.push(opcode);
+    frame
+        .write_u32::<BigEndian>(request_id)
+        .expect("Cannot write `request_id` to a tcp frame!");
+    frame
+        .write_u32::<BigEndian>(body.len() as u32)
+        .expect("Cannot write `length` to a tcp frame!");
+    frame.extend_from_slice(body);
+
+    Ok(frame)
+}
+
+async fn read_tcp_body<T>(stream: &mut T, header: &TcpHeader) -> LldResult<Vec<u8>>
+where
+    T: AsyncRead + Unpin,
+{
+    if header.length > TCP_MAX_BODY_LENGTH {
+        return Err(LldError::WrappedError(
+            "tcp protocol error",
+            format!("Frame body of {} bytes is too large", header.length),
+        ));
+    }
+
+    let mut body = vec![0u8; header.length as usize];
+    tokio::io::AsyncReadExt::read_exact(stream, &mut body).await?;
+    Ok(body)
+}
+
+/// Read the next request from a tcp stream.
+///
+/// Packets without the magic bytes are treated as 24 byte packets of protocol version 0. The
+/// inner result contains errors of a well framed but undecodable request, which should be
+/// answered with an error response instead of closing the connection. Returns `None` if the
+/// peer closed the connection before sending another request.
+pub async fn read_tcp_request<T>(
+    stream: &mut T,
+) -> LldResult<Option<(TcpHeader, LldResult<TcpRequest>)>>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buffer = [0u8; TCP_HEADER_LENGTH];
+    match tokio::io::AsyncReadExt::read_exact(stream, &mut buffer[0..4]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    if buffer[0..4] != TCP_MAGIC {
+        let mut packet = [0u8; 24];
+        packet[0..4].copy_from_slice(&buffer[0..4]);
+        tokio::io::AsyncReadExt::read_exact(stream, &mut packet[4..24]).await?;
+
+        let header = TcpHeader {
+            version: 0,
+            opcode: TCP_OPCODE_ACQUIRE,
+            request_id: 0,
+            length: 20,
+        };
+
+        return Ok(Some((header, Ok(TcpRequest::from_v0_packet(packet)))));
+    }
+
+    tokio::io::AsyncReadExt::read_exact(stream, &mut buffer[4..]).await?;
+    let header = TcpHeader::unpack(&buffer)?;
+    let body = read_tcp_body(stream, &header).await?;
+
+    if header.version != TCP_PROTOCOL_VERSION {
+        let error = LldError::WrappedError(
+            "tcp protocol error",
+            format!("Unsupported protocol version {}", header.version),
+        );
+        return Ok(Some((header, Err(error))));
+    }
+
+    let request = TcpRequest::unpack(header.opcode, &body);
+    Ok(Some((header, request)))
+}
+
+/// Read the next versioned response from a tcp stream.
+pub async fn read_tcp_response<T>(stream: &mut T) -> LldResult<(TcpHeader, TcpResponse)>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut buffer = [0u8; TCP_HEADER_LENGTH];
+    tokio::io::AsyncReadExt::read_exact(stream, &mut buffer).await?;
+    let header = TcpHeader::unpack(&buffer)?;
+    let body = read_tcp_body(stream, &header).await?;
+
+    let response = TcpResponse::unpack(header.opcode, &body)?;
+    Ok((header, response))
+}
+
+/// Optional numbers are prefixed with a `u8` flag, `0` if the number is absent.
+fn write_optional_u32(buffer: &mut Vec<u8>, value: Option<u32>) {
+    match value {
+        Some(value) => {
+            buffer.push(1);
+            buffer
+                .write_u32::<BigEndian>(value)
+                .expect("Cannot write u32 to a tcp packet!");
+        }
+        None => buffer.push(0),
+    }
+}
+
+fn read_optional_u32(buffer: &mut Cursor<&[u8]>) -> LldResult<Option<u32>> {
+    match buffer.read_u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(buffer.read_u32::<BigEndian>()?)),
+    }
+}
+
+fn write_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer
+        .write_u16::<BigEndian>(value)
+        .expect("Cannot write u16 to a tcp packet!");
+}
+
+fn write_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer
+        .write_u64::<BigEndian>(value)
+        .expect("Cannot write u64 to a tcp packet!");
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) -> LldResult<()> {
+    let bytes = value.as_bytes();
+    let length = u16::try_from(bytes.len()).map_err(|_| {
+        LldError::WrappedError(
+            "tcp protocol error",
+            format!("String of {} bytes is too long", bytes.len()),
+        )
+    })?;
+
+    write_u16(buffer, length);
+    buffer.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// The number of items of a list, as `u16`.
+fn write_count(buffer: &mut Vec<u8>, count: usize) -> LldResult<()> {
+    let count = u16::try_from(count).map_err(|_| {
+        LldError::WrappedError(
+            "tcp protocol error",
+            format!("List of {} items is too long", count),
+        )
+    })?;
+
+    write_u16(buffer, count);
+    Ok(())
+}
+
+fn read_string(buffer: &mut Cursor<&[u8]>) -> LldResult<String> {
+    let length = buffer.read_u16::<BigEndian>()?;
+    let mut bytes = vec![0u8; length as usize];
+    buffer.read_exact(&mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Optional strings are prefixed with a `u8` flag, `0` if the string is absent.
+fn write_optional_string(buffer: &mut Vec<u8>, value: Option<&str>) -> LldResult<()> {
+    match value {
+        Some(value) => {
+            buffer.push(1);
+            write_string(buffer, value)?;
+        }
+        None => buffer.push(0),
+    }
+    Ok(())
+}
+
+fn read_optional_string(buffer: &mut Cursor<&[u8]>) -> LldResult<Option<String>> {
+    match buffer.read_u8()? {
+        0 => Ok(None),
+        _ => Ok(Some(read_string(buffer)?)),
+    }
+}
+
+fn write_rejection(buffer: &mut Vec<u8>, rejection: &LeasingRejection) -> LldResult<()> {
+    write_u64(buffer, rejection.remaining);
+    write_optional_string(buffer, rejection.instance_id.as_deref())?;
+    write_optional_string(buffer, rejection.metadata.as_deref())
+}
+
+fn read_rejection(buffer: &mut Cursor<&[u8]>) -> LldResult<LeasingRejection> {
+    Ok(LeasingRejection {
+        remaining: buffer.read_u64::<BigEndian>()?,
+        instance_id: read_optional_string(buffer)?,
+        metadata: read_optional_string(buffer)?,
+    })
+}
+
+fn write_status(buffer: &mut Vec<u8>, status: &LeasingStatus) -> LldResult<()> {
+    write_string(buffer, &status.instance_id)?;
+    write_u64(buffer, status.validity);
+    write_u64(buffer, status.remaining);
+    write_u64(buffer, status.token);
+    Ok(())
+}
+
+fn read_status(buffer: &mut Cursor<&[u8]>) -> LldResult<LeasingStatus> {
+    Ok(LeasingStatus {
+        instance_id: read_string(buffer)?,
+        validity: buffer.read_u64::<BigEndian>()?,
+        remaining: buffer.read_u64::<BigEndian>()?,
+        token: buffer.read_u64::<BigEndian>()?,
+        metadata: None,
+    })
+}
+
+fn write_event(buffer: &mut Vec<u8>, event: &LeasingEvent) -> LldResult<()> {
+    buffer.push(match event.kind {
+        LeasingEventKind::Granted => 0,
+        LeasingEventKind::Renewed => 1,
+        LeasingEventKind::Released => 2,
+        LeasingEventKind::Expired => 3,
+        LeasingEventKind::Preempted => 4,
+    });
+    write_string(buffer, &event.application_id)?;
+    write_string(buffer, &event.instance_id)?;
+    write_u64(buffer, event.validity);
+    write_u64(buffer, event.token);
+    write_string(buffer, &event.namespace)
+}
+
+fn read_event(buffer: &mut Cursor<&[u8]>) -> LldResult<LeasingEvent> {
+    let kind = match buffer.read_u8()? {
+        0 => LeasingEventKind::Granted,
+        1 => LeasingEventKind::Renewed,
+        2 => LeasingEventKind::Released,
+        3 => LeasingEventKind::Expired,
+        4 => LeasingEventKind::Preempted,
+        kind => {
+            return Err(LldError::WrappedError(
+                "tcp protocol error",
+                format!("Unknown event kind {}", kind),
+            ))
+        }
+    };
+
+    let application_id = read_string(buffer)?;
+    let instance_id = read_string(buffer)?;
+    let validity = buffer.read_u64::<BigEndian>()?;
+    let token = buffer.read_u64::<BigEndian>()?;
+
+    Ok(LeasingEvent {
+        kind,
+        namespace: read_string(buffer)?,
+        application_id,
+        instance_id,
+        validity,
+        token,
+    })
+}
+
+pub fn unpack_tcp_packet(packet: [u8; 24]) -> (String, String, u64) {
+    let application_id = base64::encode(&packet[0..8]);
+    let instance_id = base64::encode(&packet[8..16]);
+    let mut duration_slice: &[u8] = &packet[16..24];
+    let duration = duration_slice.read_u64::<BigEndian>().unwrap_or(0);
+
+    (application_id, instance_id, duration)
+}
+
+pub fn pack_tcp_packet(application_id: u64, instance_id: u64, duration: u64) -> [u8; 24] {
+    let mut packet = [0u8; 24];
+    let mut buffer = packet.as_mut();
+
+    buffer
+        .write_u64::<BigEndian>(application_id)
+        .expect("Cannot write `application_id` to a tcp packet!");
+    buffer
+        .write_u64::<BigEndian>(instance_id)
+        .expect("Cannot write `instance_id` to a tcp packet!");
+    buffer
+        .write_u64::<BigEndian>(duration)
+        .expect("Cannot write `duration` to a tcp packet!");
+
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejection() -> LeasingRejection {
+        LeasingRejection {
+            remaining: 500,
+            instance_id: Some("holder".to_owned()),
+            metadata: Some("meta".to_owned()),
+        }
+    }
+
+    fn split_frame(frame: &[u8]) -> (TcpHeader, &[u8]) {
+        let header = TcpHeader::unpack(&frame[..TCP_HEADER_LENGTH]).unwrap();
+        assert_eq!(header.length as usize, frame.len() - TCP_HEADER_LENGTH);
+        (header, &frame[TCP_HEADER_LENGTH..])
+    }
+
+    /// Requests and responses do not implement `PartialEq`, their debug output is compared.
+    fn assert_request_round_trip(request: TcpRequest) {
+        let frame = request.pack(42).unwrap();
+        let (header, body) = split_frame(&frame);
+        assert_eq!(header.version, TCP_PROTOCOL_VERSION);
+        assert_eq!(header.request_id, 42);
+
+        let unpacked = TcpRequest::unpack(header.opcode, body).unwrap();
+        assert_eq!(format!("{:?}", unpacked), format!("{:?}", request));
+    }
+
+    fn assert_response_round_trip(response: TcpResponse) {
+        let frame = response.pack(TCP_PROTOCOL_VERSION, 42).unwrap();
+        let (header, body) = split_frame(&frame);
+        assert_eq!(header.request_id, 42);
+
+        let unpacked = TcpResponse::unpack(header.opcode, body).unwrap();
+        assert_eq!(format!("{:?}", unpacked), format!("{:?}", response));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let namespace = Some("team".to_owned());
+        let api_key = Some("key".to_owned());
+
+        assert_request_round_trip(TcpRequest::Acquire {
+            application_id: "a".to_owned(),
+            instance_id: "i".to_owned(),
+            duration: 1000,
+            priority: Some(3),
+            metadata: Some("meta".to_owned()),
+            namespace: namespace.clone(),
+            api_key: api_key.clone(),
+        });
+        assert_request_round_trip(TcpRequest::Acquire {
+            application_id: "a".to_owned(),
+            instance_id: "i".to_owned(),
+            duration: 1000,
+            priority: None,
+            metadata: None,
+            namespace: None,
+            api_key: None,
+        });
+        assert_request_round_trip(TcpRequest::Renew {
+            application_id: "a".to_owned(),
+            instance_id: "i".to_owned(),
+            duration: 1000,
+            namespace: namespace.clone(),
+            api_key: api_key.clone(),
+        });
+        assert_request_round_trip(TcpRequest::Release {
+            application_id: "a".to_owned(),
+            instance_id: "i".to_owned(),
+            namespace: namespace.clone(),
+            api_key: None,
+        });
+        assert_request_round_trip(TcpRequest::Status {
+            application_id: "a".to_owned(),
+            namespace: None,
+            api_key: api_key.clone(),
+        });
+        assert_request_round_trip(TcpRequest::AcquireWait {
+            application_id: "a".to_owned(),
+            instance_id: "i".to_owned(),
+            duration: 1000,
+            timeout: 5000,
+            priority: Some(1),
+            metadata: None,
+            namespace: namespace.clone(),
+            api_key: api_key.clone(),
+        });
+        assert_request_round_trip(TcpRequest::Subscribe {
+            filter: WatchFilter::Application("a".to_owned()),
+            namespace: None,
+            api_key: None,
+        });
+        assert_request_round_trip(TcpRequest::Subscribe {
+            filter: WatchFilter::Prefix("jobs/".to_owned()),
+            namespace: namespace.clone(),
+            api_key: api_key.clone(),
+        });
+        assert_request_round_trip(TcpRequest::AcquireAll {
+            application_ids: vec!["a".to_owned(), "b".to_owned()],
+            instance_id: "i".to_owned(),
+            duration: 1000,
+            namespace,
+            api_key,
+        });
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        assert_response_round_trip(grant());
+        assert_response_round_trip(TcpResponse::Released);
+        assert_response_round_trip(TcpResponse::Leased(LeasingStatus {
+            instance_id: "i".to_owned(),
+            validity: 2000,
+            remaining: 1000,
+            token: 7,
+            metadata: Some("meta".to_owned()),
+        }));
+        assert_response_round_trip(TcpResponse::Free);
+        assert_response_round_trip(TcpResponse::Rejected(rejection()));
+        assert_response_round_trip(TcpResponse::Rejected(LeasingRejection::default()));
+        assert_response_round_trip(TcpResponse::Error);
+        assert_response_round_trip(TcpResponse::Invalid("too long".to_owned()));
+        assert_response_round_trip(TcpResponse::Event(LeasingEvent {
+            kind: LeasingEventKind::Preempted,
+            namespace: "team".to_owned(),
+            application_id: "a".to_owned(),
+            instance_id: "i".to_owned(),
+            validity: 2000,
+            token: 7,
+        }));
+        assert_response_round_trip(TcpResponse::Subscribed);
+        assert_response_round_trip(TcpResponse::Preempted);
+        assert_response_round_trip(TcpResponse::Unauthorized("no key".to_owned()));
+        assert_response_round_trip(TcpResponse::GrantedAll {
+            validity: 2000,
+            remaining: 1000,
+            duration: 1000,
+            tokens: vec![("a".to_owned(), 1), ("b".to_owned(), 2)],
+        });
+        assert_response_round_trip(TcpResponse::RejectedAll {
+            application_id: "b".to_owned(),
+            rejection: rejection(),
+        });
+        assert_response_round_trip(TcpResponse::Unavailable { retry_after: 300 });
+        assert_response_round_trip(TcpResponse::Lagged { missed: 12 });
+    }
+
+    #[test]
+    fn unknown_opcodes_are_refused() {
+        assert!(TcpRequest::unpack(0x7F, &[]).is_err());
+        assert!(TcpResponse::unpack(0x7F, &[]).is_err());
+    }
+
+    #[test]
+    fn unknown_subscription_kinds_are_refused() {
+        let mut body = vec![2];
+        write_string(&mut body, "a").unwrap();
+        write_optional_string(&mut body, None).unwrap();
+        write_optional_string(&mut body, None).unwrap();
+
+        assert!(TcpRequest::unpack(TCP_OPCODE_SUBSCRIBE, &body).is_err());
+        body[0] = 1;
+        assert!(TcpRequest::unpack(TCP_OPCODE_SUBSCRIBE, &body).is_ok());
+    }
+
+    #[test]
+    fn truncated_bodies_are_refused() {
+        let frame = TcpRequest::Acquire {
+            application_id: "a".to_owned(),
+            instance_id: "i".to_owned(),
+            duration: 1000,
+            priority: Some(3),
+            metadata: None,
+            namespace: None,
+            api_key: None,
+        }
+        .pack(1)
+        .unwrap();
+        let (header, body) = split_frame(&frame);
+
+        // The optional fields are flags that every version 1 peer sends
+        for length in 0..body.len() {
+            assert!(TcpRequest::unpack(header.opcode, &body[..length]).is_err());
+        }
+        assert!(TcpRequest::unpack(header.opcode, body).is_ok());
+    }
+
+    #[tokio::test]
+    async fn v0_packets_are_read_as_requests() {
+        let packet = pack_tcp_packet(1, 2, 1000);
+        let (header, request) = read_tcp_request(&mut &packet[..]).await.unwrap().unwrap();
+        assert_eq!(header.version, 0);
+        assert_eq!(header.opcode, TCP_OPCODE_ACQUIRE);
+        let (application_id, instance_id, _) = unpack_tcp_packet(packet);
+        match request.unwrap() {
+            TcpRequest::Acquire {
+                application_id: id,
+                instance_id: instance,
+                duration,
+                ..
+            } => {
+                assert_eq!(id, application_id);
+                assert_eq!(instance, instance_id);
+                assert_eq!(duration, 1000);
+            }
+            request => panic!("unexpected request {:?}", request),
+        }
+
+        // Every duration acquires, there are no special durations in version 0
+        for duration in [0, u64::MAX] {
+            let packet = pack_tcp_packet(1, 2, duration);
+            let (header, request) = read_tcp_request(&mut &packet[..]).await.unwrap().unwrap();
+            assert_eq!(header.opcode, TCP_OPCODE_ACQUIRE);
+            assert!(
+                matches!(request, Ok(TcpRequest::Acquire { duration: d, .. }) if d == duration)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_are_read_from_a_stream() {
+        let request = TcpRequest::Status {
+            application_id: "a".to_owned(),
+            namespace: None,
+            api_key: None,
+        };
+        let mut stream = request.pack(9).unwrap();
+        stream.extend(grant().pack(TCP_PROTOCOL_VERSION, 9).unwrap());
+        let mut stream = &stream[..];
+
+        let (header, request) = read_tcp_request(&mut stream).await.unwrap().unwrap();
+        assert_eq!(header.request_id, 9);
+        assert!(matches!(request, Ok(TcpRequest::Status { .. })));
+
+        let (header, response) = read_tcp_response(&mut stream).await.unwrap();
+        assert_eq!(header.request_id, 9);
+        assert!(matches!(response, TcpResponse::Granted { token: 7, .. }));
+
+        assert!(read_tcp_request(&mut stream).await.unwrap().is_none());
+    }
+
+    fn grant() -> TcpResponse {
+        TcpResponse::Granted {
+            validity: 2000,
+            remaining: 1000,
+            token: 7,
+            duration: 1000,
+        }
+    }
+
+    #[test]
+    fn v0_responses_are_a_single_byte() {
+        assert_eq!(grant().pack(0, 0).unwrap(), vec![TCP_RESPONSE_SUCCESS]);
+        assert_eq!(
+            TcpResponse::Rejected(LeasingRejection::default())
+                .pack(0, 0)
+                .unwrap(),
+            vec![TCP_RESPONSE_REJECTED]
+        );
+        assert_eq!(
+            TcpResponse::Error.pack(0, 0).unwrap(),
+            vec![TCP_RESPONSE_ERROR]
+        );
+    }
+
+    #[test]
+    fn long_strings_are_refused() {
+        let request = TcpRequest::Status {
+            application_id: "a".repeat(u16::MAX as usize + 1),
+            namespace: None,
+            api_key: None,
+        };
+        assert!(request.pack(1).is_err());
+
+        let response = TcpResponse::Invalid("a".repeat(u16::MAX as usize + 1));
+        assert!(response.pack(TCP_PROTOCOL_VERSION, 1).is_err());
+    }
+
+    #[test]
+    fn long_lists_are_refused() {
+        let request = TcpRequest::AcquireAll {
+            application_ids: vec![String::new(); u16::MAX as usize + 1],
+            instance_id: "i".to_owned(),
+            duration: 1000,
+            namespace: None,
+            api_key: None,
+        };
+        assert!(request.pack(1).is_err());
+
+        let response = TcpResponse::GrantedAll {
+            validity: 2000,
+            remaining: 1000,
+            duration: 1000,
+            tokens: vec![(String::new(), 1); u16::MAX as usize + 1],
+        };
+        assert!(response.pack(TCP_PROTOCOL_VERSION, 1).is_err());
+    }
+}
@@ -1,31 +1,433 @@
-use std::pin::Pin;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use log::error;
-use openssl::ssl::{SslConnector, SslMethod};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
 use rand::{thread_rng, RngCore};
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
-use tokio_openssl::SslStream;
+use tokio::sync::mpsc;
 
-use crate::{LldError, LldResult};
+use crate::{LldError, LldResult, TcpConnection};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RestLeasingRequest {
+    /// Namespace of the application, the default namespace of the server if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
     pub application_id: String,
     pub instance_id: String,
-    pub duration: u64,
+    /// Requested duration in ms, the server uses its default duration if absent.
+    #[serde(default)]
+    pub duration: Option<u64>,
+    /// Wait up to this many ms for the leasing to become free instead of being rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait: Option<u64>,
+    /// Priority of the instance, a holder keeps its priority if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+    /// Opaque data stored with the leasing, a holder keeps its metadata if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum RestLeasingResponse {
-    Granted { validity: u64 },
+    /// `duration` is the effective duration granted by the server, which may differ from the
+    /// requested one.
+    Granted {
+        validity: u64,
+        token: u64,
+        duration: u64,
+    },
+    Rejected {
+        remaining: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<String>,
+    },
+    /// The holder lost the leasing to an instance with a higher priority.
+    Preempted,
+    /// The request violates the duration or metadata limits of the server.
+    Invalid {
+        reason: String,
+    },
+    /// The request has no valid key for the leasing.
+    Unauthorized {
+        reason: String,
+    },
+    /// The server lost its leasings and cannot grant this one for `retry_after` ms.
+    Unavailable {
+        retry_after: u64,
+    },
+    Error,
+}
+
+/// Acquire all `application_ids` for the instance, or none of them.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestMultiLeasingRequest {
+    /// Namespace of the application, the default namespace of the server if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub application_ids: Vec<String>,
+    pub instance_id: String,
+    /// Requested duration in ms, the server uses its default duration if absent.
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RestMultiLeasingResponse {
+    /// All leasings share the same `validity`, `tokens` holds the fencing token per application.
+    Granted {
+        validity: u64,
+        duration: u64,
+        tokens: Vec<(String, u64)>,
+    },
+    /// `application_id` is the first leasing that could not be granted.
+    Rejected {
+        application_id: String,
+        remaining: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<String>,
+    },
+    /// The request violates the duration policy of the server.
+    Invalid {
+        reason: String,
+    },
+    Unauthorized {
+        reason: String,
+    },
+    /// The server lost its leasings and cannot grant all of them for `retry_after` ms.
+    Unavailable {
+        retry_after: u64,
+    },
+    Error,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestReleaseRequest {
+    /// Namespace of the application, the default namespace of the server if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub application_id: String,
+    pub instance_id: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RestReleaseResponse {
+    Released,
     Rejected,
+    Unauthorized { reason: String },
+    Error,
+}
+
+/// A granted leasing as seen by the client.
+///
+/// The fencing `token` increases every time the leasing changes its holder and can be passed to
+/// downstream systems to reject requests of a stale holder.
+#[derive(Debug, Clone, Copy)]
+pub struct LeasingGrant {
+    pub validity: u64,
+    pub token: u64,
+    pub duration: u64,
+}
+
+/// Why a leasing request was rejected.
+///
+/// `remaining` is the time in ms until the current leasing expires, so a rejected instance knows
+/// when a retry can succeed. The holder's `instance_id` is only sent if the server is configured
+/// to expose it, its `metadata` is always sent.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct LeasingRejection {
+    pub remaining: u64,
+    pub instance_id: Option<String>,
+    #[serde(default)]
+    pub metadata: Option<String>,
+}
+
+/// Optional settings of a leasing request.
+#[derive(Debug, Clone, Default)]
+pub struct LeasingOptions {
+    /// Priority of the instance, a holder keeps its priority if absent.
+    pub priority: Option<u32>,
+    /// Opaque data stored with the leasing, e.g. how to reach the holder.
+    pub metadata: Option<String>,
+}
+
+/// Outcome of a leasing request as seen by the client.
+#[derive(Debug, Clone)]
+pub enum LeasingResult {
+    Granted(LeasingGrant),
+    Rejected(LeasingRejection),
+    /// The holder lost the leasing to an instance with a higher priority.
+    Preempted,
+    /// The server lost its leasings, the request may be sent again after `retry_after` ms.
+    Unavailable {
+        retry_after: u64,
+    },
+}
+
+impl LeasingResult {
+    pub fn grant(&self) -> Option<LeasingGrant> {
+        match self {
+            LeasingResult::Granted(grant) => Some(*grant),
+            LeasingResult::Rejected(_)
+            | LeasingResult::Preempted
+            | LeasingResult::Unavailable { .. } => None,
+        }
+    }
+}
+
+/// Several leasings granted together, see `LeasingGrant`.
+#[derive(Debug, Clone)]
+pub struct MultiLeasingGrant {
+    pub validity: u64,
+    pub duration: u64,
+    pub tokens: Vec<(String, u64)>,
+}
+
+/// Outcome of a multi leasing request as seen by the client.
+///
+/// A rejection names the first application that could not be granted, none of the leasings
+/// were granted in that case.
+#[derive(Debug, Clone)]
+pub enum MultiLeasingResult {
+    Granted(MultiLeasingGrant),
+    Rejected {
+        application_id: String,
+        rejection: LeasingRejection,
+    },
+    /// The server lost its leasings, the request may be sent again after `retry_after` ms.
+    Unavailable {
+        retry_after: u64,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LeasingStatus {
+    pub instance_id: String,
+    pub validity: u64,
+    pub remaining: u64,
+    pub token: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeasingEventKind {
+    /// The leasing was granted to a new holder.
+    Granted,
+    /// The current holder extended the leasing.
+    Renewed,
+    Released,
+    /// The holder lost the leasing to an instance with a higher priority, or an admin handed it
+    /// over to another instance.
+    Preempted,
+    Expired,
+}
+
+impl LeasingEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LeasingEventKind::Granted => "granted",
+            LeasingEventKind::Renewed => "renewed",
+            LeasingEventKind::Released => "released",
+            LeasingEventKind::Preempted => "preempted",
+            LeasingEventKind::Expired => "expired",
+        }
+    }
+}
+
+/// A change of a leasing, as streamed to watchers.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LeasingEvent {
+    pub kind: LeasingEventKind,
+    #[serde(default)]
+    pub namespace: String,
+    pub application_id: String,
+    pub instance_id: String,
+    pub validity: u64,
+    pub token: u64,
+}
+
+/// Selects the applications a watcher receives events for.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchFilter {
+    Application(String),
+    Prefix(String),
+}
+
+impl WatchFilter {
+    pub fn matches(&self, application_id: &str) -> bool {
+        match self {
+            WatchFilter::Application(id) => application_id == id,
+            WatchFilter::Prefix(prefix) => application_id.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RestStatusResponse {
+    Leased {
+        instance_id: String,
+        validity: u64,
+        remaining: u64,
+        token: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<String>,
+    },
+    Free,
+    Unauthorized {
+        reason: String,
+    },
+    Error,
+}
+
+/// A namespace as listed by the admin endpoint, with the number of valid leasings in it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RestNamespace {
+    pub namespace: String,
+    pub leasings: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RestNamespaceResponse {
+    Namespaces {
+        namespaces: Vec<RestNamespace>,
+    },
+    /// All leasings of the namespace were deleted, `leasings` of them were still valid.
+    Deleted {
+        leasings: u64,
+    },
+    Unauthorized {
+        reason: String,
+    },
+    Error,
+}
+
+/// Grant `key` access to all leasings of `application_id`, to all leasings of `namespace` if the
+/// application is absent, or to everything including the admin endpoints if the namespace is
+/// `*`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestCredentialRequest {
+    pub key: String,
+    /// The default namespace of the server if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestCredentialRevokeRequest {
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RestCredentialResponse {
+    Added,
+    Revoked,
+    /// There is no such key.
+    Unknown,
+    Unauthorized {
+        reason: String,
+    },
+    Error,
+}
+
+/// A holder as listed by the admin endpoint, expired holders are only listed on request.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RestLeasing {
+    pub namespace: String,
+    pub application_id: String,
+    pub instance_id: String,
+    pub validity: u64,
+    pub remaining: u64,
+    pub token: u64,
+    pub priority: u32,
+    /// The holder gives up the leasing at its next renewal.
+    pub preempted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RestLeasingsResponse {
+    /// A page of the holders, `total` is the number of matching holders on all pages.
+    Leasings {
+        leasings: Vec<RestLeasing>,
+        total: u64,
+    },
+    Unauthorized {
+        reason: String,
+    },
+    Error,
+}
+
+/// Take the leasing away from `instance_id`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestRevokeRequest {
+    /// The default namespace of the server if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub application_id: String,
+    pub instance_id: String,
+}
+
+/// Hand the leasing of `instance_id` over to `to_instance_id`, with a new fencing token. The
+/// validity is kept unless a `duration` is given.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RestTransferRequest {
+    /// The default namespace of the server if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub application_id: String,
+    pub instance_id: String,
+    pub to_instance_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RestAdminLeasingResponse {
+    Revoked,
+    Transferred {
+        validity: u64,
+        token: u64,
+    },
+    /// The instance holds no valid leasing, or the new holder already holds one.
+    Rejected,
+    /// The duration is outside of the allowed range.
+    Invalid {
+        reason: String,
+    },
+    Unauthorized {
+        reason: String,
+    },
     Error,
 }
 
@@ -34,6 +436,33 @@ pub struct Environment {
     pub http_request_uri: String,
     pub tcp_request_uri: String,
     pub ssl_cert_file: Option<String>,
+    /// Client certificate and its key, only needed if the server requires client certificates.
+    pub ssl_client_cert_file: Option<String>,
+    pub ssl_client_key_file: Option<String>,
+    /// Skip the check of the server hostname against its certificate, the certificate itself is
+    /// still checked against `ssl_cert_file`.
+    pub accept_invalid_hostnames: bool,
+    /// Namespace of all requests, the default namespace of the server if absent.
+    pub namespace: Option<String>,
+    /// Key that authenticates all requests, only needed if the server requires authentication.
+    pub api_key: Option<String>,
+}
+
+impl Environment {
+    /// Resolve an http endpoint relative to the configured request uri.
+    pub fn http_uri(&self, path: &str) -> LldResult<reqwest::Url> {
+        reqwest::Url::parse(&self.http_request_uri)
+            .and_then(|uri| uri.join(path))
+            .map_err(|error| LldError::WrappedError("http uri parse error", format!("{}", error)))
+    }
+
+    /// Add the api key to an http request as bearer token, if there is one.
+    pub fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
 }
 
 arg_enum! {
@@ -45,24 +474,29 @@ arg_enum! {
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for LldMode {
     fn default() -> Self {
         Self::Batching
     }
 }
 
-pub fn http_request_client(certificate_file: Option<&str>) -> LldResult<Client> {
-    if let Some(certificate_file) = certificate_file {
+/// The server certificate is checked against `ssl_cert_file` and, unless
+/// `accept_invalid_hostnames` is set, against the hostname of `http_request_uri`.
+pub fn http_request_client(environment: &Environment) -> LldResult<Client> {
+    if let Some(ref certificate_file) = environment.ssl_cert_file {
         let cert = std::fs::read(certificate_file)?;
         let cert = reqwest::Certificate::from_pem(&cert)?;
 
-        let client = reqwest::Client::builder()
+        let mut builder = reqwest::Client::builder()
             .tls_built_in_root_certs(false)
             .add_root_certificate(cert)
-            .danger_accept_invalid_certs(true)
-            .build()?;
+            .danger_accept_invalid_hostnames(environment.accept_invalid_hostnames);
+        if let Some(identity) = client_identity(environment)? {
+            builder = builder.identity(identity);
+        }
 
-        Ok(client)
+        Ok(builder.build()?)
     } else {
         let client = reqwest::Client::builder().build()?;
 
@@ -70,101 +504,399 @@ pub fn http_request_client(certificate_file: Option<&str>) -> LldResult<Client>
     }
 }
 
+/// The client certificate with its key and chain, native-tls only reads them as pkcs12 archive.
+fn client_identity(environment: &Environment) -> LldResult<Option<reqwest::Identity>> {
+    const PASSWORD: &str = "lld";
+
+    let (cert_file, key_file) = match (
+        &environment.ssl_client_cert_file,
+        &environment.ssl_client_key_file,
+    ) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        _ => return Ok(None),
+    };
+
+    let mut certs = X509::stack_from_pem(&std::fs::read(cert_file)?)?.into_iter();
+    let cert = certs.next().ok_or_else(|| {
+        LldError::WrappedError(
+            "client certificate error",
+            format!("{} is empty", cert_file),
+        )
+    })?;
+    let key = PKey::private_key_from_pem(&std::fs::read(key_file)?)?;
+
+    let mut chain = Stack::new()?;
+    for ca in certs {
+        chain.push(ca)?;
+    }
+    let mut pkcs12 = Pkcs12::builder();
+    pkcs12.ca(chain);
+    let der = pkcs12
+        .build(PASSWORD, "lld-client", &key, &cert)?
+        .to_der()?;
+
+    Ok(Some(reqwest::Identity::from_pkcs12_der(&der, PASSWORD)?))
+}
+
 pub async fn http_request_leasing(
     client: &Client,
     environment: &Environment,
     application_id: &str,
     instance_id: &str,
     duration: u64,
-) -> LldResult<Option<u64>> {
+) -> LldResult<LeasingResult> {
     let request = RestLeasingRequest {
+        namespace: environment.namespace.clone(),
         application_id: application_id.to_owned(),
         instance_id: instance_id.to_owned(),
+        duration: Some(duration),
+        wait: None,
+        priority: None,
+        metadata: None,
+    };
+
+    http_send_leasing_request(client, environment, &request).await
+}
+
+/// Like `http_request_leasing`, but the instance may preempt holders with a lower `priority`.
+pub async fn http_request_leasing_with_priority(
+    client: &Client,
+    environment: &Environment,
+    application_id: &str,
+    instance_id: &str,
+    duration: u64,
+    priority: u32,
+) -> LldResult<LeasingResult> {
+    let options = LeasingOptions {
+        priority: Some(priority),
+        ..LeasingOptions::default()
+    };
+
+    http_request_leasing_with_options(
+        client,
+        environment,
+        application_id,
+        instance_id,
         duration,
+        &options,
+    )
+    .await
+}
+
+/// Like `http_request_leasing`, with a priority and metadata from `options`.
+pub async fn http_request_leasing_with_options(
+    client: &Client,
+    environment: &Environment,
+    application_id: &str,
+    instance_id: &str,
+    duration: u64,
+    options: &LeasingOptions,
+) -> LldResult<LeasingResult> {
+    let request = RestLeasingRequest {
+        namespace: environment.namespace.clone(),
+        application_id: application_id.to_owned(),
+        instance_id: instance_id.to_owned(),
+        duration: Some(duration),
+        wait: None,
+        priority: options.priority,
+        metadata: options.metadata.clone(),
     };
 
-    let response = client
-        .post(&environment.http_request_uri)
-        .json(&request)
+    http_send_leasing_request(client, environment, &request).await
+}
+
+/// Like `http_request_leasing`, but the server waits up to `timeout` ms for the leasing to become
+/// free before it rejects the request.
+pub async fn http_wait_for_leasing(
+    client: &Client,
+    environment: &Environment,
+    application_id: &str,
+    instance_id: &str,
+    duration: u64,
+    options: &LeasingOptions,
+    timeout: u64,
+) -> LldResult<LeasingResult> {
+    let request = RestLeasingRequest {
+        namespace: environment.namespace.clone(),
+        application_id: application_id.to_owned(),
+        instance_id: instance_id.to_owned(),
+        duration: Some(duration),
+        wait: Some(timeout),
+        priority: options.priority,
+        metadata: options.metadata.clone(),
+    };
+
+    http_send_leasing_request(client, environment, &request).await
+}
+
+async fn http_send_leasing_request(
+    client: &Client,
+    environment: &Environment,
+    request: &RestLeasingRequest,
+) -> LldResult<LeasingResult> {
+    let response = environment
+        .authorize(client.post(&environment.http_request_uri))
+        .json(request)
         .send()
         .await?
         .json::<RestLeasingResponse>()
         .await?;
 
+    match response {
+        RestLeasingResponse::Granted {
+            validity,
+            token,
+            duration,
+        } => Ok(LeasingResult::Granted(LeasingGrant {
+            validity,
+            token,
+            duration,
+        })),
+        RestLeasingResponse::Rejected {
+            remaining,
+            instance_id,
+            metadata,
+        } => Ok(LeasingResult::Rejected(LeasingRejection {
+            remaining,
+            instance_id,
+            metadata,
+        })),
+        RestLeasingResponse::Preempted => Ok(LeasingResult::Preempted),
+        RestLeasingResponse::Invalid { reason } => Err(LldError::WrappedError(
+            "http_request_leasing - invalid request",
+            reason,
+        )),
+        RestLeasingResponse::Unauthorized { reason } => Err(LldError::Unauthorized(reason)),
+        RestLeasingResponse::Unavailable { retry_after } => {
+            Ok(LeasingResult::Unavailable { retry_after })
+        }
+        RestLeasingResponse::Error => Err(LldError::WrappedError(
+            "http_request_leasing - server error",
+            "Receive error response!".to_owned(),
+        )),
+    }
+}
+
+/// Acquire all `application_ids` with a single validity, or none of them.
+pub async fn http_request_leasings(
+    client: &Client,
+    environment: &Environment,
+    application_ids: &[String],
+    instance_id: &str,
+    duration: u64,
+) -> LldResult<MultiLeasingResult> {
+    let request = RestMultiLeasingRequest {
+        namespace: environment.namespace.clone(),
+        application_ids: application_ids.to_vec(),
+        instance_id: instance_id.to_owned(),
+        duration: Some(duration),
+    };
+
+    let response = environment
+        .authorize(client.post(environment.http_uri("request-all")?))
+        .json(&request)
+        .send()
+        .await?
+        .json::<RestMultiLeasingResponse>()
+        .await?;
+
+    match response {
+        RestMultiLeasingResponse::Granted {
+            validity,
+            duration,
+            tokens,
+        } => Ok(MultiLeasingResult::Granted(MultiLeasingGrant {
+            validity,
+            duration,
+            tokens,
+        })),
+        RestMultiLeasingResponse::Rejected {
+            application_id,
+            remaining,
+            instance_id,
+            metadata,
+        } => Ok(MultiLeasingResult::Rejected {
+            application_id,
+            rejection: LeasingRejection {
+                remaining,
+                instance_id,
+                metadata,
+            },
+        }),
+        RestMultiLeasingResponse::Invalid { reason } => Err(LldError::WrappedError(
+            "http_request_leasings - invalid request",
+            reason,
+        )),
+        RestMultiLeasingResponse::Unauthorized { reason } => Err(LldError::Unauthorized(reason)),
+        RestMultiLeasingResponse::Unavailable { retry_after } => {
+            Ok(MultiLeasingResult::Unavailable { retry_after })
+        }
+        RestMultiLeasingResponse::Error => Err(LldError::WrappedError(
+            "http_request_leasings - server error",
+            "Receive error response!".to_owned(),
+        )),
+    }
+}
+
+pub async fn http_release_leasing(
+    client: &Client,
+    environment: &Environment,
+    application_id: &str,
+    instance_id: &str,
+) -> LldResult<bool> {
+    let request = RestReleaseRequest {
+        namespace: environment.namespace.clone(),
+        application_id: application_id.to_owned(),
+        instance_id: instance_id.to_owned(),
+    };
+
+    let response = environment
+        .authorize(client.post(environment.http_uri("release")?))
+        .json(&request)
+        .send()
+        .await?
+        .json::<RestReleaseResponse>()
+        .await?;
+
     Ok(match response {
-        RestLeasingResponse::Granted { validity } => Some(validity),
-        RestLeasingResponse::Rejected => None,
-        RestLeasingResponse::Error => {
+        RestReleaseResponse::Released => true,
+        RestReleaseResponse::Rejected => false,
+        RestReleaseResponse::Unauthorized { reason } => return Err(LldError::Unauthorized(reason)),
+        RestReleaseResponse::Error => {
             error!("Receive error response!");
-            None
+            false
         }
     })
 }
 
-async fn tcp_request_leasing_socket<T>(
-    mut stream: T,
-    application_id: u64,
-    instance_id: u64,
+pub async fn http_query_leasing(
+    client: &Client,
+    environment: &Environment,
+    application_id: &str,
+) -> LldResult<Option<LeasingStatus>> {
+    let mut request = environment
+        .authorize(client.get(environment.http_uri(&format!("leases/{}", application_id))?));
+    if let Some(namespace) = &environment.namespace {
+        request = request.query(&[("namespace", namespace)]);
+    }
+
+    let response = request.send().await?.json::<RestStatusResponse>().await?;
+
+    Ok(match response {
+        RestStatusResponse::Leased {
+            instance_id,
+            validity,
+            remaining,
+            token,
+            metadata,
+        } => Some(LeasingStatus {
+            instance_id,
+            validity,
+            remaining,
+            token,
+            metadata,
+        }),
+        RestStatusResponse::Free => None,
+        RestStatusResponse::Unauthorized { reason } => return Err(LldError::Unauthorized(reason)),
+        RestStatusResponse::Error => {
+            return Err(LldError::WrappedError(
+                "http_query_leasing - server error",
+                "Receive error response!".to_owned(),
+            ))
+        }
+    })
+}
+
+pub async fn tcp_request_leasing(
+    environment: &Environment,
+    application_id: &str,
+    instance_id: &str,
     duration: u64,
-) -> LldResult<Option<u64>>
-where
-    T: AsyncRead + AsyncWrite + Unpin,
-{
-    let packet = pack_tcp_packet(application_id, instance_id, duration);
-    tokio::io::AsyncWriteExt::write_all(&mut stream, &packet)
-        .await
-        .map_err(|error| {
-            LldError::WrappedError(
-                "tcp_request_leasing - write_all error",
-                format!("{}", error),
-            )
-        })?;
-
-    let result = tokio::io::AsyncReadExt::read_u8(&mut stream)
+) -> LldResult<LeasingResult> {
+    TcpConnection::connect(environment)
+        .await?
+        .request_leasing(application_id, instance_id, duration)
         .await
-        .map_err(|error| {
-            LldError::WrappedError("tcp_request_leasing - read_u8 error", format!("{}", error))
-        })?;
+}
 
-    if result == 48 {
-        let now = get_current_time();
-        Ok(Some(duration + now))
-    } else {
-        Ok(None)
-    }
+/// Acquire all `application_ids` with a single validity, or none of them.
+pub async fn tcp_request_leasings(
+    environment: &Environment,
+    application_ids: &[String],
+    instance_id: &str,
+    duration: u64,
+) -> LldResult<MultiLeasingResult> {
+    TcpConnection::connect(environment)
+        .await?
+        .request_leasings(application_ids, instance_id, duration)
+        .await
 }
 
-pub async fn tcp_request_leasing(
+/// Like `tcp_request_leasing`, but the server waits up to `timeout` ms for the leasing to become
+/// free before it rejects the request.
+pub async fn tcp_wait_for_leasing(
     environment: &Environment,
-    application_id: u64,
-    instance_id: u64,
+    application_id: &str,
+    instance_id: &str,
     duration: u64,
-) -> LldResult<Option<u64>> {
-    let stream = TcpStream::connect(&environment.tcp_request_uri)
+    options: &LeasingOptions,
+    timeout: u64,
+) -> LldResult<LeasingResult> {
+    TcpConnection::connect(environment)
+        .await?
+        .wait_for_leasing(application_id, instance_id, duration, options, timeout)
         .await
-        .map_err(|error| {
-            LldError::WrappedError("tcp_request_leasing - connect error", format!("{}", error))
-        })?;
+}
 
-    if let Some(ref certificate_file) = environment.ssl_cert_file {
-        let mut connector = SslConnector::builder(SslMethod::tls())?;
-        connector.set_ca_file(certificate_file)?;
-        let ssl = connector.build().configure()?.into_ssl("localhost")?;
+/// Like `tcp_request_leasing`, but rejected if the instance does not hold the leasing already.
+pub async fn tcp_renew_leasing(
+    environment: &Environment,
+    application_id: &str,
+    instance_id: &str,
+    duration: u64,
+) -> LldResult<LeasingResult> {
+    TcpConnection::connect(environment)
+        .await?
+        .renew_leasing(application_id, instance_id, duration)
+        .await
+}
 
-        let mut stream = SslStream::new(ssl, stream)?;
+/// Stream the events of all leasings matched by `filter`, until the connection is closed.
+pub async fn tcp_watch_leasings(
+    environment: &Environment,
+    filter: WatchFilter,
+) -> LldResult<mpsc::UnboundedReceiver<LeasingEvent>> {
+    TcpConnection::connect(environment)
+        .await?
+        .watch_leasings(filter)
+        .await
+}
 
-        Pin::new(&mut stream).connect().await?;
+pub async fn tcp_release_leasing(
+    environment: &Environment,
+    application_id: &str,
+    instance_id: &str,
+) -> LldResult<bool> {
+    TcpConnection::connect(environment)
+        .await?
+        .release_leasing(application_id, instance_id)
+        .await
+}
 
-        tcp_request_leasing_socket(stream, application_id, instance_id, duration).await
-    } else {
-        tcp_request_leasing_socket(stream, application_id, instance_id, duration).await
-    }
+pub async fn tcp_query_leasing(
+    environment: &Environment,
+    application_id: &str,
+) -> LldResult<Option<LeasingStatus>> {
+    TcpConnection::connect(environment)
+        .await?
+        .query_leasing(application_id)
+        .await
 }
 
 pub fn generate_random_id<const T: usize>() -> String {
     let mut buffer = [0u8; T];
     thread_rng().fill_bytes(&mut buffer);
-    base64::encode(&buffer)
+    base64::encode(buffer)
 }
 
 pub fn generate_random_u64() -> u64 {
@@ -180,29 +912,3 @@ pub fn get_current_time() -> u64 {
         .expect("Time went backwards")
         .as_millis() as u64
 }
-
-pub fn unpack_tcp_packet(packet: [u8; 24]) -> (String, String, u64) {
-    let application_id = base64::encode(&packet[0..8]);
-    let instance_id = base64::encode(&packet[8..16]);
-    let mut duration_slice: &[u8] = &packet[16..24];
-    let duration = duration_slice.read_u64::<BigEndian>().unwrap_or(0);
-
-    (application_id, instance_id, duration)
-}
-
-pub fn pack_tcp_packet(application_id: u64, instance_id: u64, duration: u64) -> [u8; 24] {
-    let mut packet = [0u8; 24];
-    let mut buffer = packet.as_mut();
-
-    buffer
-        .write_u64::<BigEndian>(application_id)
-        .expect("Cannot write `application_id` to a tcp packet!");
-    buffer
-        .write_u64::<BigEndian>(instance_id)
-        .expect("Cannot write `instance_id` to a tcp packet!");
-    buffer
-        .write_u64::<BigEndian>(duration)
-        .expect("Cannot write `duration` to a tcp packet!");
-
-    packet
-}
@@ -0,0 +1,675 @@
+use std::net::SocketAddr;
+
+use warp::Filter;
+
+use crate::context::Context;
+use crate::http_api::serve;
+use crate::shutdown::Shutdown;
+use crate::{ApiOptions, SslContext};
+
+/// Listings of leasings and namespaces, forced changes of leasings and the management of api
+/// keys, on an address of their own that can be kept away from the clients.
+pub async fn start_server(
+    context: Context,
+    options: ApiOptions,
+    addr: SocketAddr,
+    ssl_context: Option<SslContext>,
+    shutdown: Shutdown,
+) {
+    let metrics = context.metrics().clone();
+    serve(
+        addr,
+        ssl_context,
+        metrics,
+        shutdown,
+        move |certificate_names| {
+            filters::admin(context.clone(), options.clone(), certificate_names)
+                .with(warp::log("admin_api"))
+        },
+    )
+    .await;
+}
+
+mod filters {
+    use super::handlers;
+    use crate::context::Context;
+    use crate::http_api::filters::{caller, json_body, with_context, with_options};
+    use crate::ApiOptions;
+    use lld_common::{
+        RestCredentialRequest, RestCredentialRevokeRequest, RestRevokeRequest, RestTransferRequest,
+    };
+    use serde::Deserialize;
+    use warp::Filter;
+
+    pub fn admin(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        list_leasings(context.clone(), certificate_names.clone())
+            .or(revoke_leasing(
+                context.clone(),
+                options.clone(),
+                certificate_names.clone(),
+            ))
+            .or(transfer_leasing(
+                context.clone(),
+                options.clone(),
+                certificate_names.clone(),
+            ))
+            .or(list_namespaces(
+                context.clone(),
+                options.clone(),
+                certificate_names.clone(),
+            ))
+            .or(delete_namespace(context.clone(), certificate_names.clone()))
+            .or(add_credential(
+                context.clone(),
+                options,
+                certificate_names.clone(),
+            ))
+            .or(revoke_credential(context, certificate_names))
+    }
+
+    /// A page of the holders of all namespaces, or of the namespace given by `?namespace=`.
+    pub fn list_leasings(
+        context: Context,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "leases")
+            .and(warp::get())
+            .and(warp::query::<LeasingsQuery>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and_then(handlers::list_leasings)
+    }
+
+    pub fn revoke_leasing(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "leases" / "revoke")
+            .and(warp::post())
+            .and(json_body::<RestRevokeRequest>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::revoke_leasing)
+    }
+
+    pub fn transfer_leasing(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "leases" / "transfer")
+            .and(warp::post())
+            .and(json_body::<RestTransferRequest>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::transfer_leasing)
+    }
+
+    /// All known namespaces with their number of valid leasings.
+    pub fn list_namespaces(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "namespaces")
+            .and(warp::get())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::list_namespaces)
+    }
+
+    /// Drop all leasings of a namespace.
+    pub fn delete_namespace(
+        context: Context,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "namespaces" / String)
+            .and(warp::delete())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and_then(handlers::delete_namespace)
+    }
+
+    /// Grant a new api key access to leasings, only possible with an admin key.
+    pub fn add_credential(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "credentials")
+            .and(warp::post())
+            .and(json_body::<RestCredentialRequest>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::add_credential)
+    }
+
+    pub fn revoke_credential(
+        context: Context,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("admin" / "credentials")
+            .and(warp::delete())
+            .and(json_body::<RestCredentialRevokeRequest>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and_then(handlers::revoke_credential)
+    }
+
+    /// Filters of the leasing listing, `expires_before` and `expires_after` are times in ms like
+    /// the validity. Expired holders are only listed with `expired=true`.
+    #[derive(Debug, Deserialize)]
+    pub struct LeasingsQuery {
+        pub namespace: Option<String>,
+        pub instance_id: Option<String>,
+        pub expires_before: Option<u64>,
+        pub expires_after: Option<u64>,
+        #[serde(default)]
+        pub expired: bool,
+        #[serde(default)]
+        pub offset: usize,
+        pub limit: Option<usize>,
+    }
+}
+
+mod handlers {
+    use lld_common::{
+        get_current_time, LldError, RestAdminLeasingResponse, RestCredentialRequest,
+        RestCredentialResponse, RestCredentialRevokeRequest, RestLeasing, RestLeasingsResponse,
+        RestNamespace, RestNamespaceResponse, RestRevokeRequest, RestTransferRequest,
+    };
+
+    use super::filters::LeasingsQuery;
+    use crate::auth::{Caller, Scope};
+    use crate::cache::LeasingFilter;
+    use crate::context::{Context, LeasingResponse};
+    use crate::ApiOptions;
+    use std::convert::Infallible;
+
+    const DEFAULT_PAGE_SIZE: usize = 100;
+    const MAX_PAGE_SIZE: usize = 1000;
+
+    pub async fn list_leasings(
+        query: LeasingsQuery,
+        caller: Caller,
+        context: Context,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let filter = LeasingFilter {
+            namespace: query.namespace,
+            instance_id: query.instance_id,
+            expires_before: query.expires_before,
+            expires_after: query.expires_after,
+            include_expired: query.expired,
+        };
+
+        let leasings = match context.list_leasings(&caller, &filter).await {
+            Ok(leasings) => leasings,
+            Err(LldError::Unauthorized(reason)) => {
+                return Ok(warp::reply::json(&RestLeasingsResponse::Unauthorized {
+                    reason,
+                }))
+            }
+            Err(e) => {
+                error!("Error while listing the leasings {:?}", e);
+                return Ok(warp::reply::json(&RestLeasingsResponse::Error));
+            }
+        };
+
+        let now = get_current_time();
+        let total = leasings.len() as u64;
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        let leasings = leasings
+            .into_iter()
+            .skip(query.offset)
+            .take(limit)
+            .map(|(id, leasing)| RestLeasing {
+                namespace: id.namespace,
+                application_id: id.application_id,
+                instance_id: leasing.instance_id,
+                validity: leasing.validity,
+                remaining: leasing.validity.saturating_sub(now),
+                token: leasing.token,
+                priority: leasing.priority,
+                preempted: leasing.preempted,
+                metadata: leasing.metadata,
+            })
+            .collect();
+
+        Ok(warp::reply::json(&RestLeasingsResponse::Leasings {
+            leasings,
+            total,
+        }))
+    }
+
+    pub async fn revoke_leasing(
+        request: RestRevokeRequest,
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = options.leasing_id(request.namespace, request.application_id);
+        let response = context
+            .revoke_leasing(&caller, id, request.instance_id)
+            .await;
+
+        Ok(warp::reply::json(&to_admin_response(response)))
+    }
+
+    /// The duration policy applies to a requested duration, the validity of the former holder
+    /// is kept otherwise.
+    pub async fn transfer_leasing(
+        request: RestTransferRequest,
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = options.leasing_id(request.namespace, request.application_id);
+        let duration = match request.duration {
+            Some(duration) => match options.effective_duration(&id, Some(duration)) {
+                Ok(duration) => Some(duration),
+                Err(reason) => {
+                    return Ok(warp::reply::json(&RestAdminLeasingResponse::Invalid {
+                        reason,
+                    }))
+                }
+            },
+            None => None,
+        };
+
+        let response = context
+            .transfer_leasing(
+                &caller,
+                id,
+                request.instance_id,
+                request.to_instance_id,
+                duration,
+            )
+            .await;
+
+        Ok(warp::reply::json(&to_admin_response(response)))
+    }
+
+    fn to_admin_response(response: Result<LeasingResponse, LldError>) -> RestAdminLeasingResponse {
+        match response {
+            Ok(LeasingResponse::Released) => RestAdminLeasingResponse::Revoked,
+            Ok(LeasingResponse::Granted { validity, token }) => {
+                RestAdminLeasingResponse::Transferred { validity, token }
+            }
+            Ok(LeasingResponse::Rejected(_)) => RestAdminLeasingResponse::Rejected,
+            Ok(LeasingResponse::Preempted | LeasingResponse::Unavailable { .. }) => {
+                RestAdminLeasingResponse::Error
+            }
+            Err(LldError::Unauthorized(reason)) => {
+                RestAdminLeasingResponse::Unauthorized { reason }
+            }
+            Err(e) => {
+                error!("Error while changing the leasing {:?}", e);
+                RestAdminLeasingResponse::Error
+            }
+        }
+    }
+
+    /// Namespaces with a duration policy are listed even without leasings, like the default
+    /// namespace.
+    pub async fn list_namespaces(
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let mut counts = match context.count_namespaces(&caller).await {
+            Ok(counts) => counts,
+            Err(LldError::Unauthorized(reason)) => {
+                return Ok(warp::reply::json(&RestNamespaceResponse::Unauthorized {
+                    reason,
+                }))
+            }
+            Err(e) => {
+                error!("Error while counting the namespaces {:?}", e);
+                return Ok(warp::reply::json(&RestNamespaceResponse::Error));
+            }
+        };
+
+        counts.entry(options.default_namespace.clone()).or_insert(0);
+        for namespace in options.duration_policy.namespaces() {
+            counts.entry(namespace.clone()).or_insert(0);
+        }
+
+        let mut namespaces: Vec<RestNamespace> = counts
+            .into_iter()
+            .map(|(namespace, leasings)| RestNamespace {
+                namespace,
+                leasings,
+            })
+            .collect();
+        namespaces.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+
+        Ok(warp::reply::json(&RestNamespaceResponse::Namespaces {
+            namespaces,
+        }))
+    }
+
+    /// The fencing tokens of a deleted namespace continue above its highest token once it is
+    /// used again.
+    pub async fn delete_namespace(
+        namespace: String,
+        caller: Caller,
+        context: Context,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match context.delete_namespace(&caller, namespace).await {
+            Ok(leasings) => warp::reply::json(&RestNamespaceResponse::Deleted { leasings }),
+            Err(LldError::Unauthorized(reason)) => {
+                warp::reply::json(&RestNamespaceResponse::Unauthorized { reason })
+            }
+            Err(e) => {
+                error!("Error while deleting the namespace {:?}", e);
+                warp::reply::json(&RestNamespaceResponse::Error)
+            }
+        })
+    }
+
+    pub async fn add_credential(
+        request: RestCredentialRequest,
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let scope = Scope::new(options.namespace(request.namespace), request.application_id);
+
+        Ok(
+            match context.credentials().insert(&caller, request.key, scope) {
+                Ok(()) => warp::reply::json(&RestCredentialResponse::Added),
+                Err(LldError::Unauthorized(reason)) => {
+                    warp::reply::json(&RestCredentialResponse::Unauthorized { reason })
+                }
+                Err(e) => {
+                    error!("Error while adding a credential {:?}", e);
+                    warp::reply::json(&RestCredentialResponse::Error)
+                }
+            },
+        )
+    }
+
+    pub async fn revoke_credential(
+        request: RestCredentialRevokeRequest,
+        caller: Caller,
+        context: Context,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match context.credentials().remove(&caller, &request.key) {
+            Ok(true) => warp::reply::json(&RestCredentialResponse::Revoked),
+            Ok(false) => warp::reply::json(&RestCredentialResponse::Unknown),
+            Err(LldError::Unauthorized(reason)) => {
+                warp::reply::json(&RestCredentialResponse::Unauthorized { reason })
+            }
+            Err(e) => {
+                error!("Error while revoking a credential {:?}", e);
+                warp::reply::json(&RestCredentialResponse::Error)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use lld_common::{RestAdminLeasingResponse, RestLeasing, RestLeasingsResponse};
+    use serde::de::DeserializeOwned;
+    use serde_json::json;
+
+    use super::filters;
+    use crate::auth::{Caller, Credentials};
+    use crate::cache::LeasingId;
+    use crate::context::{Context, LeasingResponse};
+    use crate::context_batching::ContextBatching;
+    use crate::context_naive::ContextNaive;
+    use crate::database;
+    use crate::epoch::Epoch;
+    use crate::policy::{CapacityPolicy, DurationLimits, DurationMode, DurationPolicy, Preemption};
+    use crate::ApiOptions;
+
+    fn options() -> ApiOptions {
+        let limits = DurationLimits {
+            min: 1,
+            max: 60_000,
+            default: 10_000,
+        };
+        ApiOptions {
+            expose_holder: true,
+            duration_policy: Arc::new(DurationPolicy::new(DurationMode::Reject, limits).unwrap()),
+            max_wait: 0,
+            max_metadata_length: 1024,
+            default_namespace: "default".to_owned(),
+            ready_timeout: 1000,
+        }
+    }
+
+    /// A naive and a batching context with their own database, the batching worker runs until
+    /// the test ends.
+    fn contexts(name: &str, credentials: Credentials) -> Vec<Context> {
+        let credentials = Arc::new(credentials);
+        let capacities = Arc::new(CapacityPolicy::new(1).unwrap());
+
+        let naive = ContextNaive::new(
+            database::tests::open(&format!("{}-naive", name)),
+            capacities.clone(),
+            credentials.clone(),
+            Preemption::Disabled,
+            Epoch::new(1, 0, None),
+        )
+        .unwrap();
+        let batching = ContextBatching::new(
+            database::tests::open(&format!("{}-batching", name)),
+            capacities,
+            credentials,
+            Preemption::Disabled,
+            Epoch::new(1, 0, None),
+        )
+        .unwrap();
+
+        let batching = Context::Batching(batching);
+        let worker = batching.clone();
+        tokio::spawn(async move { worker.run().await });
+
+        vec![Context::Naive(naive), batching]
+    }
+
+    async fn grant(context: &Context, namespace: &str, application_id: &str, instance_id: &str) {
+        let response = context
+            .request_leasing(
+                &Caller::new(None, &[]),
+                LeasingId::new(namespace, application_id),
+                instance_id.to_owned(),
+                10_000,
+                None,
+                Some(format!("{}-metadata", instance_id)),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(response, LeasingResponse::Granted { .. }));
+    }
+
+    async fn get<T: DeserializeOwned>(context: &Context, path: &str) -> T {
+        let response = warp::test::request()
+            .method("GET")
+            .path(path)
+            .reply(&filters::admin(context.clone(), options(), Vec::new()))
+            .await;
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    async fn post<T: DeserializeOwned>(
+        context: &Context,
+        path: &str,
+        body: serde_json::Value,
+    ) -> T {
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .json(&body)
+            .reply(&filters::admin(context.clone(), options(), Vec::new()))
+            .await;
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    async fn list(context: &Context, query: &str) -> (Vec<RestLeasing>, u64) {
+        match get(context, &format!("/admin/leases?{}", query)).await {
+            RestLeasingsResponse::Leasings { leasings, total } => (leasings, total),
+            response => panic!("leasings were not listed: {:?}", response),
+        }
+    }
+
+    fn application_ids(leasings: &[RestLeasing]) -> Vec<&str> {
+        leasings
+            .iter()
+            .map(|leasing| leasing.application_id.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lists_pages_of_the_matching_holders() {
+        for context in contexts("admin-list", Credentials::disabled()) {
+            for application_id in ["a", "b", "c", "d"] {
+                grant(&context, "default", application_id, "x").await;
+            }
+            grant(&context, "default", "e", "y").await;
+            grant(&context, "other", "a", "x").await;
+
+            let (leasings, total) = list(&context, "").await;
+            assert_eq!(total, 6);
+            assert_eq!(leasings.len(), 6);
+
+            let (leasings, total) = list(&context, "namespace=default&offset=1&limit=2").await;
+            assert_eq!(total, 5);
+            assert_eq!(application_ids(&leasings), vec!["b", "c"]);
+
+            let (leasings, total) = list(&context, "namespace=default&offset=4&limit=2").await;
+            assert_eq!(total, 5);
+            assert_eq!(application_ids(&leasings), vec!["e"]);
+
+            let (leasings, total) = list(&context, "instance_id=y").await;
+            assert_eq!(total, 1);
+            assert_eq!(leasings[0].instance_id, "y");
+            assert_eq!(leasings[0].metadata.as_deref(), Some("y-metadata"));
+
+            let (leasings, total) = list(&context, "namespace=other").await;
+            assert_eq!(total, 1);
+            assert_eq!(leasings[0].namespace, "other");
+
+            // All holders expire within the next 10s
+            let (_, total) = list(&context, "expires_after=1").await;
+            assert_eq!(total, 6);
+            let (_, total) = list(&context, "expires_before=1").await;
+            assert_eq!(total, 0);
+
+            context.stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn revokes_a_holder() {
+        for context in contexts("admin-revoke", Credentials::disabled()) {
+            grant(&context, "default", "a", "x").await;
+
+            let request = json!({"application_id": "a", "instance_id": "x"});
+            let response = post(&context, "/admin/leases/revoke", request.clone()).await;
+            assert!(matches!(response, RestAdminLeasingResponse::Revoked));
+            assert_eq!(list(&context, "").await.1, 0);
+
+            let response = post(&context, "/admin/leases/revoke", request).await;
+            assert!(matches!(response, RestAdminLeasingResponse::Rejected));
+
+            // The leasing is free for others right away
+            grant(&context, "default", "a", "y").await;
+
+            context.stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn transfers_a_holder_with_its_metadata() {
+        for context in contexts("admin-transfer", Credentials::disabled()) {
+            grant(&context, "default", "a", "x").await;
+            let (leasings, _) = list(&context, "").await;
+            let before = leasings[0].clone();
+
+            let request = json!({"application_id": "a", "instance_id": "x", "to_instance_id": "y"});
+            let response = post(&context, "/admin/leases/transfer", request.clone()).await;
+            let token = match response {
+                RestAdminLeasingResponse::Transferred { validity, token } => {
+                    assert_eq!(validity, before.validity);
+                    token
+                }
+                response => panic!("leasing was not transferred: {:?}", response),
+            };
+            assert!(token > before.token);
+
+            let (leasings, total) = list(&context, "").await;
+            assert_eq!(total, 1);
+            assert_eq!(leasings[0].instance_id, "y");
+            assert_eq!(leasings[0].token, token);
+            assert_eq!(leasings[0].metadata.as_deref(), Some("x-metadata"));
+
+            // x does not hold the leasing anymore
+            let response = post(&context, "/admin/leases/transfer", request).await;
+            assert!(matches!(response, RestAdminLeasingResponse::Rejected));
+
+            let request = json!({
+                "application_id": "a",
+                "instance_id": "y",
+                "to_instance_id": "z",
+                "duration": 120_000,
+            });
+            let response = post(&context, "/admin/leases/transfer", request).await;
+            assert!(matches!(response, RestAdminLeasingResponse::Invalid { .. }));
+
+            context.stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn listing_all_namespaces_needs_an_admin_key() {
+        let file = std::env::temp_dir().join(format!("lld-{}-admin-keys", std::process::id()));
+        std::fs::write(&file, "admin,*,\nuser,default,\n").unwrap();
+        let mut credentials = Credentials::disabled();
+        credentials
+            .load_keys(file.to_str().unwrap(), "default")
+            .unwrap();
+
+        for context in contexts("admin-keys", credentials) {
+            let filter = filters::admin(context.clone(), options(), Vec::new());
+            let list = |key: &str, query: &str| {
+                warp::test::request()
+                    .method("GET")
+                    .path(&format!("/admin/leases?{}", query))
+                    .header("authorization", format!("Bearer {}", key))
+                    .reply(&filter)
+            };
+
+            let response = list("user", "").await;
+            let response: RestLeasingsResponse = serde_json::from_slice(response.body()).unwrap();
+            assert!(matches!(
+                response,
+                RestLeasingsResponse::Unauthorized { .. }
+            ));
+
+            let response = list("user", "namespace=default").await;
+            let response: RestLeasingsResponse = serde_json::from_slice(response.body()).unwrap();
+            assert!(matches!(response, RestLeasingsResponse::Leasings { .. }));
+
+            let response = list("admin", "").await;
+            let response: RestLeasingsResponse = serde_json::from_slice(response.body()).unwrap();
+            assert!(matches!(response, RestLeasingsResponse::Leasings { .. }));
+
+            context.stop();
+        }
+    }
+}
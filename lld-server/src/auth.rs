@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use lld_common::{LldError, WatchFilter};
+use openssl::nid::Nid;
+use openssl::x509::X509Ref;
+
+use crate::{cache::LeasingId, LldResult};
+
+/// Namespace of keys that grant access to everything, including the admin endpoints.
+pub const SCOPE_ALL: &str = "*";
+
+/// The leasings a key grants access to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    All,
+    Namespace(String),
+    Application(LeasingId),
+}
+
+impl Scope {
+    /// The scope of a key for `application_id` in `namespace`, or for the whole namespace if the
+    /// application is absent.
+    pub fn new(namespace: String, application_id: Option<String>) -> Self {
+        if namespace == SCOPE_ALL {
+            return Scope::All;
+        }
+
+        match application_id.filter(|id| !id.is_empty()) {
+            Some(application_id) => Scope::Application(LeasingId {
+                namespace,
+                application_id,
+            }),
+            None => Scope::Namespace(namespace),
+        }
+    }
+
+    fn covers(&self, id: &LeasingId) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Namespace(namespace) => *namespace == id.namespace,
+            Scope::Application(scope) => scope == id,
+        }
+    }
+
+    fn covers_namespace(&self, namespace: &str) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Namespace(scope) => scope == namespace,
+            Scope::Application(_) => false,
+        }
+    }
+}
+
+/// Who sent a request: the api key of the request and the names of the verified client
+/// certificate of its connection, if any.
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    pub api_key: Option<String>,
+    pub certificate_names: Vec<String>,
+}
+
+impl Caller {
+    pub fn new(api_key: Option<String>, certificate_names: &[String]) -> Self {
+        Self {
+            api_key,
+            certificate_names: certificate_names.to_vec(),
+        }
+    }
+}
+
+/// Api keys, client certificate names and the leasings they grant access to.
+///
+/// Authentication is disabled unless keys or certificate names are loaded from a file, then every
+/// request needs a key or a client certificate whose scope covers the requested leasing. Keys
+/// added at runtime are lost on restart.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    required: bool,
+    keys: RwLock<HashMap<String, Scope>>,
+    /// Scopes per common name or subject alternative name of client certificates.
+    identities: HashMap<String, Vec<Scope>>,
+}
+
+impl Credentials {
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Load keys from a csv file and require authentication from now on.
+    ///
+    /// Every line has the form `key,namespace,application_id`. An empty namespace is the default
+    /// namespace, an empty application grants access to the whole namespace and the namespace `*`
+    /// grants access to everything. Empty lines and lines starting with `#` are ignored.
+    pub fn load_keys(&mut self, file: &str, default_namespace: &str) -> LldResult<()> {
+        let keys = self.keys.get_mut().unwrap();
+        for (key, scope) in read_credentials_file(file, default_namespace)? {
+            keys.insert(key, scope);
+        }
+
+        self.required = true;
+        Ok(())
+    }
+
+    /// Load the scopes of client certificates from a csv file and require authentication from now
+    /// on.
+    ///
+    /// Every line has the form `name,namespace,application_id`, where `name` is the common name or
+    /// a subject alternative name of the certificate. A name may be listed several times, the
+    /// other fields are read like the ones of `load_keys`.
+    pub fn load_identities(&mut self, file: &str, default_namespace: &str) -> LldResult<()> {
+        for (name, scope) in read_credentials_file(file, default_namespace)? {
+            self.identities.entry(name).or_default().push(scope);
+        }
+
+        self.required = true;
+        Ok(())
+    }
+
+    /// Whether a key or a client certificate name grants access to the admin endpoints.
+    pub fn has_admin(&self) -> bool {
+        self.keys
+            .read()
+            .unwrap()
+            .values()
+            .chain(self.identities.values().flatten())
+            .any(|scope| *scope == Scope::All)
+    }
+
+    pub fn authorize(&self, caller: &Caller, id: &LeasingId) -> LldResult<()> {
+        self.check(
+            caller,
+            |scope| scope.covers(id),
+            || format!("the leasing {}", id),
+        )
+    }
+
+    pub fn authorize_namespace(&self, caller: &Caller, namespace: &str) -> LldResult<()> {
+        self.check(
+            caller,
+            |scope| scope.covers_namespace(namespace),
+            || format!("the namespace {}", namespace),
+        )
+    }
+
+    /// Watchers of a single application only need a key for that application.
+    pub fn authorize_watch(
+        &self,
+        caller: &Caller,
+        namespace: &str,
+        filter: &WatchFilter,
+    ) -> LldResult<()> {
+        match filter {
+            WatchFilter::Application(application_id) => {
+                self.authorize(caller, &LeasingId::new(namespace, application_id))
+            }
+            WatchFilter::Prefix(_) => self.authorize_namespace(caller, namespace),
+        }
+    }
+
+    pub fn authorize_admin(&self, caller: &Caller) -> LldResult<()> {
+        self.check(
+            caller,
+            |scope| *scope == Scope::All,
+            || "the admin endpoints".to_owned(),
+        )
+    }
+
+    /// Add or replace a key, only possible if authentication is required.
+    pub fn insert(&self, caller: &Caller, key: String, scope: Scope) -> LldResult<()> {
+        self.authorize_management(caller)?;
+
+        self.keys.write().unwrap().insert(key, scope);
+        Ok(())
+    }
+
+    /// Remove a key, returns whether it existed.
+    pub fn remove(&self, caller: &Caller, key: &str) -> LldResult<bool> {
+        self.authorize_management(caller)?;
+
+        Ok(self.keys.write().unwrap().remove(key).is_some())
+    }
+
+    fn authorize_management(&self, caller: &Caller) -> LldResult<()> {
+        if !self.required {
+            return Err(LldError::Unauthorized(
+                "Authentication is disabled, keys can only be managed with a credentials file"
+                    .to_owned(),
+            ));
+        }
+        self.authorize_admin(caller)
+    }
+
+    /// The request is accepted if the scope of its key or of any name of its client certificate
+    /// covers the target, `target` names what was requested in case it is refused.
+    fn check<F, T>(&self, caller: &Caller, covers: F, target: T) -> LldResult<()>
+    where
+        F: Fn(&Scope) -> bool,
+        T: Fn() -> String,
+    {
+        if !self.required {
+            return Ok(());
+        }
+
+        if caller
+            .certificate_names
+            .iter()
+            .filter_map(|name| self.identities.get(name))
+            .flatten()
+            .any(&covers)
+        {
+            return Ok(());
+        }
+
+        let api_key = match (&caller.api_key, caller.certificate_names.is_empty()) {
+            (Some(api_key), _) => api_key,
+            (None, true) => return Err(LldError::Unauthorized("Missing api key".to_owned())),
+            (None, false) => {
+                return Err(LldError::Unauthorized(format!(
+                    "The client certificate is not valid for {}",
+                    target()
+                )))
+            }
+        };
+        match self.keys.read().unwrap().get(api_key) {
+            Some(scope) if covers(scope) => Ok(()),
+            Some(_) => Err(LldError::Unauthorized(format!(
+                "The api key is not valid for {}",
+                target()
+            ))),
+            None => Err(LldError::Unauthorized("Unknown api key".to_owned())),
+        }
+    }
+}
+
+/// The common names and the dns, email and uri subject alternative names of a certificate.
+pub fn certificate_names(certificate: &X509Ref) -> Vec<String> {
+    let mut names: Vec<String> = certificate
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+        .collect();
+
+    if let Some(alt_names) = certificate.subject_alt_names() {
+        for alt_name in alt_names.iter() {
+            if let Some(name) = alt_name
+                .dnsname()
+                .or_else(|| alt_name.email())
+                .or_else(|| alt_name.uri())
+            {
+                names.push(name.to_owned());
+            }
+        }
+    }
+
+    names
+}
+
+/// Read the `name,namespace,application_id` lines of a credentials file.
+fn read_credentials_file(file: &str, default_namespace: &str) -> LldResult<Vec<(String, Scope)>> {
+    let content = std::fs::read_to_string(file)?;
+    let mut credentials = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if fields.len() != 3 || fields[0].is_empty() {
+            return Err(LldError::WrappedError(
+                "credentials error",
+                format!(
+                    "{}:{}: expected `name,namespace,application_id`",
+                    file,
+                    index + 1
+                ),
+            ));
+        }
+
+        let namespace = match fields[1] {
+            "" => default_namespace,
+            namespace => namespace,
+        };
+        let scope = Scope::new(namespace.to_owned(), Some(fields[2].to_owned()));
+        credentials.push((fields[0].to_owned(), scope));
+    }
+
+    Ok(credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(name: &str, keys: &str) -> Credentials {
+        let file = std::env::temp_dir().join(format!("lld-{}-{}", std::process::id(), name));
+        std::fs::write(&file, keys).unwrap();
+        let mut credentials = Credentials::disabled();
+        credentials
+            .load_keys(file.to_str().unwrap(), "default")
+            .unwrap();
+        std::fs::remove_file(&file).unwrap();
+        credentials
+    }
+
+    fn caller(api_key: &str) -> Caller {
+        Caller::new(Some(api_key.to_owned()), &[])
+    }
+
+    fn id(namespace: &str, application_id: &str) -> LeasingId {
+        LeasingId::new(namespace, application_id)
+    }
+
+    fn unauthorized(result: LldResult<()>, expected: &str) {
+        match result {
+            Err(LldError::Unauthorized(message)) => assert_eq!(message, expected),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn disabled_credentials_accept_everything() {
+        let credentials = Credentials::disabled();
+
+        credentials
+            .authorize(&Caller::default(), &id("default", "app"))
+            .unwrap();
+        credentials
+            .authorize_namespace(&Caller::default(), "other")
+            .unwrap();
+        credentials.authorize_admin(&Caller::default()).unwrap();
+        assert!(!credentials.has_admin());
+    }
+
+    #[test]
+    fn keys_are_checked_against_their_scope() {
+        let credentials = credentials(
+            "auth-scopes",
+            "# comment\n\napp,,app\nnamespace,other,\nadmin,*,\n",
+        );
+
+        unauthorized(
+            credentials.authorize(&Caller::default(), &id("default", "app")),
+            "Missing api key",
+        );
+        unauthorized(
+            credentials.authorize(&caller("unknown"), &id("default", "app")),
+            "Unknown api key",
+        );
+
+        credentials
+            .authorize(&caller("app"), &id("default", "app"))
+            .unwrap();
+        unauthorized(
+            credentials.authorize(&caller("app"), &id("default", "other")),
+            "The api key is not valid for the leasing default/other",
+        );
+        unauthorized(
+            credentials.authorize_namespace(&caller("app"), "default"),
+            "The api key is not valid for the namespace default",
+        );
+
+        credentials
+            .authorize(&caller("namespace"), &id("other", "app"))
+            .unwrap();
+        credentials
+            .authorize_namespace(&caller("namespace"), "other")
+            .unwrap();
+        unauthorized(
+            credentials.authorize(&caller("namespace"), &id("default", "app")),
+            "The api key is not valid for the leasing default/app",
+        );
+        unauthorized(
+            credentials.authorize_admin(&caller("namespace")),
+            "The api key is not valid for the admin endpoints",
+        );
+
+        credentials
+            .authorize(&caller("admin"), &id("default", "app"))
+            .unwrap();
+        credentials
+            .authorize_namespace(&caller("admin"), "other")
+            .unwrap();
+        credentials.authorize_admin(&caller("admin")).unwrap();
+    }
+
+    #[test]
+    fn keys_are_inserted_replaced_and_removed() {
+        let credentials = credentials("auth-manage", "admin,*,\n");
+        let admin = caller("admin");
+
+        credentials
+            .insert(
+                &admin,
+                "key".to_owned(),
+                Scope::new("default".to_owned(), None),
+            )
+            .unwrap();
+        credentials
+            .authorize(&caller("key"), &id("default", "app"))
+            .unwrap();
+
+        credentials
+            .insert(
+                &admin,
+                "key".to_owned(),
+                Scope::new("default".to_owned(), Some("other".to_owned())),
+            )
+            .unwrap();
+        unauthorized(
+            credentials.authorize(&caller("key"), &id("default", "app")),
+            "The api key is not valid for the leasing default/app",
+        );
+
+        assert!(credentials.remove(&admin, "key").unwrap());
+        assert!(!credentials.remove(&admin, "key").unwrap());
+        unauthorized(
+            credentials.authorize(&caller("key"), &id("default", "other")),
+            "Unknown api key",
+        );
+
+        assert!(matches!(
+            credentials.insert(&caller("unknown"), "key".to_owned(), Scope::All),
+            Err(LldError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            Credentials::disabled().remove(&admin, "admin"),
+            Err(LldError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn admin_keys_are_detected() {
+        assert!(!credentials("auth-no-admin", "user,default,\n").has_admin());
+
+        let credentials = credentials("auth-admin", "admin,*,\n");
+        assert!(credentials.has_admin());
+        assert!(credentials.remove(&caller("admin"), "admin").unwrap());
+        assert!(!credentials.has_admin());
+    }
+
+    #[test]
+    fn certificate_names_are_checked_against_their_scopes() {
+        let file = std::env::temp_dir().join(format!("lld-{}-auth-identities", std::process::id()));
+        std::fs::write(&file, "client,,app\nclient,other,\n").unwrap();
+        let mut credentials = credentials("auth-identity-keys", "key,default,\n");
+        credentials
+            .load_identities(file.to_str().unwrap(), "default")
+            .unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let client = Caller::new(None, &["unknown".to_owned(), "client".to_owned()]);
+        credentials
+            .authorize(&client, &id("default", "app"))
+            .unwrap();
+        credentials.authorize_namespace(&client, "other").unwrap();
+        unauthorized(
+            credentials.authorize(&client, &id("default", "other")),
+            "The client certificate is not valid for the leasing default/other",
+        );
+
+        let client = Caller::new(Some("key".to_owned()), &["client".to_owned()]);
+        credentials
+            .authorize(&client, &id("default", "other"))
+            .unwrap();
+    }
+
+    #[test]
+    fn certificates_are_named_by_common_and_alternative_names() {
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::x509::extension::SubjectAlternativeName;
+        use openssl::x509::{X509Name, X509};
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "client").unwrap();
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "lld")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        let alt_names = SubjectAlternativeName::new()
+            .dns("client.example.com")
+            .email("client@example.com")
+            .uri("spiffe://example.com/client")
+            .ip("127.0.0.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(alt_names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        assert_eq!(
+            certificate_names(&builder.build()),
+            vec![
+                "client",
+                "client.example.com",
+                "client@example.com",
+                "spiffe://example.com/client",
+            ]
+        );
+    }
+}
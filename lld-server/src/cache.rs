@@ -1,126 +1,1348 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+};
 
+use lld_common::{LeasingEvent, LeasingEventKind, LeasingRejection};
 use tokio::sync::RwLock;
 
-use crate::{database::Database, LldResult};
+use crate::{
+    context::LeasingResponse,
+    database::{Database, DatabaseTask},
+    policy::{CapacityPolicy, Preemption},
+    LldResult,
+};
 
-pub type CacheMap = HashMap<String, (String, u64)>;
+#[derive(Debug, Clone)]
+pub struct Leasing {
+    pub instance_id: String,
+    pub validity: u64,
+    /// Fencing token, increased for every new holder of the application.
+    pub token: u64,
+    pub priority: u32,
+    /// The holder has to give up the leasing at its next renewal.
+    pub preempted: bool,
+    /// Opaque data of the holder, returned with status queries and rejections.
+    pub metadata: Option<String>,
+}
+
+impl Leasing {
+    /// Describe the rejection of another instance at time `now`.
+    pub fn to_rejection(&self, now: u64) -> LeasingRejection {
+        if self.validity > now {
+            LeasingRejection {
+                remaining: self.validity - now,
+                instance_id: Some(self.instance_id.clone()),
+                metadata: self.metadata.clone(),
+            }
+        } else {
+            LeasingRejection::default()
+        }
+    }
+}
+
+/// Rejection for an application without free capacity, naming the holder that expires first.
+pub fn to_capacity_rejection(holders: &[Leasing], now: u64) -> LeasingRejection {
+    holders
+        .iter()
+        .filter(|leasing| leasing.validity > now)
+        .min_by_key(|leasing| leasing.validity)
+        .map(|leasing| leasing.to_rejection(now))
+        .unwrap_or_default()
+}
+
+/// Identifies the leasing of an application within a namespace.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LeasingId {
+    pub namespace: String,
+    pub application_id: String,
+}
+
+impl LeasingId {
+    pub fn new(namespace: &str, application_id: &str) -> Self {
+        Self {
+            namespace: namespace.to_owned(),
+            application_id: application_id.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for LeasingId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.application_id)
+    }
+}
+
+/// All holders per leasing, including expired ones whose rows are reused by new holders.
+pub type CacheMap = HashMap<LeasingId, Vec<Leasing>>;
+
+/// Selects leasings for the admin listing, absent fields match every leasing.
+#[derive(Debug, Clone, Default)]
+pub struct LeasingFilter {
+    pub namespace: Option<String>,
+    pub instance_id: Option<String>,
+    /// Only leasings whose validity is before this time.
+    pub expires_before: Option<u64>,
+    /// Only leasings whose validity is after this time.
+    pub expires_after: Option<u64>,
+    /// Include the rows of expired and released holders.
+    pub include_expired: bool,
+}
+
+impl LeasingFilter {
+    fn matches(&self, id: &LeasingId, leasing: &Leasing, now: u64) -> bool {
+        self.namespace
+            .as_ref()
+            .is_none_or(|namespace| *namespace == id.namespace)
+            && self
+                .instance_id
+                .as_ref()
+                .is_none_or(|instance_id| *instance_id == leasing.instance_id)
+            && self
+                .expires_before
+                .is_none_or(|time| leasing.validity < time)
+            && self
+                .expires_after
+                .is_none_or(|time| leasing.validity > time)
+            && (self.include_expired || leasing.validity > now)
+    }
+}
+
+/// How a request competes with the current holders of an application.
+#[derive(Debug, Clone, Copy)]
+pub struct Admission {
+    pub capacity: u64,
+    /// Priority of the request, a holder keeps its priority if absent.
+    pub priority: Option<u32>,
+    pub preemption: Preemption,
+    /// The lowest fencing token of a new holder.
+    pub first_token: u64,
+    /// Only extend a valid leasing of the instance, never acquire one.
+    pub renewal: bool,
+}
 
 #[derive(Debug, Clone)]
 pub struct ContextCache {
     cache: Arc<RwLock<CacheMap>>,
+    /// Increased whenever the cache is reloaded from the database, changes that were decided in
+    /// an earlier generation must not be stored anymore.
+    generation: Arc<AtomicU64>,
+    /// Highest fencing token of every deleted namespace.
+    deleted_tokens: Arc<RwLock<HashMap<String, u64>>>,
 }
 
 #[derive(Debug)]
 pub enum CacheResult {
-    Rejected,
+    Rejected(LeasingRejection),
+    /// Rejected, but the holder `instance_id` gives up the leasing at its next renewal.
+    PreemptionScheduled {
+        id: LeasingId,
+        instance_id: String,
+        rejection: LeasingRejection,
+    },
     GrantedInsert {
-        application_id: String,
+        id: LeasingId,
         instance_id: String,
         validity: u64,
+        token: u64,
+        priority: u32,
+        metadata: Option<String>,
     },
     GrantedUpdate {
-        application_id: String,
+        id: LeasingId,
+        /// The holder whose leasing is taken over, `instance_id` itself or an expired holder.
+        previous_instance_id: String,
         instance_id: String,
         validity: u64,
+        token: u64,
+        priority: u32,
+        metadata: Option<String>,
+        /// The current holder extended its valid leasing.
+        renewed: bool,
+        /// The holder that lost its leasing to this request.
+        preempted: Option<Leasing>,
     },
+    Released {
+        id: LeasingId,
+        instance_id: String,
+        validity: u64,
+        token: u64,
+        /// The holder renewed after its preemption was scheduled.
+        preempted: bool,
+    },
+}
+
+impl CacheResult {
+    pub fn is_granted(&self) -> bool {
+        matches!(
+            self,
+            CacheResult::GrantedInsert { .. } | CacheResult::GrantedUpdate { .. }
+        )
+    }
+
+    pub fn to_response(&self) -> LeasingResponse {
+        match self {
+            CacheResult::Rejected(rejection)
+            | CacheResult::PreemptionScheduled { rejection, .. } => {
+                LeasingResponse::Rejected(rejection.clone())
+            }
+            CacheResult::GrantedInsert {
+                validity, token, ..
+            }
+            | CacheResult::GrantedUpdate {
+                validity, token, ..
+            } => LeasingResponse::Granted {
+                validity: *validity,
+                token: *token,
+            },
+            CacheResult::Released {
+                preempted: false, ..
+            } => LeasingResponse::Released,
+            CacheResult::Released {
+                preempted: true, ..
+            } => LeasingResponse::Preempted,
+        }
+    }
+
+    /// The tasks that store the result in the database.
+    pub fn to_tasks(&self) -> Vec<DatabaseTask> {
+        match self {
+            CacheResult::Rejected(_) => Vec::new(),
+            CacheResult::PreemptionScheduled {
+                id, instance_id, ..
+            } => vec![DatabaseTask::Preempt {
+                id: id.clone(),
+                instance_id: instance_id.to_owned(),
+            }],
+            CacheResult::GrantedInsert {
+                id,
+                instance_id,
+                validity,
+                token,
+                priority,
+                metadata,
+            } => vec![DatabaseTask::Insert {
+                id: id.clone(),
+                instance_id: instance_id.to_owned(),
+                validity: *validity,
+                token: *token,
+                priority: *priority,
+                metadata: metadata.clone(),
+            }],
+            CacheResult::GrantedUpdate {
+                id,
+                previous_instance_id,
+                instance_id,
+                validity,
+                token,
+                priority,
+                metadata,
+                preempted,
+                ..
+            } => {
+                let mut tasks = Vec::with_capacity(2);
+
+                // The row of the preempted holder is only released if it is not taken over
+                if let Some(preempted) = preempted {
+                    if &preempted.instance_id != previous_instance_id {
+                        tasks.push(DatabaseTask::Release {
+                            id: id.clone(),
+                            instance_id: preempted.instance_id.to_owned(),
+                            validity: preempted.validity,
+                        });
+                    }
+                }
+
+                tasks.push(DatabaseTask::Update {
+                    id: id.clone(),
+                    previous_instance_id: previous_instance_id.to_owned(),
+                    instance_id: instance_id.to_owned(),
+                    validity: *validity,
+                    token: *token,
+                    priority: *priority,
+                    metadata: metadata.clone(),
+                });
+                tasks
+            }
+            CacheResult::Released {
+                id,
+                instance_id,
+                validity,
+                ..
+            } => vec![DatabaseTask::Release {
+                id: id.clone(),
+                instance_id: instance_id.to_owned(),
+                validity: *validity,
+            }],
+        }
+    }
+
+    /// The events to emit once the result is stored.
+    pub fn to_events(&self) -> Vec<LeasingEvent> {
+        let event = |kind, id: &LeasingId, instance_id: &str, validity, token| LeasingEvent {
+            kind,
+            namespace: id.namespace.clone(),
+            application_id: id.application_id.clone(),
+            instance_id: instance_id.to_owned(),
+            validity,
+            token,
+        };
+
+        match self {
+            CacheResult::Rejected(_) | CacheResult::PreemptionScheduled { .. } => Vec::new(),
+            CacheResult::GrantedInsert {
+                id,
+                instance_id,
+                validity,
+                token,
+                ..
+            } => vec![event(
+                LeasingEventKind::Granted,
+                id,
+                instance_id,
+                *validity,
+                *token,
+            )],
+            CacheResult::GrantedUpdate {
+                id,
+                instance_id,
+                validity,
+                token,
+                renewed,
+                preempted,
+                ..
+            } => {
+                let mut events = Vec::with_capacity(2);
+                if let Some(preempted) = preempted {
+                    events.push(event(
+                        LeasingEventKind::Preempted,
+                        id,
+                        &preempted.instance_id,
+                        preempted.validity,
+                        preempted.token,
+                    ));
+                }
+
+                let kind = if *renewed {
+                    LeasingEventKind::Renewed
+                } else {
+                    LeasingEventKind::Granted
+                };
+                events.push(event(kind, id, instance_id, *validity, *token));
+                events
+            }
+            CacheResult::Released {
+                id,
+                instance_id,
+                validity,
+                token,
+                preempted,
+            } => {
+                let kind = if *preempted {
+                    LeasingEventKind::Preempted
+                } else {
+                    LeasingEventKind::Released
+                };
+                vec![event(kind, id, instance_id, *validity, *token)]
+            }
+        }
+    }
 }
 
 impl ContextCache {
     pub fn new(db: &Database) -> LldResult<Self> {
         let cache = db.build_cache()?;
+        let deleted_tokens = db.deleted_tokens()?;
 
         Ok(Self {
             cache: Arc::new(RwLock::new(cache)),
+            generation: Arc::new(AtomicU64::new(0)),
+            deleted_tokens: Arc::new(RwLock::new(deleted_tokens)),
         })
     }
 
+    /// The lowest fencing token of a new holder in `namespace`, above the tokens it had before
+    /// it was deleted. Only called under the lock of the cache, which is taken first.
+    async fn first_token(&self, namespace: &str, first_token: u64) -> u64 {
+        let deleted_token = self.deleted_tokens.read().await.get(namespace).copied();
+        after_deletion(first_token, deleted_token)
+    }
+
+    /// The generation that decides the next requests, read before the decision.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Replace the cache with the `leasings` read from the database, after changes of the cache
+    /// could not be stored.
+    ///
+    /// The generation is increased even if the database could not be read, because the cache
+    /// may still hold changes that were not stored.
+    pub async fn reload(&self, leasings: LldResult<CacheMap>) -> LldResult<()> {
+        let mut cache = self.cache.write().await;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *cache = leasings?;
+        Ok(())
+    }
+
+    /// Decide a leasing request of `instance_id` among the current `holders`.
+    ///
+    /// Without free capacity, a request may preempt the valid holder with the lowest priority
+    /// below its own. Depending on the preemption mode the holder loses the leasing right away,
+    /// or at its next renewal. Only one preemption per application is pending at a time.
     pub fn to_cache_result(
-        application_id: String,
+        id: LeasingId,
         instance_id: String,
         duration: u64,
+        admission: Admission,
+        metadata: Option<String>,
         now: u64,
-        result: Option<(String, u64)>,
+        holders: &[Leasing],
     ) -> CacheResult {
-        match result {
-            Some((leased_instance_id, validity)) => {
-                if validity > now && leased_instance_id != instance_id {
-                    CacheResult::Rejected
-                } else {
-                    CacheResult::GrantedUpdate {
-                        application_id,
-                        instance_id,
-                        validity: now + duration,
-                    }
+        let validity = now.saturating_add(duration);
+        let own = holders
+            .iter()
+            .find(|leasing| leasing.instance_id == instance_id);
+
+        if let Some(own) = own.filter(|own| own.validity > now) {
+            if own.preempted && admission.preemption == Preemption::Renewal {
+                return CacheResult::Released {
+                    id,
+                    instance_id,
+                    validity: now,
+                    token: own.token,
+                    preempted: true,
+                };
+            }
+
+            return CacheResult::GrantedUpdate {
+                id,
+                previous_instance_id: instance_id.clone(),
+                instance_id,
+                validity,
+                token: own.token,
+                priority: admission.priority.unwrap_or(own.priority),
+                metadata: metadata.or_else(|| own.metadata.clone()),
+                renewed: true,
+                preempted: None,
+            };
+        }
+
+        if admission.renewal {
+            return CacheResult::Rejected(to_capacity_rejection(holders, now));
+        }
+
+        let priority = admission
+            .priority
+            .or_else(|| own.map(|own| own.priority))
+            .unwrap_or(0);
+        let metadata = metadata.or_else(|| own.and_then(|own| own.metadata.clone()));
+
+        let active = holders.iter().filter(|leasing| leasing.validity > now);
+        if (active.clone().count() as u64) < admission.capacity {
+            return ContextCache::to_grant(
+                id,
+                instance_id,
+                validity,
+                priority,
+                metadata,
+                now,
+                holders,
+                None,
+                admission.first_token,
+            );
+        }
+
+        let victim = active
+            .clone()
+            .filter(|leasing| leasing.priority < priority)
+            .min_by_key(|leasing| (leasing.priority, leasing.validity));
+
+        match (admission.preemption, victim) {
+            (Preemption::Immediate, Some(victim)) => {
+                let victim = Leasing {
+                    validity: now,
+                    ..victim.clone()
+                };
+                let holders: Vec<Leasing> = holders
+                    .iter()
+                    .map(|leasing| {
+                        if leasing.instance_id == victim.instance_id {
+                            victim.clone()
+                        } else {
+                            leasing.clone()
+                        }
+                    })
+                    .collect();
+
+                ContextCache::to_grant(
+                    id,
+                    instance_id,
+                    validity,
+                    priority,
+                    metadata,
+                    now,
+                    &holders,
+                    Some(victim),
+                    admission.first_token,
+                )
+            }
+            (Preemption::Renewal, Some(victim)) => {
+                let mut active = active;
+                match active.find(|leasing| leasing.preempted) {
+                    Some(pending) => CacheResult::Rejected(pending.to_rejection(now)),
+                    None => CacheResult::PreemptionScheduled {
+                        id,
+                        instance_id: victim.instance_id.clone(),
+                        rejection: victim.to_rejection(now),
+                    },
                 }
             }
+            _ => CacheResult::Rejected(to_capacity_rejection(holders, now)),
+        }
+    }
+
+    /// Grant the leasing to `instance_id`, which is known to fit into the capacity.
+    ///
+    /// A holder keeps its fencing token when it acquires again, a new holder gets a token above
+    /// all tokens of the application, and at least `first_token`. It takes over the row of an
+    /// expired holder if there is one.
+    #[allow(clippy::too_many_arguments)]
+    fn to_grant(
+        id: LeasingId,
+        instance_id: String,
+        validity: u64,
+        priority: u32,
+        metadata: Option<String>,
+        now: u64,
+        holders: &[Leasing],
+        preempted: Option<Leasing>,
+        first_token: u64,
+    ) -> CacheResult {
+        if let Some(own) = holders
+            .iter()
+            .find(|leasing| leasing.instance_id == instance_id)
+        {
+            return CacheResult::GrantedUpdate {
+                id,
+                previous_instance_id: instance_id.clone(),
+                instance_id,
+                validity,
+                token: own.token,
+                priority,
+                metadata,
+                renewed: false,
+                preempted,
+            };
+        }
+
+        let token = next_token(holders, first_token);
+
+        match holders.iter().find(|leasing| leasing.validity <= now) {
+            Some(expired) => CacheResult::GrantedUpdate {
+                id,
+                previous_instance_id: expired.instance_id.clone(),
+                instance_id,
+                validity,
+                token,
+                priority,
+                metadata,
+                renewed: false,
+                preempted,
+            },
             None => CacheResult::GrantedInsert {
-                application_id,
+                id,
+                instance_id,
+                validity,
+                token,
+                priority,
+                metadata,
+            },
+        }
+    }
+
+    pub fn to_release_result(
+        id: LeasingId,
+        instance_id: String,
+        now: u64,
+        holders: &[Leasing],
+    ) -> CacheResult {
+        match holders
+            .iter()
+            .find(|leasing| leasing.instance_id == instance_id && leasing.validity > now)
+        {
+            Some(leasing) => CacheResult::Released {
+                id,
                 instance_id,
-                validity: now + duration,
+                validity: now,
+                token: leasing.token,
+                preempted: false,
             },
+            None => CacheResult::Rejected(to_capacity_rejection(holders, now)),
+        }
+    }
+
+    /// Hand the valid leasing of `instance_id` over to `to_instance_id`, which must not hold one.
+    ///
+    /// The new holder gets a new fencing token of at least `first_token` and keeps the validity,
+    /// unless a `duration` is given. The former holder is treated like a preempted one. An expired
+    /// row of the new holder is taken over, so that it does not end up with two rows.
+    pub fn to_transfer_result(
+        id: LeasingId,
+        instance_id: String,
+        to_instance_id: String,
+        duration: Option<u64>,
+        now: u64,
+        holders: &[Leasing],
+        first_token: u64,
+    ) -> CacheResult {
+        let valid = |instance_id: &str| {
+            holders
+                .iter()
+                .find(|leasing| leasing.instance_id == instance_id && leasing.validity > now)
+        };
+
+        let own = match valid(&instance_id) {
+            Some(own) if valid(&to_instance_id).is_none() => own,
+            _ => return CacheResult::Rejected(to_capacity_rejection(holders, now)),
+        };
+
+        let token = next_token(holders, first_token);
+
+        let previous_instance_id = if holders
+            .iter()
+            .any(|leasing| leasing.instance_id == to_instance_id)
+        {
+            to_instance_id.clone()
+        } else {
+            instance_id
+        };
+
+        CacheResult::GrantedUpdate {
+            id,
+            previous_instance_id,
+            instance_id: to_instance_id,
+            validity: duration.map_or(own.validity, |duration| now.saturating_add(duration)),
+            token,
+            priority: own.priority,
+            metadata: own.metadata.clone(),
+            renewed: false,
+            preempted: Some(Leasing {
+                validity: now,
+                ..own.clone()
+            }),
         }
     }
 
     pub async fn request_leasing(
         &self,
-        application_id: String,
+        id: LeasingId,
         instance_id: String,
         duration: u64,
+        admission: Admission,
+        metadata: Option<String>,
         now: u64,
     ) -> LldResult<CacheResult> {
-        let result = {
+        let cache_result = {
             let cache = self.cache.read().await;
-            cache.get(&application_id).cloned()
+            ContextCache::to_cache_result(
+                id.clone(),
+                instance_id.clone(),
+                duration,
+                admission,
+                metadata.clone(),
+                now,
+                holders(&cache, &id),
+            )
         };
+        if let CacheResult::Rejected(_) = cache_result {
+            return Ok(cache_result);
+        }
 
-        let cache_result =
-            ContextCache::to_cache_result(application_id, instance_id, duration, now, result);
+        // The leasing may have changed while waiting for the write lock, so the decision is repeated
+        let mut cache = self.cache.write().await;
+        let admission = Admission {
+            first_token: self.first_token(&id.namespace, admission.first_token).await,
+            ..admission
+        };
+        let cache_result = ContextCache::to_cache_result(
+            id.clone(),
+            instance_id,
+            duration,
+            admission,
+            metadata,
+            now,
+            holders(&cache, &id),
+        );
+        ContextCache::store(&mut cache, &cache_result);
+
+        Ok(cache_result)
+    }
+
+    /// Decide all leasings together under one lock, they are only stored if all are granted.
+    ///
+    /// Multi leasing requests neither preempt other holders nor give up their own leasings.
+    pub async fn request_leasings(
+        &self,
+        ids: &[LeasingId],
+        instance_id: &str,
+        duration: u64,
+        capacities: &CapacityPolicy,
+        first_token: u64,
+        now: u64,
+    ) -> LldResult<Vec<CacheResult>> {
+        let mut cache = self.cache.write().await;
+        let deleted_tokens = self.deleted_tokens.read().await;
+
+        let cache_results: Vec<CacheResult> = ids
+            .iter()
+            .map(|id| {
+                let admission = Admission {
+                    capacity: capacities.capacity(id),
+                    priority: None,
+                    preemption: Preemption::Disabled,
+                    first_token: after_deletion(
+                        first_token,
+                        deleted_tokens.get(&id.namespace).copied(),
+                    ),
+                    renewal: false,
+                };
+                ContextCache::to_cache_result(
+                    id.clone(),
+                    instance_id.to_owned(),
+                    duration,
+                    admission,
+                    None,
+                    now,
+                    holders(&cache, id),
+                )
+            })
+            .collect();
+
+        if cache_results.iter().all(CacheResult::is_granted) {
+            for cache_result in &cache_results {
+                ContextCache::store(&mut cache, cache_result);
+            }
+        }
 
-        let cache_result = match cache_result {
-            CacheResult::Rejected => CacheResult::Rejected,
+        Ok(cache_results)
+    }
+
+    fn store(cache: &mut CacheMap, cache_result: &CacheResult) {
+        match cache_result {
+            CacheResult::Rejected(_) => {}
+            CacheResult::PreemptionScheduled {
+                id, instance_id, ..
+            } => {
+                if let Some(leasing) = find_holder(cache, id, instance_id) {
+                    leasing.preempted = true;
+                }
+            }
             CacheResult::GrantedInsert {
-                application_id,
+                id,
                 instance_id,
                 validity,
+                token,
+                priority,
+                metadata,
             } => {
-                let mut cache = self.cache.write().await;
-                if cache.get(&application_id).is_some() {
-                    CacheResult::Rejected
-                } else {
-                    cache.insert(
-                        application_id.to_owned(),
-                        (instance_id.to_owned(), now + duration),
-                    );
-                    CacheResult::GrantedInsert {
-                        application_id,
-                        instance_id,
-                        validity,
-                    }
-                }
+                cache.entry(id.clone()).or_default().push(Leasing {
+                    instance_id: instance_id.to_owned(),
+                    validity: *validity,
+                    token: *token,
+                    priority: *priority,
+                    preempted: false,
+                    metadata: metadata.clone(),
+                });
             }
             CacheResult::GrantedUpdate {
-                application_id,
+                id,
+                previous_instance_id,
                 instance_id,
                 validity,
+                token,
+                priority,
+                metadata,
+                preempted,
+                ..
             } => {
-                let mut cache = self.cache.write().await;
-                if cache.get(&application_id).unwrap().0.as_str() != instance_id {
-                    CacheResult::Rejected
-                } else {
-                    cache.insert(
-                        application_id.to_owned(),
-                        (instance_id.to_owned(), now + duration),
-                    );
-                    CacheResult::GrantedUpdate {
-                        application_id,
-                        instance_id,
-                        validity,
+                if let Some(preempted) = preempted {
+                    if let Some(leasing) = find_holder(cache, id, &preempted.instance_id) {
+                        leasing.validity = preempted.validity;
+                    }
+                }
+
+                // A stale row of the new holder would leave it with two rows
+                if previous_instance_id != instance_id {
+                    if let Some(leasings) = cache.get_mut(id) {
+                        leasings.retain(|leasing| leasing.instance_id != *instance_id);
                     }
                 }
+
+                if let Some(leasing) = find_holder(cache, id, previous_instance_id) {
+                    *leasing = Leasing {
+                        instance_id: instance_id.to_owned(),
+                        validity: *validity,
+                        token: *token,
+                        priority: *priority,
+                        preempted: false,
+                        metadata: metadata.clone(),
+                    };
+                }
             }
-        };
+            CacheResult::Released {
+                id,
+                instance_id,
+                validity,
+                ..
+            } => {
+                if let Some(leasing) = find_holder(cache, id, instance_id) {
+                    leasing.validity = *validity;
+                }
+            }
+        }
+    }
+
+    pub async fn query_holders(&self, id: &LeasingId) -> Vec<Leasing> {
+        let cache = self.cache.read().await;
+        holders(&cache, id).to_vec()
+    }
+
+    /// The number of holders in the cache, including expired ones.
+    pub async fn size(&self) -> usize {
+        let cache = self.cache.read().await;
+        cache.values().map(Vec::len).sum()
+    }
+
+    pub async fn list_leasings(
+        &self,
+        filter: &LeasingFilter,
+        now: u64,
+    ) -> Vec<(LeasingId, Leasing)> {
+        let cache = self.cache.read().await;
+        list_leasings(&cache, filter, now)
+    }
+
+    pub async fn count_namespaces(&self, now: u64) -> HashMap<String, u64> {
+        let cache = self.cache.read().await;
+        count_namespaces(&cache, now)
+    }
+
+    /// Drop all leasings of a namespace, the events tell their valid holders.
+    pub async fn delete_namespace(&self, namespace: &str, now: u64) -> Vec<LeasingEvent> {
+        let mut cache = self.cache.write().await;
+        let mut deleted_tokens = self.deleted_tokens.write().await;
+
+        let events = to_deletion_events(&cache, namespace, now);
+        if let Some(token) = highest_token(&cache, namespace) {
+            deleted_tokens.insert(namespace.to_owned(), token);
+        }
+        cache.retain(|id, _| id.namespace != namespace);
+
+        events
+    }
+
+    pub async fn release_leasing(
+        &self,
+        id: LeasingId,
+        instance_id: String,
+        now: u64,
+    ) -> LldResult<CacheResult> {
+        let mut cache = self.cache.write().await;
+
+        let cache_result =
+            ContextCache::to_release_result(id.clone(), instance_id, now, holders(&cache, &id));
+        ContextCache::store(&mut cache, &cache_result);
+
+        Ok(cache_result)
+    }
+
+    pub async fn transfer_leasing(
+        &self,
+        id: LeasingId,
+        instance_id: String,
+        to_instance_id: String,
+        duration: Option<u64>,
+        first_token: u64,
+        now: u64,
+    ) -> LldResult<CacheResult> {
+        let mut cache = self.cache.write().await;
+        let first_token = self.first_token(&id.namespace, first_token).await;
+
+        let cache_result = ContextCache::to_transfer_result(
+            id.clone(),
+            instance_id,
+            to_instance_id,
+            duration,
+            now,
+            holders(&cache, &id),
+            first_token,
+        );
+        ContextCache::store(&mut cache, &cache_result);
 
         Ok(cache_result)
     }
 }
+
+/// The token of a new holder, above the tokens of all `holders` and at least `first_token`.
+fn next_token(holders: &[Leasing], first_token: u64) -> u64 {
+    holders
+        .iter()
+        .map(|leasing| leasing.token + 1)
+        .max()
+        .unwrap_or(1)
+        .max(first_token)
+}
+
+/// The holders that match the filter, ordered by leasing and validity.
+pub fn list_leasings(
+    cache: &CacheMap,
+    filter: &LeasingFilter,
+    now: u64,
+) -> Vec<(LeasingId, Leasing)> {
+    let mut leasings: Vec<(LeasingId, Leasing)> = cache
+        .iter()
+        .flat_map(|(id, leasings)| {
+            leasings
+                .iter()
+                .filter(move |leasing| filter.matches(id, leasing, now))
+                .map(move |leasing| (id.clone(), leasing.clone()))
+        })
+        .collect();
+    leasings.sort_by(|(a, x), (b, y)| a.cmp(b).then(x.validity.cmp(&y.validity)));
+
+    leasings
+}
+
+/// The number of valid leasings per namespace, including namespaces without valid leasings.
+pub fn count_namespaces(cache: &CacheMap, now: u64) -> HashMap<String, u64> {
+    let mut namespaces = HashMap::new();
+
+    for (id, leasings) in cache {
+        let count = namespaces.entry(id.namespace.clone()).or_insert(0);
+        *count += leasings
+            .iter()
+            .filter(|leasing| leasing.validity > now)
+            .count() as u64;
+    }
+
+    namespaces
+}
+
+/// The highest fencing token of a namespace.
+fn highest_token(cache: &CacheMap, namespace: &str) -> Option<u64> {
+    cache
+        .iter()
+        .filter(|(id, _)| id.namespace == namespace)
+        .flat_map(|(_, leasings)| leasings.iter().map(|leasing| leasing.token))
+        .max()
+}
+
+/// The lowest fencing token of a new holder in a namespace whose highest token before it was
+/// deleted is `deleted_token`.
+pub fn after_deletion(first_token: u64, deleted_token: Option<u64>) -> u64 {
+    deleted_token.map_or(first_token, |token| first_token.max(token + 1))
+}
+
+/// Release events for the valid leasings of a namespace that is deleted.
+pub fn to_deletion_events(cache: &CacheMap, namespace: &str, now: u64) -> Vec<LeasingEvent> {
+    cache
+        .iter()
+        .filter(|(id, _)| id.namespace == namespace)
+        .flat_map(|(id, leasings)| {
+            leasings
+                .iter()
+                .filter(|leasing| leasing.validity > now)
+                .map(move |leasing| LeasingEvent {
+                    kind: LeasingEventKind::Released,
+                    namespace: id.namespace.clone(),
+                    application_id: id.application_id.clone(),
+                    instance_id: leasing.instance_id.clone(),
+                    validity: now,
+                    token: leasing.token,
+                })
+        })
+        .collect()
+}
+
+fn holders<'a>(cache: &'a CacheMap, id: &LeasingId) -> &'a [Leasing] {
+    cache.get(id).map(Vec::as_slice).unwrap_or_default()
+}
+
+fn find_holder<'a>(
+    cache: &'a mut CacheMap,
+    id: &LeasingId,
+    instance_id: &str,
+) -> Option<&'a mut Leasing> {
+    cache
+        .get_mut(id)?
+        .iter_mut()
+        .find(|leasing| leasing.instance_id == instance_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+
+    const NOW: u64 = 1_000_000;
+
+    fn id() -> LeasingId {
+        LeasingId::new("default", "a")
+    }
+
+    fn admission(capacity: u64) -> Admission {
+        Admission {
+            capacity,
+            priority: None,
+            preemption: Preemption::Disabled,
+            first_token: 0,
+            renewal: false,
+        }
+    }
+
+    /// Decide a request like `ContextCache::request_leasing` and store it in the cache and the
+    /// database.
+    fn request(
+        cache: &mut CacheMap,
+        db: &Database,
+        instance_id: &str,
+        capacity: u64,
+        now: u64,
+    ) -> CacheResult {
+        let cache_result = ContextCache::to_cache_result(
+            id(),
+            instance_id.to_owned(),
+            1000,
+            admission(capacity),
+            None,
+            now,
+            holders(cache, &id()),
+        );
+        apply(cache, db, cache_result)
+    }
+
+    fn release(cache: &mut CacheMap, db: &Database, instance_id: &str, now: u64) -> CacheResult {
+        let cache_result = ContextCache::to_release_result(
+            id(),
+            instance_id.to_owned(),
+            now,
+            holders(cache, &id()),
+        );
+        apply(cache, db, cache_result)
+    }
+
+    fn apply(cache: &mut CacheMap, db: &Database, cache_result: CacheResult) -> CacheResult {
+        db.execute_tasks(&cache_result.to_tasks()).unwrap();
+        ContextCache::store(cache, &cache_result);
+        cache_result
+    }
+
+    fn instances(cache: &CacheMap) -> Vec<String> {
+        let mut instances: Vec<String> = holders(cache, &id())
+            .iter()
+            .map(|leasing| leasing.instance_id.clone())
+            .collect();
+        instances.sort();
+        instances
+    }
+
+    #[test]
+    fn capacity_limits_the_valid_holders() {
+        let db = database::tests::open("capacity");
+        let mut cache = CacheMap::new();
+
+        assert!(request(&mut cache, &db, "A", 2, NOW).is_granted());
+        assert!(request(&mut cache, &db, "B", 2, NOW + 100).is_granted());
+
+        // The rejection names the holder that expires first
+        match request(&mut cache, &db, "C", 2, NOW + 200) {
+            CacheResult::Rejected(rejection) => {
+                assert_eq!(rejection.instance_id.as_deref(), Some("A"));
+                assert_eq!(rejection.remaining, 800);
+            }
+            result => panic!("leasing was granted: {:?}", result),
+        }
+
+        match request(&mut cache, &db, "C", 2, NOW + 1000) {
+            CacheResult::GrantedUpdate {
+                previous_instance_id,
+                ..
+            } => assert_eq!(previous_instance_id, "A"),
+            result => panic!("expired row was not taken over: {:?}", result),
+        }
+        assert_eq!(instances(&cache), vec!["B", "C"]);
+    }
+
+    #[test]
+    fn higher_priorities_preempt_the_lowest_holder() {
+        let db = database::tests::open("preemption");
+        let mut cache = CacheMap::new();
+        assert!(request(&mut cache, &db, "A", 2, NOW).is_granted());
+        assert!(request(&mut cache, &db, "B", 2, NOW + 100).is_granted());
+
+        let decide = |cache: &CacheMap, preemption, priority| {
+            let admission = Admission {
+                priority: Some(priority),
+                preemption,
+                ..admission(2)
+            };
+            ContextCache::to_cache_result(
+                id(),
+                "C".to_owned(),
+                1000,
+                admission,
+                None,
+                NOW + 200,
+                holders(cache, &id()),
+            )
+        };
+
+        assert!(!decide(&cache, Preemption::Immediate, 0).is_granted());
+        assert!(!decide(&cache, Preemption::Disabled, 1).is_granted());
+        assert!(matches!(
+            decide(&cache, Preemption::Renewal, 1),
+            CacheResult::PreemptionScheduled { instance_id, .. } if instance_id == "A"
+        ));
+
+        let cache_result = decide(&cache, Preemption::Immediate, 1);
+        match &cache_result {
+            CacheResult::GrantedUpdate {
+                preempted: Some(victim),
+                ..
+            } => assert_eq!(victim.instance_id, "A"),
+            result => panic!("no holder was preempted: {:?}", result),
+        }
+        apply(&mut cache, &db, cache_result);
+        assert_eq!(instances(&cache), vec!["B", "C"]);
+    }
+
+    fn token(cache_result: &CacheResult) -> u64 {
+        match cache_result {
+            CacheResult::GrantedInsert { token, .. } | CacheResult::GrantedUpdate { token, .. } => {
+                *token
+            }
+            result => panic!("leasing was not granted: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn tokens_increase_with_every_new_holder() {
+        let db = database::tests::open("token-monotonicity");
+        let mut cache = CacheMap::new();
+
+        assert_eq!(token(&request(&mut cache, &db, "A", 1, NOW)), 1);
+        // Renewals keep the token
+        assert_eq!(token(&request(&mut cache, &db, "A", 1, NOW + 500)), 1);
+        assert!(!request(&mut cache, &db, "B", 1, NOW + 500).is_granted());
+        assert_eq!(token(&request(&mut cache, &db, "B", 1, NOW + 1500)), 2);
+
+        let cache_result = ContextCache::to_transfer_result(
+            id(),
+            "B".to_owned(),
+            "C".to_owned(),
+            None,
+            NOW + 1600,
+            holders(&cache, &id()),
+            0,
+        );
+        assert_eq!(token(&apply(&mut cache, &db, cache_result)), 3);
+
+        release(&mut cache, &db, "C", NOW + 1700);
+        assert_eq!(token(&request(&mut cache, &db, "A", 1, NOW + 1700)), 4);
+
+        let tokens = |cache: &CacheMap| {
+            let mut tokens: Vec<u64> = holders(cache, &id())
+                .iter()
+                .map(|leasing| leasing.token)
+                .collect();
+            tokens.sort_unstable();
+            tokens
+        };
+        assert_eq!(tokens(&cache), vec![4]);
+        assert_eq!(tokens(&db.build_cache().unwrap()), vec![4]);
+    }
+
+    #[test]
+    fn transfer_takes_over_an_expired_row_of_the_new_holder() {
+        let db = database::tests::open("transfer-expired-row");
+        let mut cache = CacheMap::new();
+
+        assert!(request(&mut cache, &db, "B", 2, NOW).is_granted());
+        assert!(request(&mut cache, &db, "C", 2, NOW).is_granted());
+        release(&mut cache, &db, "B", NOW + 1);
+        release(&mut cache, &db, "C", NOW + 1);
+        assert!(request(&mut cache, &db, "A", 2, NOW + 2).is_granted());
+
+        let cache_result = ContextCache::to_transfer_result(
+            id(),
+            "A".to_owned(),
+            "C".to_owned(),
+            None,
+            NOW + 3,
+            holders(&cache, &id()),
+            0,
+        );
+        match &cache_result {
+            CacheResult::GrantedUpdate {
+                previous_instance_id,
+                instance_id,
+                ..
+            } => {
+                assert_eq!(previous_instance_id, "C");
+                assert_eq!(instance_id, "C");
+            }
+            result => panic!("transfer was not granted: {:?}", result),
+        }
+        apply(&mut cache, &db, cache_result);
+
+        assert_eq!(instances(&cache), vec!["A", "C"]);
+        assert_eq!(
+            instances(&db.build_cache().unwrap()),
+            vec!["A", "C"],
+            "database and cache differ"
+        );
+        let valid: Vec<&Leasing> = holders(&cache, &id())
+            .iter()
+            .filter(|leasing| leasing.validity > NOW + 3)
+            .collect();
+        assert_eq!(valid.len(), 1);
+        assert_eq!(valid[0].instance_id, "C");
+
+        // The leasing goes on as usual after the transfer
+        assert!(request(&mut cache, &db, "C", 2, NOW + 4).is_granted());
+        assert!(request(&mut cache, &db, "B", 2, NOW + 4).is_granted());
+    }
+
+    #[test]
+    fn new_holders_get_at_least_the_first_token() {
+        let holder = |token| Leasing {
+            instance_id: "A".to_owned(),
+            validity: NOW - 1,
+            token,
+            priority: 0,
+            preempted: false,
+            metadata: None,
+        };
+
+        assert_eq!(next_token(&[], 0), 1);
+        assert_eq!(next_token(&[holder(5)], 0), 6);
+        assert_eq!(next_token(&[holder(5)], 1 << 32), 1 << 32);
+        assert_eq!(next_token(&[holder((2 << 32) + 3)], 1 << 32), (2 << 32) + 4);
+
+        let mut admission = admission(1);
+        admission.first_token = 1 << 32;
+        let cache_result = ContextCache::to_cache_result(
+            id(),
+            "B".to_owned(),
+            1000,
+            admission,
+            None,
+            NOW,
+            &[holder(5)],
+        );
+        assert!(matches!(
+            cache_result,
+            CacheResult::GrantedUpdate { token, .. } if token == 1 << 32
+        ));
+    }
+
+    #[test]
+    fn renewals_only_extend_a_valid_leasing() {
+        let holder = |validity| Leasing {
+            instance_id: "A".to_owned(),
+            validity,
+            token: 1,
+            priority: 0,
+            preempted: false,
+            metadata: None,
+        };
+        let renew = |holders: &[Leasing]| {
+            let mut admission = admission(1);
+            admission.renewal = true;
+            ContextCache::to_cache_result(id(), "A".to_owned(), 1000, admission, None, NOW, holders)
+        };
+
+        assert!(matches!(
+            renew(&[holder(NOW + 1)]),
+            CacheResult::GrantedUpdate { renewed: true, validity, .. } if validity == NOW + 1000
+        ));
+        assert!(matches!(renew(&[holder(NOW)]), CacheResult::Rejected(_)));
+        assert!(matches!(renew(&[]), CacheResult::Rejected(_)));
+    }
+
+    #[tokio::test]
+    async fn tokens_continue_after_a_namespace_was_deleted() {
+        let db = database::tests::open("namespace-tokens");
+        let cache = ContextCache::new(&db).unwrap();
+        let grant = |instance_id: &str, now| {
+            cache.request_leasing(id(), instance_id.to_owned(), 1000, admission(1), None, now)
+        };
+
+        let cache_result = grant("A", NOW).await.unwrap();
+        db.execute_tasks(&cache_result.to_tasks()).unwrap();
+        let cache_result = grant("B", NOW + 2000).await.unwrap();
+        db.execute_tasks(&cache_result.to_tasks()).unwrap();
+        assert!(matches!(
+            cache_result,
+            CacheResult::GrantedUpdate { token: 2, .. }
+        ));
+
+        cache.delete_namespace("default", NOW + 2000).await;
+        db.execute_tasks(&[DatabaseTask::DeleteNamespace {
+            namespace: "default".to_owned(),
+        }])
+        .unwrap();
+        assert_eq!(db.deleted_token("default").unwrap(), Some(2));
+
+        let cache_result = grant("C", NOW + 2000).await.unwrap();
+        assert!(matches!(
+            cache_result,
+            CacheResult::GrantedInsert { token: 3, .. }
+        ));
+
+        // The tokens continue after a restart as well
+        let cache = ContextCache::new(&db).unwrap();
+        let cache_result = cache
+            .request_leasing(id(), "D".to_owned(), 1000, admission(1), None, NOW + 2000)
+            .await
+            .unwrap();
+        assert!(matches!(
+            cache_result,
+            CacheResult::GrantedInsert { token: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn store_drops_stale_rows_of_the_new_holder() {
+        let mut cache = CacheMap::new();
+        let leasing = |instance_id: &str, validity| Leasing {
+            instance_id: instance_id.to_owned(),
+            validity,
+            token: 1,
+            priority: 0,
+            preempted: false,
+            metadata: None,
+        };
+        cache.insert(id(), vec![leasing("A", NOW - 1), leasing("C", NOW - 1)]);
+
+        ContextCache::store(
+            &mut cache,
+            &CacheResult::GrantedUpdate {
+                id: id(),
+                previous_instance_id: "A".to_owned(),
+                instance_id: "C".to_owned(),
+                validity: NOW + 1000,
+                token: 2,
+                priority: 0,
+                metadata: None,
+                renewed: false,
+                preempted: None,
+            },
+        );
+
+        assert_eq!(instances(&cache), vec!["C"]);
+    }
+}
@@ -1,11 +1,27 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::time::Duration;
 
-use lld_common::get_current_time;
+use lld_common::{get_current_time, LeasingEvent, LeasingRejection, LeasingStatus, WatchFilter};
+use tokio::sync::broadcast;
+use tokio::time::{sleep, sleep_until, Instant};
 
+use crate::auth::{Caller, Credentials};
+use crate::cache::{CacheResult, Leasing, LeasingFilter, LeasingId};
 use crate::context_batching::ContextBatching;
 use crate::context_naive::ContextNaive;
+use crate::database::DatabaseTask;
+use crate::epoch::Epoch;
+use crate::metrics::Metrics;
+use crate::wait_queue::WaitQueue;
 use crate::LldResult;
 
+/// Lower bound for the time a waiter sleeps before it retries, in case the current holder is
+/// unknown.
+const WAIT_RETRY_INTERVAL: u64 = 10;
+
+/// Every operation takes the caller of its request and fails with `LldError::Unauthorized` if
+/// neither its api key nor its client certificate covers the leasing, see `Credentials`.
 #[derive(Clone)]
 pub enum Context {
     Naive(ContextNaive),
@@ -20,35 +36,749 @@ impl Context {
         }
     }
 
+    /// Stop the batching worker once it stored the waiting requests, `run` returns afterwards.
+    pub fn stop(&self) {
+        if let Context::Batching(context) = self {
+            context.stop();
+        }
+    }
+
+    /// Whether the server can still answer requests, which it cannot once the batching worker
+    /// stopped.
+    pub fn is_alive(&self) -> bool {
+        match self {
+            Context::Naive(_) => true,
+            Context::Batching(context) => context.is_alive(),
+        }
+    }
+
+    /// Check that requests are answered within `timeout` ms, which needs a database that answers
+    /// and a batching worker that drains its queue. Returns why the server is not ready
+    /// otherwise.
+    pub async fn check_ready(&self, timeout: u64) -> Result<(), String> {
+        if !self.is_alive() {
+            return Err("The batching worker stopped".to_owned());
+        }
+
+        let ready = async {
+            match self {
+                Context::Naive(context) => context.check_ready().await,
+                Context::Batching(context) => context.check_ready().await,
+            }
+        };
+        match tokio::time::timeout(Duration::from_millis(timeout), ready).await {
+            Ok(Ok(true)) => Ok(()),
+            Ok(Ok(false)) => Err("The database does not answer".to_owned()),
+            Ok(Err(e)) => Err(format!("{:?}", e)),
+            Err(_) => Err(format!("No answer within {}ms", timeout)),
+        }
+    }
+
+    /// Request a leasing, `priority` only matters if preemption is enabled. The holder keeps its
+    /// priority and metadata if they are absent.
     pub async fn request_leasing(
         &self,
-        application_id: String,
+        caller: &Caller,
+        id: LeasingId,
+        instance_id: String,
+        duration: u64,
+        priority: Option<u32>,
+        metadata: Option<String>,
+    ) -> LldResult<LeasingResponse> {
+        debug!("Request leasing for {} with duration {}", id, duration);
+        self.acquire(caller, id, instance_id, duration, priority, metadata, false)
+            .await
+    }
+
+    /// Decide a request, or a renewal that only extends a valid leasing of `instance_id`.
+    #[allow(clippy::too_many_arguments)]
+    async fn acquire(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
+        instance_id: String,
+        duration: u64,
+        priority: Option<u32>,
+        metadata: Option<String>,
+        renewal: bool,
+    ) -> LldResult<LeasingResponse> {
+        self.credentials().authorize(caller, &id)?;
+        let now = get_current_time();
+
+        if let Some(retry_after) = self.epoch().retry_after(&id, now) {
+            return Ok(LeasingResponse::Unavailable { retry_after });
+        }
+
+        let response = match self {
+            Context::Naive(context) => {
+                context
+                    .request_leasing(
+                        id.clone(),
+                        instance_id,
+                        duration,
+                        priority,
+                        metadata,
+                        renewal,
+                        now,
+                    )
+                    .await?
+            }
+            Context::Batching(context) => {
+                context
+                    .request_leasing(
+                        id.clone(),
+                        instance_id,
+                        duration,
+                        priority,
+                        metadata,
+                        renewal,
+                        now,
+                    )
+                    .await?
+            }
+        };
+
+        if let LeasingResponse::Preempted = response {
+            self.wait_queue().notify(&id);
+        }
+
+        Ok(response)
+    }
+
+    /// Acquire all leasings with a single validity, or none of them. The leasings are usually
+    /// of the same namespace.
+    pub async fn request_leasings(
+        &self,
+        caller: &Caller,
+        mut ids: Vec<LeasingId>,
+        instance_id: String,
+        duration: u64,
+    ) -> LldResult<MultiLeasingResponse> {
+        debug!("Request leasings for {:?} with duration {}", ids, duration);
+        for id in &ids {
+            self.credentials().authorize(caller, id)?;
+        }
+        let now = get_current_time();
+
+        if let Some(retry_after) = ids
+            .iter()
+            .filter_map(|id| self.epoch().retry_after(id, now))
+            .max()
+        {
+            return Ok(MultiLeasingResponse::Unavailable { retry_after });
+        }
+
+        ids.sort();
+        ids.dedup();
+
+        match self {
+            Context::Naive(context) => {
+                context
+                    .request_leasings(ids, instance_id, duration, now)
+                    .await
+            }
+            Context::Batching(context) => {
+                context
+                    .request_leasings(ids, instance_id, duration, now)
+                    .await
+            }
+        }
+    }
+
+    /// Like `request_leasing`, but wait up to `timeout` ms for the leasing to become free.
+    ///
+    /// Waiters are served in order of arrival. Only the first waiter retries, either when the
+    /// current leasing expires or when it is released.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn wait_for_leasing(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
         instance_id: String,
         duration: u64,
+        priority: Option<u32>,
+        metadata: Option<String>,
+        timeout: u64,
     ) -> LldResult<LeasingResponse> {
-        debug!(
-            "Request leasing for {} with duration {}",
-            application_id, duration
+        self.credentials().authorize(caller, &id)?;
+        let deadline = Instant::now() + Duration::from_millis(timeout);
+        let ticket = self.wait_queue().enter(&id);
+
+        loop {
+            if !ticket.is_head() {
+                tokio::select! {
+                    _ = ticket.notified() => continue,
+                    _ = sleep_until(deadline) => {
+                        return Ok(LeasingResponse::Rejected(
+                            self.to_rejection(caller, id).await?,
+                        ));
+                    }
+                }
+            }
+
+            let rejection = match self
+                .request_leasing(
+                    caller,
+                    id.clone(),
+                    instance_id.clone(),
+                    duration,
+                    priority,
+                    metadata.clone(),
+                )
+                .await?
+            {
+                LeasingResponse::Rejected(rejection) => rejection,
+                // Wait for the end of the quarantine if it ends in time
+                LeasingResponse::Unavailable { retry_after }
+                    if Instant::now() + Duration::from_millis(retry_after) < deadline =>
+                {
+                    sleep(Duration::from_millis(retry_after)).await;
+                    continue;
+                }
+                response => return Ok(response),
+            };
+
+            if Instant::now() >= deadline {
+                return Ok(LeasingResponse::Rejected(rejection));
+            }
+
+            let retry = Duration::from_millis(rejection.remaining.max(WAIT_RETRY_INTERVAL));
+            tokio::select! {
+                _ = ticket.notified() => {}
+                _ = sleep(retry) => {}
+                _ = sleep_until(deadline) => {}
+            }
+        }
+    }
+
+    /// Extend a leasing that is currently held by `instance_id`, without acquiring a free one.
+    ///
+    /// The check of the holder and the extension are decided together, so a leasing that expires
+    /// in between is rejected instead of acquired.
+    pub async fn renew_leasing(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
+        instance_id: String,
+        duration: u64,
+    ) -> LldResult<LeasingResponse> {
+        debug!("Renew leasing for {} with duration {}", id, duration);
+        self.acquire(caller, id, instance_id, duration, None, None, true)
+            .await
+    }
+
+    pub async fn release_leasing(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
+        instance_id: String,
+    ) -> LldResult<LeasingResponse> {
+        debug!("Release leasing for {}", id);
+        self.credentials().authorize(caller, &id)?;
+        let now = get_current_time();
+
+        let response = match self {
+            Context::Naive(context) => {
+                context
+                    .release_leasing(id.clone(), instance_id, now)
+                    .await?
+            }
+            Context::Batching(context) => {
+                context
+                    .release_leasing(id.clone(), instance_id, now)
+                    .await?
+            }
+        };
+
+        if let LeasingResponse::Released = response {
+            self.wait_queue().notify(&id);
+        }
+
+        Ok(response)
+    }
+
+    /// Take the leasing away from `instance_id`, as if it had released it.
+    pub async fn revoke_leasing(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
+        instance_id: String,
+    ) -> LldResult<LeasingResponse> {
+        info!("Revoke leasing for {} of {}", id, instance_id);
+        self.credentials()
+            .authorize_namespace(caller, &id.namespace)?;
+        let now = get_current_time();
+
+        let response = match self {
+            Context::Naive(context) => {
+                context
+                    .release_leasing(id.clone(), instance_id, now)
+                    .await?
+            }
+            Context::Batching(context) => {
+                context
+                    .release_leasing(id.clone(), instance_id, now)
+                    .await?
+            }
+        };
+
+        if let LeasingResponse::Released = response {
+            self.wait_queue().notify(&id);
+        }
+
+        Ok(response)
+    }
+
+    /// Hand the leasing of `instance_id` over to `to_instance_id`, see
+    /// `ContextCache::to_transfer_result`.
+    pub async fn transfer_leasing(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
+        instance_id: String,
+        to_instance_id: String,
+        duration: Option<u64>,
+    ) -> LldResult<LeasingResponse> {
+        info!(
+            "Transfer leasing for {} from {} to {}",
+            id, instance_id, to_instance_id
         );
+        self.credentials()
+            .authorize_namespace(caller, &id.namespace)?;
         let now = get_current_time();
 
         match self {
             Context::Naive(context) => {
                 context
-                    .request_leasing(application_id, instance_id, duration, now)
+                    .transfer_leasing(id, instance_id, to_instance_id, duration, now)
                     .await
             }
             Context::Batching(context) => {
                 context
-                    .request_leasing(application_id, instance_id, duration, now)
+                    .transfer_leasing(id, instance_id, to_instance_id, duration, now)
                     .await
             }
         }
     }
+
+    /// The holder of a leasing that expires first, if any.
+    pub async fn query_leasing(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
+    ) -> LldResult<Option<LeasingStatus>> {
+        Ok(self.query_holders(caller, id).await?.into_iter().next())
+    }
+
+    /// All valid holders of a leasing, ordered by their validity.
+    pub async fn query_holders(
+        &self,
+        caller: &Caller,
+        id: LeasingId,
+    ) -> LldResult<Vec<LeasingStatus>> {
+        debug!("Query leasing for {}", id);
+        self.credentials().authorize(caller, &id)?;
+        let now = get_current_time();
+
+        let holders = match self {
+            Context::Naive(context) => context.query_holders(&id).await?,
+            Context::Batching(context) => context.query_holders(&id).await?,
+        };
+
+        let mut holders: Vec<LeasingStatus> = holders
+            .into_iter()
+            .filter(|leasing| leasing.validity > now)
+            .map(|leasing| LeasingStatus {
+                instance_id: leasing.instance_id,
+                validity: leasing.validity,
+                remaining: leasing.validity - now,
+                token: leasing.token,
+                metadata: leasing.metadata,
+            })
+            .collect();
+        holders.sort_by_key(|status| status.validity);
+
+        Ok(holders)
+    }
+
+    /// All holders that match the filter, ordered by leasing and validity. Listing all
+    /// namespaces needs an admin key.
+    pub async fn list_leasings(
+        &self,
+        caller: &Caller,
+        filter: &LeasingFilter,
+    ) -> LldResult<Vec<(LeasingId, Leasing)>> {
+        match &filter.namespace {
+            Some(namespace) => self.credentials().authorize_namespace(caller, namespace)?,
+            None => self.credentials().authorize_admin(caller)?,
+        }
+        let now = get_current_time();
+
+        match self {
+            Context::Naive(context) => context.list_leasings(filter, now).await,
+            Context::Batching(context) => context.list_leasings(filter, now).await,
+        }
+    }
+
+    /// The number of valid leasings per namespace that has any leasing.
+    pub async fn count_namespaces(&self, caller: &Caller) -> LldResult<HashMap<String, u64>> {
+        self.credentials().authorize_admin(caller)?;
+        let now = get_current_time();
+
+        match self {
+            Context::Naive(context) => context.count_namespaces(now).await,
+            Context::Batching(context) => context.count_namespaces(now).await,
+        }
+    }
+
+    /// Drop all leasings of a namespace, returns the number of valid leasings that were dropped.
+    ///
+    /// The highest fencing token of the namespace is kept, the tokens continue above it once the
+    /// namespace is used again.
+    pub async fn delete_namespace(&self, caller: &Caller, namespace: String) -> LldResult<u64> {
+        debug!("Delete namespace {}", namespace);
+        self.credentials().authorize_namespace(caller, &namespace)?;
+        let now = get_current_time();
+
+        let count = match self {
+            Context::Naive(context) => context.delete_namespace(namespace.clone(), now).await?,
+            Context::Batching(context) => context.delete_namespace(namespace.clone(), now).await?,
+        };
+
+        self.wait_queue().notify_namespace(&namespace);
+        Ok(count)
+    }
+
+    /// Receive the events of all leasing changes from now on, the subscriber picks the events of
+    /// `namespace` that match the `filter`.
+    pub fn watch_leasings(
+        &self,
+        caller: &Caller,
+        namespace: &str,
+        filter: &WatchFilter,
+    ) -> LldResult<broadcast::Receiver<LeasingEvent>> {
+        self.credentials()
+            .authorize_watch(caller, namespace, filter)?;
+
+        Ok(match self {
+            Context::Naive(context) => context.events().subscribe(),
+            Context::Batching(context) => context.events().subscribe(),
+        })
+    }
+
+    pub fn credentials(&self) -> &Credentials {
+        match self {
+            Context::Naive(context) => context.credentials(),
+            Context::Batching(context) => context.credentials(),
+        }
+    }
+
+    pub fn epoch(&self) -> &Epoch {
+        match self {
+            Context::Naive(context) => context.epoch(),
+            Context::Batching(context) => context.epoch(),
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        match self {
+            Context::Naive(context) => context.metrics(),
+            Context::Batching(context) => context.metrics(),
+        }
+    }
+
+    /// All metrics in the prometheus text format, with the current size of the cache and epoch.
+    pub async fn encode_metrics(&self) -> LldResult<String> {
+        let cache_size = match self {
+            Context::Naive(context) => context.cache_size().await,
+            Context::Batching(context) => context.cache_size().await,
+        };
+        if let Some(cache_size) = cache_size {
+            self.metrics().set_cache_size(cache_size);
+        }
+        self.metrics().set_epoch(self.epoch().number);
+
+        self.metrics().encode()
+    }
+
+    fn wait_queue(&self) -> &WaitQueue {
+        match self {
+            Context::Naive(context) => context.wait_queue(),
+            Context::Batching(context) => context.wait_queue(),
+        }
+    }
+
+    async fn to_rejection(&self, caller: &Caller, id: LeasingId) -> LldResult<LeasingRejection> {
+        Ok(match self.query_leasing(caller, id).await? {
+            Some(status) => LeasingRejection {
+                remaining: status.remaining,
+                instance_id: Some(status.instance_id),
+                metadata: status.metadata,
+            },
+            None => LeasingRejection::default(),
+        })
+    }
+}
+
+/// Split the cache results of a multi leasing request into the tasks to store, the events to
+/// emit once they are stored and the response.
+///
+/// There is nothing to store if any of the leasings was rejected.
+pub fn to_multi_leasing(
+    ids: &[LeasingId],
+    cache_results: &[CacheResult],
+) -> (Vec<DatabaseTask>, Vec<LeasingEvent>, MultiLeasingResponse) {
+    let mut tasks = Vec::with_capacity(cache_results.len());
+    let mut events = Vec::with_capacity(cache_results.len());
+    let mut tokens = Vec::with_capacity(cache_results.len());
+    let mut validity = 0;
+
+    for (id, cache_result) in ids.iter().zip(cache_results) {
+        match cache_result {
+            CacheResult::GrantedInsert {
+                validity: v, token, ..
+            }
+            | CacheResult::GrantedUpdate {
+                validity: v, token, ..
+            } => {
+                validity = *v;
+                tokens.push((id.application_id.clone(), *token));
+                tasks.extend(cache_result.to_tasks());
+                events.extend(cache_result.to_events());
+            }
+            CacheResult::Rejected(rejection)
+            | CacheResult::PreemptionScheduled { rejection, .. } => {
+                let response = MultiLeasingResponse::Rejected {
+                    application_id: id.application_id.clone(),
+                    rejection: rejection.clone(),
+                };
+                return (Vec::new(), Vec::new(), response);
+            }
+            CacheResult::Released { .. } => {}
+        }
+    }
+
+    (
+        tasks,
+        events,
+        MultiLeasingResponse::Granted { validity, tokens },
+    )
+}
+
+#[derive(Debug)]
+pub enum MultiLeasingResponse {
+    /// Every leasing was granted with the same `validity`, `tokens` are the fencing tokens per
+    /// application.
+    Granted {
+        validity: u64,
+        tokens: Vec<(String, u64)>,
+    },
+    /// Nothing was granted, because the leasing of `application_id` is held by another instance.
+    Rejected {
+        application_id: String,
+        rejection: LeasingRejection,
+    },
+    /// Nothing was granted, because the server is in quarantine for `retry_after` ms.
+    Unavailable { retry_after: u64 },
 }
 
 #[derive(Debug)]
 pub enum LeasingResponse {
-    Granted { validity: u64 },
-    Rejected,
+    Granted {
+        validity: u64,
+        token: u64,
+    },
+    Released,
+    Rejected(LeasingRejection),
+    /// The holder lost the leasing to an instance with a higher priority.
+    Preempted,
+    /// The server does not know whether the leasing is held after it lost its leasings, the
+    /// request may be sent again after `retry_after` ms.
+    Unavailable {
+        retry_after: u64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::database;
+    use crate::policy::{CapacityPolicy, Preemption};
+
+    /// A naive and a batching context with their own database, the batching worker runs until
+    /// the test ends.
+    fn contexts(name: &str) -> Vec<Context> {
+        let capacities = Arc::new(CapacityPolicy::new(1).unwrap());
+        let credentials = Arc::new(Credentials::disabled());
+
+        let naive = ContextNaive::new(
+            database::tests::open(&format!("{}-naive", name)),
+            capacities.clone(),
+            credentials.clone(),
+            Preemption::Disabled,
+            Epoch::new(1, 0, None),
+        )
+        .unwrap();
+        let batching = ContextBatching::new(
+            database::tests::open(&format!("{}-batching", name)),
+            capacities,
+            credentials,
+            Preemption::Disabled,
+            Epoch::new(1, 0, None),
+        )
+        .unwrap();
+
+        let batching = Context::Batching(batching);
+        let worker = batching.clone();
+        tokio::spawn(async move { worker.run().await });
+
+        vec![Context::Naive(naive), batching]
+    }
+
+    fn caller() -> Caller {
+        Caller::new(None, &[])
+    }
+
+    fn id() -> LeasingId {
+        LeasingId::new("default", "a")
+    }
+
+    async fn acquire(context: &Context, instance_id: &str) -> LeasingResponse {
+        context
+            .request_leasing(&caller(), id(), instance_id.to_owned(), 60_000, None, None)
+            .await
+            .unwrap()
+    }
+
+    async fn release(context: &Context, instance_id: &str) -> LeasingResponse {
+        context
+            .release_leasing(&caller(), id(), instance_id.to_owned())
+            .await
+            .unwrap()
+    }
+
+    /// Wait for the leasing in the background, for up to `timeout` ms.
+    fn wait(
+        context: &Context,
+        instance_id: &str,
+        timeout: u64,
+    ) -> tokio::task::JoinHandle<LeasingResponse> {
+        let context = context.clone();
+        let instance_id = instance_id.to_owned();
+        tokio::spawn(async move {
+            context
+                .wait_for_leasing(&caller(), id(), instance_id, 60_000, None, None, timeout)
+                .await
+                .unwrap()
+        })
+    }
+
+    fn holder(response: &LeasingResponse) -> bool {
+        matches!(response, LeasingResponse::Granted { .. })
+    }
+
+    #[tokio::test]
+    async fn only_the_holder_releases_its_leasing() {
+        let databases = ["release-naive", "release-batching"];
+        for (context, database) in contexts("release").into_iter().zip(databases) {
+            assert!(holder(&acquire(&context, "x").await));
+
+            match release(&context, "y").await {
+                LeasingResponse::Rejected(rejection) => {
+                    assert_eq!(rejection.instance_id.as_deref(), Some("x"))
+                }
+                response => panic!("leasing was released: {:?}", response),
+            }
+
+            assert!(matches!(
+                release(&context, "x").await,
+                LeasingResponse::Released
+            ));
+            let holders = database::tests::reopen(database)
+                .query_holders(&id())
+                .unwrap();
+            assert!(holders[0].validity <= get_current_time());
+
+            // Another instance takes over right away, the former holder cannot release it
+            assert!(holder(&acquire(&context, "y").await));
+            assert!(matches!(
+                release(&context, "x").await,
+                LeasingResponse::Rejected(_)
+            ));
+
+            context.stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn waiters_are_served_in_order_of_arrival() {
+        for context in contexts("wait-order") {
+            assert!(holder(&acquire(&context, "x").await));
+
+            let first = wait(&context, "y", 10_000);
+            sleep(Duration::from_millis(50)).await;
+            let mut second = wait(&context, "z", 10_000);
+            sleep(Duration::from_millis(50)).await;
+
+            assert!(matches!(
+                release(&context, "x").await,
+                LeasingResponse::Released
+            ));
+            assert!(holder(&first.await.unwrap()));
+            // The second waiter keeps waiting while the first one holds the leasing
+            assert!(timeout(Duration::from_millis(100), &mut second)
+                .await
+                .is_err());
+
+            assert!(matches!(
+                release(&context, "y").await,
+                LeasingResponse::Released
+            ));
+            assert!(holder(&second.await.unwrap()));
+
+            context.stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn waiters_give_up_after_the_timeout() {
+        for context in contexts("wait-timeout") {
+            assert!(holder(&acquire(&context, "x").await));
+
+            let start = Instant::now();
+            let response = wait(&context, "y", 200).await.unwrap();
+            let waited = start.elapsed();
+            match response {
+                LeasingResponse::Rejected(rejection) => {
+                    assert_eq!(rejection.instance_id.as_deref(), Some("x"))
+                }
+                response => panic!("leasing was not rejected: {:?}", response),
+            }
+            assert!(waited >= Duration::from_millis(200));
+            assert!(waited < Duration::from_millis(2000));
+
+            context.stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_leave_the_queue() {
+        for context in contexts("wait-cancel") {
+            assert!(holder(&acquire(&context, "x").await));
+
+            let waiter = wait(&context, "y", 10_000);
+            sleep(Duration::from_millis(50)).await;
+            assert!(!context.wait_queue().enter(&id()).is_head());
+
+            waiter.abort();
+            assert!(waiter.await.is_err());
+            assert!(context.wait_queue().enter(&id()).is_head());
+
+            context.stop();
+        }
+    }
 }
@@ -1,18 +1,39 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+    time::Instant,
+};
 
-use tokio::sync::{oneshot, Mutex, Notify, RwLock};
+use lld_common::{LeasingRejection, LldError};
+use tokio::{
+    runtime::Handle,
+    sync::{oneshot, Mutex, Notify, RwLock},
+};
 
 use crate::{
-    cache::{CacheResult, ContextCache},
-    context::LeasingResponse,
+    auth::Credentials,
+    cache::{Admission, CacheResult, ContextCache, Leasing, LeasingFilter, LeasingId},
+    context::{to_multi_leasing, LeasingResponse, MultiLeasingResponse},
     database::{Database, DatabaseTask},
+    epoch::Epoch,
+    events::EventBus,
+    metrics::Metrics,
+    policy::{CapacityPolicy, Preemption},
+    wait_queue::WaitQueue,
     LldResult,
 };
 
+/// Tasks that are stored together, `tx` receives whether they were stored or why not.
+///
+/// An entry without tasks is a readiness probe, `tx` receives whether the database answers.
 #[derive(Debug)]
 pub struct QueueEntry {
-    pub task: DatabaseTask,
-    pub tx: oneshot::Sender<LeasingResponse>,
+    pub tasks: Vec<DatabaseTask>,
+    /// The generation of the cache the tasks were decided in.
+    pub generation: u64,
+    pub tx: oneshot::Sender<LldResult<bool>>,
 }
 
 #[derive(Debug, Clone)]
@@ -21,114 +42,607 @@ pub struct ContextBatching {
     notify: Arc<Notify>,
     db: Arc<Mutex<Database>>,
     cache: ContextCache,
+    capacities: Arc<CapacityPolicy>,
+    credentials: Arc<Credentials>,
+    preemption: Preemption,
+    epoch: Epoch,
+    waiters: WaitQueue,
+    events: EventBus,
+    metrics: Metrics,
+    /// Set by `stop`, `run` returns once the queue is empty.
+    stopping: Arc<AtomicBool>,
+    /// Set once `run` returned or panicked, no request is stored from then on.
+    stopped: Arc<AtomicBool>,
+}
+
+/// Marks the worker as stopped when `run` ends, and fails the waiting entries so that their
+/// requests do not wait forever.
+struct WorkerGuard<'a>(&'a ContextBatching);
+
+impl Drop for WorkerGuard<'_> {
+    fn drop(&mut self) {
+        if !self.0.stopping.load(Ordering::SeqCst) {
+            error!("The batching worker stopped");
+        }
+        self.0.stopped.store(true, Ordering::SeqCst);
+
+        // Entries are only added under the lock while the worker is alive, so none is added
+        // after the queue was drained
+        let queue = self.0.queue.clone();
+        match Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    let entries = queue.write().await.drain(..).collect();
+                    answer(entries, Err(stopped()));
+                });
+            }
+            Err(_) => {
+                let entries = queue.blocking_write().drain(..).collect();
+                answer(entries, Err(stopped()));
+            }
+        }
+    }
 }
 
 impl ContextBatching {
     #[allow(clippy::new_without_default)]
-    pub fn new(db: Database) -> LldResult<Self> {
+    pub fn new(
+        db: Database,
+        capacities: Arc<CapacityPolicy>,
+        credentials: Arc<Credentials>,
+        preemption: Preemption,
+        epoch: Epoch,
+    ) -> LldResult<Self> {
         let cache = ContextCache::new(&db)?;
         Ok(Self {
             queue: Arc::new(RwLock::new(Vec::new())),
             notify: Arc::new(Notify::new()),
             db: Arc::new(Mutex::new(db)),
             cache,
+            capacities,
+            credentials,
+            preemption,
+            epoch,
+            waiters: WaitQueue::new(),
+            events: EventBus::new(),
+            metrics: Metrics::new()?,
+            stopping: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub fn epoch(&self) -> &Epoch {
+        &self.epoch
+    }
+
+    pub fn wait_queue(&self) -> &WaitQueue {
+        &self.waiters
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
     async fn check_tasks(&self) -> Option<Vec<QueueEntry>> {
         let mut queue = self.queue.write().await;
         let len = queue.len();
         if len > 0 {
+            self.metrics.set_queue_depth(0);
             Some(queue.drain(0..len).collect())
         } else {
             None
         }
     }
 
+    /// Whether the worker is still running.
+    pub fn is_alive(&self) -> bool {
+        !self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Let the worker store the waiting entries and return, entries that are added afterwards
+    /// fail.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    /// Pass a probe through the queue, returns whether the worker took it and the database
+    /// answered.
+    pub async fn check_ready(&self) -> LldResult<bool> {
+        self.enqueue_tasks(Vec::new(), self.cache.generation())
+            .await
+    }
+
+    /// Store the queued tasks in batches until the worker is stopped.
+    ///
+    /// A batch that cannot be stored fails as a whole. Its changes are dropped from the cache by
+    /// reloading it from the database, requests that were decided before are failed as well.
     pub async fn run(&self) -> LldResult<()> {
+        let _guard = WorkerGuard(self);
         let db = self.db.lock().await;
+        let mut reload_pending = false;
         loop {
             match self.check_tasks().await {
                 Some(entries) => {
+                    if reload_pending {
+                        match self.cache.reload(db.build_cache()).await {
+                            Ok(()) => reload_pending = false,
+                            Err(e) => {
+                                error!("Cannot reload the cache: {:?}", e);
+                                self.metrics.count_error(&e);
+                                answer(entries, Err(e));
+                                continue;
+                            }
+                        }
+                    }
+
+                    let generation = self.cache.generation();
                     let mut tasks = Vec::<DatabaseTask>::with_capacity(entries.len());
                     let mut callbacks =
-                        Vec::<(u64, oneshot::Sender<LeasingResponse>)>::with_capacity(
-                            entries.len(),
-                        );
+                        Vec::<oneshot::Sender<LldResult<bool>>>::with_capacity(entries.len());
 
                     for entry in entries {
-                        let QueueEntry { task, tx } = entry;
-                        let validity = task.get_validity();
-                        tasks.push(task);
-                        callbacks.push((validity, tx));
+                        let QueueEntry {
+                            tasks: mut entry_tasks,
+                            generation: entry_generation,
+                            tx,
+                        } = entry;
+                        if !entry_tasks.is_empty() && entry_generation != generation {
+                            send(tx, Err(outdated()));
+                            continue;
+                        }
+                        tasks.append(&mut entry_tasks);
+                        callbacks.push(tx);
                     }
 
-                    if !tasks.is_empty() {
-                        let result = db.execute_tasks(&tasks)?;
-
-                        if result {
-                            for (validity, tx) in callbacks {
-                                if let Err(e) = tx.send(LeasingResponse::Granted { validity }) {
-                                    error!("Cannot send leasing result to client! ({:?})", e)
-                                }
+                    let result = if tasks.is_empty() {
+                        match db.ping() {
+                            Ok(()) => Ok(true),
+                            Err(e) => {
+                                warn!("Database does not answer: {:?}", e);
+                                self.metrics.count_error(&e);
+                                Ok(false)
+                            }
+                        }
+                    } else {
+                        let start = Instant::now();
+                        match db.execute_tasks(&tasks) {
+                            Ok(result) => {
+                                self.metrics.observe_commit(tasks.len(), start.elapsed());
+                                Ok(result)
                             }
-                        } else {
-                            for (_, tx) in callbacks {
-                                if let Err(e) = tx.send(LeasingResponse::Rejected) {
-                                    error!("Cannot send leasing result to client! ({:?})", e)
+                            Err(e) => {
+                                error!("Cannot store a batch of {} tasks: {:?}", tasks.len(), e);
+                                self.metrics.count_error(&e);
+                                if let Err(e) = self.cache.reload(db.build_cache()).await {
+                                    error!("Cannot reload the cache: {:?}", e);
+                                    reload_pending = true;
                                 }
+                                Err(e)
                             }
                         }
+                    };
+
+                    for tx in callbacks {
+                        send(tx, result.clone());
                     }
                 }
+                None if self.stopping.load(Ordering::SeqCst) => return Ok(()),
                 None => self.notify.notified().await,
             };
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn request_leasing(
         &self,
-        application_id: String,
+        id: LeasingId,
         instance_id: String,
         duration: u64,
+        priority: Option<u32>,
+        metadata: Option<String>,
+        renewal: bool,
         now: u64,
     ) -> LldResult<LeasingResponse> {
+        let admission = Admission {
+            capacity: self.capacities.capacity(&id),
+            priority,
+            preemption: self.preemption,
+            first_token: self.epoch.first_token(),
+            renewal,
+        };
+        let generation = self.cache.generation();
         let cache_result = self
             .cache
-            .request_leasing(application_id, instance_id, duration, now)
+            .request_leasing(id, instance_id, duration, admission, metadata, now)
             .await?;
 
-        let task = match cache_result {
-            CacheResult::Rejected => return Ok(LeasingResponse::Rejected),
-            CacheResult::GrantedInsert {
-                application_id,
-                instance_id,
-                validity,
-            } => DatabaseTask::Insert {
-                application_id,
-                instance_id,
-                validity,
-            },
-            CacheResult::GrantedUpdate {
-                application_id,
-                instance_id,
-                validity,
-            } => DatabaseTask::Update {
-                application_id,
+        self.store_result(cache_result, generation).await
+    }
+
+    pub async fn release_leasing(
+        &self,
+        id: LeasingId,
+        instance_id: String,
+        now: u64,
+    ) -> LldResult<LeasingResponse> {
+        let generation = self.cache.generation();
+        let cache_result = self.cache.release_leasing(id, instance_id, now).await?;
+
+        self.store_result(cache_result, generation).await
+    }
+
+    pub async fn transfer_leasing(
+        &self,
+        id: LeasingId,
+        instance_id: String,
+        to_instance_id: String,
+        duration: Option<u64>,
+        now: u64,
+    ) -> LldResult<LeasingResponse> {
+        let generation = self.cache.generation();
+        let cache_result = self
+            .cache
+            .transfer_leasing(
+                id,
                 instance_id,
-                validity,
-            },
-        };
+                to_instance_id,
+                duration,
+                self.epoch.first_token(),
+                now,
+            )
+            .await?;
+
+        self.store_result(cache_result, generation).await
+    }
+
+    pub async fn request_leasings(
+        &self,
+        ids: Vec<LeasingId>,
+        instance_id: String,
+        duration: u64,
+        now: u64,
+    ) -> LldResult<MultiLeasingResponse> {
+        let generation = self.cache.generation();
+        let cache_results = self
+            .cache
+            .request_leasings(
+                &ids,
+                &instance_id,
+                duration,
+                &self.capacities,
+                self.epoch.first_token(),
+                now,
+            )
+            .await?;
+
+        let (tasks, events, response) = to_multi_leasing(&ids, &cache_results);
+        if tasks.is_empty() {
+            return Ok(response);
+        }
+
+        if !self.enqueue_tasks(tasks, generation).await? {
+            return Err(LldError::WrappedError(
+                "context batching - request leasings",
+                "The leasings were not stored".to_owned(),
+            ));
+        }
+
+        for event in events {
+            self.events.emit(event);
+        }
+        Ok(response)
+    }
+
+    pub async fn query_holders(&self, id: &LeasingId) -> LldResult<Vec<Leasing>> {
+        Ok(self.cache.query_holders(id).await)
+    }
+
+    pub async fn cache_size(&self) -> Option<usize> {
+        Some(self.cache.size().await)
+    }
 
+    pub async fn list_leasings(
+        &self,
+        filter: &LeasingFilter,
+        now: u64,
+    ) -> LldResult<Vec<(LeasingId, Leasing)>> {
+        Ok(self.cache.list_leasings(filter, now).await)
+    }
+
+    pub async fn count_namespaces(&self, now: u64) -> LldResult<HashMap<String, u64>> {
+        Ok(self.cache.count_namespaces(now).await)
+    }
+
+    /// Drop all leasings of a namespace with the next batch, returns the number of valid ones.
+    pub async fn delete_namespace(&self, namespace: String, now: u64) -> LldResult<u64> {
+        let generation = self.cache.generation();
+        let events = self.cache.delete_namespace(&namespace, now).await;
+
+        if !self
+            .enqueue_tasks(
+                vec![DatabaseTask::DeleteNamespace { namespace }],
+                generation,
+            )
+            .await?
+        {
+            return Err(LldError::WrappedError(
+                "context batching - delete namespace",
+                "The deletion was not stored".to_owned(),
+            ));
+        }
+
+        let count = events.len() as u64;
+        for event in events {
+            self.events.emit(event);
+        }
+        Ok(count)
+    }
+
+    /// Store the changes of a cache result with the next batch and emit its events.
+    async fn store_result(
+        &self,
+        cache_result: CacheResult,
+        generation: u64,
+    ) -> LldResult<LeasingResponse> {
+        let tasks = cache_result.to_tasks();
+        let response = cache_result.to_response();
+        if tasks.is_empty() {
+            return Ok(response);
+        }
+
+        let response = self.store(tasks, generation, response).await?;
+        self.events.emit_stored(cache_result.to_events(), &response);
+        Ok(response)
+    }
+
+    /// Store the tasks with the next batch, `response` is returned if they were stored.
+    async fn store(
+        &self,
+        tasks: Vec<DatabaseTask>,
+        generation: u64,
+        response: LeasingResponse,
+    ) -> LldResult<LeasingResponse> {
+        if self.enqueue_tasks(tasks, generation).await? {
+            Ok(response)
+        } else {
+            Ok(LeasingResponse::Rejected(LeasingRejection::default()))
+        }
+    }
+
+    async fn enqueue_tasks(&self, tasks: Vec<DatabaseTask>, generation: u64) -> LldResult<bool> {
         let (tx, rx) = oneshot::channel();
-        let entry = QueueEntry { task, tx };
+        let entry = QueueEntry {
+            tasks,
+            generation,
+            tx,
+        };
 
         {
             let mut queue = self.queue.write().await;
+            if !self.is_alive() {
+                return Err(stopped());
+            }
             queue.push(entry);
+            self.metrics.set_queue_depth(queue.len());
         }
 
         self.notify.notify_one();
-        Ok(rx.await?)
+        rx.await?
+    }
+}
+
+fn answer(entries: Vec<QueueEntry>, result: LldResult<bool>) {
+    for entry in entries {
+        send(entry.tx, result.clone());
+    }
+}
+
+fn send(tx: oneshot::Sender<LldResult<bool>>, result: LldResult<bool>) {
+    if let Err(e) = tx.send(result) {
+        error!("Cannot send leasing result to client! ({:?})", e)
+    }
+}
+
+/// The error of requests that cannot be stored, because the worker stopped.
+fn stopped() -> LldError {
+    LldError::WrappedError(
+        "context batching - enqueue tasks",
+        "The batching worker stopped".to_owned(),
+    )
+}
+
+/// The error of requests that were decided on changes that could not be stored.
+fn outdated() -> LldError {
+    LldError::WrappedError(
+        "context batching - run",
+        "The leasing changed because a batch could not be stored, try again".to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+
+    fn context(name: &str) -> ContextBatching {
+        ContextBatching::new(
+            database::tests::open(name),
+            Arc::new(CapacityPolicy::new(1).unwrap()),
+            Arc::new(Credentials::disabled()),
+            Preemption::Disabled,
+            Epoch::new(1, 0, None),
+        )
+        .unwrap()
+    }
+
+    fn id() -> LeasingId {
+        LeasingId::new("default", "a")
+    }
+
+    fn insert(instance_id: &str) -> DatabaseTask {
+        DatabaseTask::Insert {
+            id: id(),
+            instance_id: instance_id.to_owned(),
+            validity: 2000,
+            token: 1,
+            priority: 0,
+            metadata: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn worker_survives_a_failed_batch() {
+        let context = context("batching-failed-batch");
+        let worker = context.clone();
+        let handle = tokio::spawn(async move { worker.run().await });
+
+        // Both rows have the same key, the batch violates the primary key
+        let generation = context.cache.generation();
+        let result = context
+            .enqueue_tasks(vec![insert("x"), insert("x")], generation)
+            .await;
+        assert!(result.is_err());
+        assert!(context.is_alive());
+
+        // Requests decided before the failure cannot be stored anymore
+        let result = context.enqueue_tasks(vec![insert("y")], generation).await;
+        assert!(result.is_err());
+
+        let response = context
+            .request_leasing(id(), "z".to_owned(), 1000, None, None, false, 1000)
+            .await
+            .unwrap();
+        assert!(matches!(response, LeasingResponse::Granted { .. }));
+        assert_eq!(context.query_holders(&id()).await.unwrap().len(), 1);
+
+        context.stop();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stopped_worker_fails_waiting_entries() {
+        let context = context("batching-stopped");
+        let (tx, rx) = oneshot::channel();
+        context.queue.write().await.push(QueueEntry {
+            tasks: vec![insert("x")],
+            generation: 0,
+            tx,
+        });
+
+        drop(WorkerGuard(&context));
+
+        assert!(rx.await.unwrap().is_err());
+        assert!(!context.is_alive());
+        assert!(context.enqueue_tasks(vec![insert("y")], 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn multiple_leasings_are_granted_all_or_nothing() {
+        let context = context("batching-all-or-nothing");
+        let worker = context.clone();
+        let handle = tokio::spawn(async move { worker.run().await });
+        let ids: Vec<LeasingId> = ["b", "a", "c"]
+            .iter()
+            .map(|application_id| LeasingId::new("default", application_id))
+            .collect();
+
+        let response = context
+            .request_leasing(id(), "x".to_owned(), 1000, None, None, false, 1000)
+            .await
+            .unwrap();
+        assert!(matches!(response, LeasingResponse::Granted { .. }));
+
+        let response = context
+            .request_leasings(ids.clone(), "y".to_owned(), 1000, 1000)
+            .await
+            .unwrap();
+        match response {
+            MultiLeasingResponse::Rejected { application_id, .. } => {
+                assert_eq!(application_id, "a")
+            }
+            response => panic!("leasings were granted: {:?}", response),
+        }
+
+        // Neither the cache nor the database hold a leasing of "y"
+        for id in &ids {
+            let holders = context.query_holders(id).await.unwrap();
+            assert!(holders.iter().all(|leasing| leasing.instance_id != "y"));
+        }
+        assert_eq!(context.cache_size().await, Some(1));
+        let db = database::tests::reopen("batching-all-or-nothing");
+        assert_eq!(db.build_cache().unwrap().len(), 1);
+
+        // Once "a" is free all of them are granted at once
+        let response = context
+            .request_leasings(ids.clone(), "y".to_owned(), 1000, 3000)
+            .await
+            .unwrap();
+        assert!(matches!(response, MultiLeasingResponse::Granted { .. }));
+        for id in &ids {
+            assert_eq!(db.query_holders(id).unwrap()[0].instance_id, "y");
+        }
+
+        context.stop();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn stopping_stores_the_pending_requests() {
+        let context = context("batching-shutdown");
+        let ids: Vec<LeasingId> = ["a", "b", "c"]
+            .iter()
+            .map(|application_id| LeasingId::new("default", application_id))
+            .collect();
+
+        // The worker cannot store anything while the database is locked
+        let db = context.db.lock().await;
+        let worker = context.clone();
+        let handle = tokio::spawn(async move { worker.run().await });
+
+        let requests: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                let context = context.clone();
+                let id = id.clone();
+                tokio::spawn(async move {
+                    context
+                        .request_leasing(id, "x".to_owned(), 1000, None, None, false, 1000)
+                        .await
+                })
+            })
+            .collect();
+        while context.queue.read().await.len() < ids.len() {
+            tokio::task::yield_now().await;
+        }
+
+        context.stop();
+        drop(db);
+
+        for request in requests {
+            let response = request.await.unwrap().unwrap();
+            assert!(matches!(response, LeasingResponse::Granted { .. }));
+        }
+        handle.await.unwrap().unwrap();
+        assert!(!context.is_alive());
+
+        let db = database::tests::reopen("batching-shutdown");
+        for id in &ids {
+            assert_eq!(db.query_holders(id).unwrap()[0].instance_id, "x");
+        }
+
+        // Requests after the worker returned are refused
+        let result = context
+            .request_leasing(id(), "y".to_owned(), 1000, None, None, false, 2000)
+            .await;
+        assert!(result.is_err());
     }
 }
@@ -1,11 +1,21 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use tokio::sync::Mutex;
 
 use crate::{
-    cache::{CacheResult, ContextCache},
-    context::LeasingResponse,
-    database::Database,
+    auth::Credentials,
+    cache::{
+        after_deletion, count_namespaces, list_leasings, to_deletion_events, Admission,
+        CacheResult, ContextCache, Leasing, LeasingFilter, LeasingId,
+    },
+    context::{to_multi_leasing, LeasingResponse, MultiLeasingResponse},
+    database::{Database, DatabaseTask},
+    epoch::Epoch,
+    events::EventBus,
+    metrics::Metrics,
+    policy::{CapacityPolicy, Preemption},
+    wait_queue::WaitQueue,
     LldResult,
 };
 
@@ -13,76 +23,365 @@ use crate::{
 pub struct ContextNaive {
     db: Arc<Mutex<Database>>,
     cache: Option<ContextCache>,
+    capacities: Arc<CapacityPolicy>,
+    credentials: Arc<Credentials>,
+    preemption: Preemption,
+    epoch: Epoch,
+    waiters: WaitQueue,
+    events: EventBus,
+    metrics: Metrics,
 }
 
 impl ContextNaive {
-    pub fn new(db: Database) -> LldResult<Self> {
+    pub fn new(
+        db: Database,
+        capacities: Arc<CapacityPolicy>,
+        credentials: Arc<Credentials>,
+        preemption: Preemption,
+        epoch: Epoch,
+    ) -> LldResult<Self> {
         let cache = Some(ContextCache::new(&db)?);
         Ok(Self {
             db: Arc::new(Mutex::new(db)),
             cache,
+            capacities,
+            credentials,
+            preemption,
+            epoch,
+            waiters: WaitQueue::new(),
+            events: EventBus::new(),
+            metrics: Metrics::new()?,
         })
     }
 
-    pub fn new_without_cache(db: Database) -> LldResult<Self> {
+    pub fn new_without_cache(
+        db: Database,
+        capacities: Arc<CapacityPolicy>,
+        credentials: Arc<Credentials>,
+        preemption: Preemption,
+        epoch: Epoch,
+    ) -> LldResult<Self> {
         Ok(Self {
             db: Arc::new(Mutex::new(db)),
             cache: None,
+            capacities,
+            credentials,
+            preemption,
+            epoch,
+            waiters: WaitQueue::new(),
+            events: EventBus::new(),
+            metrics: Metrics::new()?,
         })
     }
 
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub fn epoch(&self) -> &Epoch {
+        &self.epoch
+    }
+
+    pub fn wait_queue(&self) -> &WaitQueue {
+        &self.waiters
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
     pub async fn run(&self) -> LldResult<()> {
         Ok(())
     }
 
+    /// Whether the database answers a query.
+    pub async fn check_ready(&self) -> LldResult<bool> {
+        let db = self.db.lock().await;
+        match db.ping() {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                warn!("Database does not answer: {:?}", e);
+                self.metrics.count_error(&e);
+                Ok(false)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub async fn request_leasing(
         &self,
-        application_id: String,
+        id: LeasingId,
         instance_id: String,
         duration: u64,
+        priority: Option<u32>,
+        metadata: Option<String>,
+        renewal: bool,
         now: u64,
     ) -> LldResult<LeasingResponse> {
+        let admission = Admission {
+            capacity: self.capacities.capacity(&id),
+            priority,
+            preemption: self.preemption,
+            first_token: self.epoch.first_token(),
+            renewal,
+        };
         let cache_result = match &self.cache {
             Some(cache) => Some(
                 cache
-                    .request_leasing(application_id.clone(), instance_id.clone(), duration, now)
+                    .request_leasing(
+                        id.clone(),
+                        instance_id.clone(),
+                        duration,
+                        admission,
+                        metadata.clone(),
+                        now,
+                    )
                     .await?,
             ),
             None => None,
         };
 
-        if let Some(CacheResult::Rejected) = cache_result {
-            return Ok(LeasingResponse::Rejected);
+        if let Some(CacheResult::Rejected(rejection)) = cache_result {
+            return Ok(LeasingResponse::Rejected(rejection));
         }
 
         let db = self.db.lock().await;
         let cache_result = if let Some(cache_result) = cache_result {
             cache_result
         } else {
-            let result = db.query_leasing(&application_id)?;
-            ContextCache::to_cache_result(application_id, instance_id, duration, now, result)
+            let holders = db.query_holders(&id)?;
+            let admission = Admission {
+                first_token: after_deletion(
+                    admission.first_token,
+                    db.deleted_token(&id.namespace)?,
+                ),
+                ..admission
+            };
+            ContextCache::to_cache_result(
+                id,
+                instance_id,
+                duration,
+                admission,
+                metadata,
+                now,
+                &holders,
+            )
         };
 
-        let leasing_result = match cache_result {
-            CacheResult::Rejected => LeasingResponse::Rejected,
-            CacheResult::GrantedInsert {
-                application_id,
-                instance_id,
-                validity,
-            } => {
-                db.insert_leasing(&application_id, &instance_id, validity)?;
-                LeasingResponse::Granted { validity }
+        self.store(&db, cache_result)
+    }
+
+    pub async fn request_leasings(
+        &self,
+        ids: Vec<LeasingId>,
+        instance_id: String,
+        duration: u64,
+        now: u64,
+    ) -> LldResult<MultiLeasingResponse> {
+        let cache_results = match &self.cache {
+            Some(cache) => Some(
+                cache
+                    .request_leasings(
+                        &ids,
+                        &instance_id,
+                        duration,
+                        &self.capacities,
+                        self.epoch.first_token(),
+                        now,
+                    )
+                    .await?,
+            ),
+            None => None,
+        };
+
+        let db = self.db.lock().await;
+        let cache_results = match cache_results {
+            Some(cache_results) => cache_results,
+            None => ids
+                .iter()
+                .map(|id| {
+                    let holders = db.query_holders(id)?;
+                    let admission = Admission {
+                        capacity: self.capacities.capacity(id),
+                        priority: None,
+                        preemption: Preemption::Disabled,
+                        first_token: after_deletion(
+                            self.epoch.first_token(),
+                            db.deleted_token(&id.namespace)?,
+                        ),
+                        renewal: false,
+                    };
+                    Ok(ContextCache::to_cache_result(
+                        id.clone(),
+                        instance_id.clone(),
+                        duration,
+                        admission,
+                        None,
+                        now,
+                        &holders,
+                    ))
+                })
+                .collect::<LldResult<Vec<_>>>()?,
+        };
+
+        let (tasks, events, response) = to_multi_leasing(&ids, &cache_results);
+        if !tasks.is_empty() {
+            db.execute_tasks(&tasks)?;
+            for event in events {
+                self.events.emit(event);
             }
-            CacheResult::GrantedUpdate {
-                application_id,
+        }
+
+        Ok(response)
+    }
+
+    pub async fn query_holders(&self, id: &LeasingId) -> LldResult<Vec<Leasing>> {
+        if let Some(cache) = &self.cache {
+            return Ok(cache.query_holders(id).await);
+        }
+
+        let db = self.db.lock().await;
+        db.query_holders(id)
+    }
+
+    pub async fn cache_size(&self) -> Option<usize> {
+        match &self.cache {
+            Some(cache) => Some(cache.size().await),
+            None => None,
+        }
+    }
+
+    pub async fn list_leasings(
+        &self,
+        filter: &LeasingFilter,
+        now: u64,
+    ) -> LldResult<Vec<(LeasingId, Leasing)>> {
+        if let Some(cache) = &self.cache {
+            return Ok(cache.list_leasings(filter, now).await);
+        }
+
+        let db = self.db.lock().await;
+        Ok(list_leasings(&db.build_cache()?, filter, now))
+    }
+
+    pub async fn count_namespaces(&self, now: u64) -> LldResult<HashMap<String, u64>> {
+        if let Some(cache) = &self.cache {
+            return Ok(cache.count_namespaces(now).await);
+        }
+
+        let db = self.db.lock().await;
+        Ok(count_namespaces(&db.build_cache()?, now))
+    }
+
+    /// Drop all leasings of a namespace, returns the number of valid ones.
+    pub async fn delete_namespace(&self, namespace: String, now: u64) -> LldResult<u64> {
+        let db = self.db.lock().await;
+        let events = match &self.cache {
+            Some(cache) => cache.delete_namespace(&namespace, now).await,
+            None => to_deletion_events(&db.build_cache()?, &namespace, now),
+        };
+
+        db.execute_tasks(&[DatabaseTask::DeleteNamespace { namespace }])?;
+
+        let count = events.len() as u64;
+        for event in events {
+            self.events.emit(event);
+        }
+        Ok(count)
+    }
+
+    pub async fn release_leasing(
+        &self,
+        id: LeasingId,
+        instance_id: String,
+        now: u64,
+    ) -> LldResult<LeasingResponse> {
+        let cache_result = match &self.cache {
+            Some(cache) => Some(
+                cache
+                    .release_leasing(id.clone(), instance_id.clone(), now)
+                    .await?,
+            ),
+            None => None,
+        };
+
+        if let Some(CacheResult::Rejected(rejection)) = cache_result {
+            return Ok(LeasingResponse::Rejected(rejection));
+        }
+
+        let db = self.db.lock().await;
+        let cache_result = if let Some(cache_result) = cache_result {
+            cache_result
+        } else {
+            let holders = db.query_holders(&id)?;
+            ContextCache::to_release_result(id, instance_id, now, &holders)
+        };
+
+        self.store(&db, cache_result)
+    }
+
+    pub async fn transfer_leasing(
+        &self,
+        id: LeasingId,
+        instance_id: String,
+        to_instance_id: String,
+        duration: Option<u64>,
+        now: u64,
+    ) -> LldResult<LeasingResponse> {
+        let cache_result = match &self.cache {
+            Some(cache) => Some(
+                cache
+                    .transfer_leasing(
+                        id.clone(),
+                        instance_id.clone(),
+                        to_instance_id.clone(),
+                        duration,
+                        self.epoch.first_token(),
+                        now,
+                    )
+                    .await?,
+            ),
+            None => None,
+        };
+
+        if let Some(CacheResult::Rejected(rejection)) = cache_result {
+            return Ok(LeasingResponse::Rejected(rejection));
+        }
+
+        let db = self.db.lock().await;
+        let cache_result = if let Some(cache_result) = cache_result {
+            cache_result
+        } else {
+            let holders = db.query_holders(&id)?;
+            let first_token =
+                after_deletion(self.epoch.first_token(), db.deleted_token(&id.namespace)?);
+            ContextCache::to_transfer_result(
+                id,
                 instance_id,
-                validity,
-            } => {
-                db.update_leasing(&application_id, &instance_id, validity)?;
-                LeasingResponse::Granted { validity }
-            }
+                to_instance_id,
+                duration,
+                now,
+                &holders,
+                first_token,
+            )
         };
 
-        Ok(leasing_result)
+        self.store(&db, cache_result)
+    }
+
+    /// Store the changes of a cache result and emit its events.
+    fn store(&self, db: &Database, cache_result: CacheResult) -> LldResult<LeasingResponse> {
+        let tasks = cache_result.to_tasks();
+        if !tasks.is_empty() {
+            db.execute_tasks(&tasks)?;
+        }
+
+        let response = cache_result.to_response();
+        self.events.emit_stored(cache_result.to_events(), &response);
+        Ok(response)
     }
 }
@@ -1,94 +1,219 @@
 use core::fmt;
 use std::collections::HashMap;
 
-use std::fmt::Write;
+use clap::ArgEnum;
+use lld_common::LldError;
 
-use crate::{cache::CacheMap, LldResult};
+use crate::{
+    cache::{CacheMap, Leasing, LeasingId},
+    LldResult,
+};
 
 #[derive(Debug)]
 pub enum DatabaseTask {
     Insert {
-        application_id: String,
+        id: LeasingId,
         instance_id: String,
         validity: u64,
+        token: u64,
+        priority: u32,
+        metadata: Option<String>,
     },
+    /// Take over the row of `previous_instance_id`.
     Update {
-        application_id: String,
+        id: LeasingId,
+        previous_instance_id: String,
         instance_id: String,
         validity: u64,
+        token: u64,
+        priority: u32,
+        metadata: Option<String>,
     },
+    Release {
+        id: LeasingId,
+        instance_id: String,
+        validity: u64,
+    },
+    /// Mark the holder to give up the leasing at its next renewal.
+    Preempt { id: LeasingId, instance_id: String },
+    /// Drop all leasings of a namespace.
+    DeleteNamespace { namespace: String },
 }
 
-impl DatabaseTask {
-    pub fn get_validity(&self) -> u64 {
-        match self {
-            DatabaseTask::Insert {
-                application_id: _,
-                instance_id: _,
-                validity,
-            } => *validity,
-            DatabaseTask::Update {
-                application_id: _,
-                instance_id: _,
-                validity,
-            } => *validity,
-        }
-    }
+/// Value of the sqlite `synchronous` pragma.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqliteSynchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+/// Value of the sqlite `journal_mode` pragma.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqliteJournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+/// Where the leasings are stored and how the store is tuned, only the options of the backend the
+/// server is built with exist.
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    /// File of the sqlite database.
+    #[cfg(not(feature = "dqlite"))]
+    pub path: String,
+    /// Trade durability for speed with `synchronous=OFF` and `journal_mode=WAL`.
+    #[cfg(not(feature = "dqlite"))]
+    pub sqlite_optimization: bool,
+    /// Overrides the value of `sqlite_optimization`.
+    #[cfg(not(feature = "dqlite"))]
+    pub synchronous: Option<SqliteSynchronous>,
+    /// Overrides the value of `sqlite_optimization`.
+    #[cfg(not(feature = "dqlite"))]
+    pub journal_mode: Option<SqliteJournalMode>,
+    /// Time in ms to wait for a lock on the database.
+    #[cfg(not(feature = "dqlite"))]
+    pub busy_timeout: Option<u64>,
+    /// Pages if positive, KiB if negative.
+    #[cfg(not(feature = "dqlite"))]
+    pub cache_size: Option<i64>,
+    #[cfg(feature = "dqlite")]
+    pub dqlite_database: String,
+    /// File with the addresses of the dqlite cluster, one per line.
+    #[cfg(feature = "dqlite")]
+    pub dqlite_cluster_file: String,
 }
 
 #[cfg(not(feature = "dqlite"))]
 mod database_connection {
 
     use crate::sqlite::Connection as SqliteConnection;
+    use crate::sqlite::Value as SqliteValue;
     use crate::LldResult;
 
-    use super::DatabaseValue;
+    use super::{
+        DatabaseOptions, DatabaseParameter, DatabaseValue, SqliteJournalMode, SqliteSynchronous,
+    };
 
     pub struct Connection {
         connection: SqliteConnection,
     }
 
     impl Connection {
-        pub fn open(sqlite_optimization: bool) -> LldResult<Self> {
-            info!("Connect to sqlite database");
-            let connection = SqliteConnection::open("./database.db")?;
+        pub fn open(options: &DatabaseOptions) -> LldResult<Self> {
+            info!("Connect to sqlite database {}", options.path);
+            let connection = SqliteConnection::open(&options.path)?;
 
-            if sqlite_optimization {
+            if options.sqlite_optimization {
                 info!("Optimize sqlite");
                 connection.enable_optimizations()?;
             }
+            // Pragmas cannot be bound, their values are known names and numbers
+            if let Some(synchronous) = options.synchronous {
+                connection.execute(format!("PRAGMA synchronous={};", synchronous.pragma()))?;
+            }
+            if let Some(journal_mode) = options.journal_mode {
+                connection.execute(format!("PRAGMA journal_mode={};", journal_mode.pragma()))?;
+            }
+            if let Some(cache_size) = options.cache_size {
+                connection.execute(format!("PRAGMA cache_size={};", cache_size))?;
+            }
+            if let Some(busy_timeout) = options.busy_timeout {
+                connection.set_busy_timeout(busy_timeout)?;
+            }
 
             Ok(Self { connection })
         }
 
+        pub fn ping(&self) -> LldResult<()> {
+            self.connection.iterate("SELECT 1;", |_| true)
+        }
+
         pub fn execute(&self, statement: &str) -> LldResult<()> {
             self.connection.execute(statement)?;
             Ok(())
         }
 
-        pub fn iterate<T: AsRef<str>, F>(&self, statement: T, mut callback: F) -> LldResult<()>
+        /// Execute a single statement with `parameters` bound to its `?` placeholders.
+        pub fn execute_with(
+            &self,
+            statement: &'static str,
+            parameters: &[DatabaseParameter],
+        ) -> LldResult<()> {
+            self.connection
+                .execute_prepared(statement, &to_values(parameters))
+        }
+
+        pub fn iterate_with<F>(
+            &self,
+            statement: &'static str,
+            parameters: &[DatabaseParameter],
+            mut callback: F,
+        ) -> LldResult<()>
         where
             F: FnMut(&[(String, DatabaseValue)]) -> bool,
         {
-            self.connection.iterate(statement, |fields| {
-                let vec: Vec<(String, DatabaseValue)> = fields
-                    .iter()
-                    .map(|(key, value)| {
-                        (
-                            key.to_owned().to_owned(),
-                            match value {
-                                Some(value) => DatabaseValue::Text(value.to_owned().to_owned()),
-                                None => DatabaseValue::Null(),
-                            },
-                        )
-                    })
-                    .collect();
+            self.connection
+                .iterate_prepared(statement, &to_values(parameters), |fields| {
+                    let vec: Vec<(String, DatabaseValue)> = fields
+                        .iter()
+                        .map(|(key, value)| {
+                            (
+                                (*key).to_owned(),
+                                match value {
+                                    SqliteValue::Integer(x) => DatabaseValue::Integer(*x),
+                                    SqliteValue::Float(x) => DatabaseValue::Float(*x),
+                                    SqliteValue::Text(x) => DatabaseValue::Text((*x).to_owned()),
+                                    SqliteValue::Null => DatabaseValue::Null(),
+                                },
+                            )
+                        })
+                        .collect();
+
+                    callback(&vec)
+                })
+        }
+    }
 
-                callback(&vec)
-            })?;
-            Ok(())
+    impl SqliteSynchronous {
+        fn pragma(&self) -> &'static str {
+            match self {
+                Self::Off => "OFF",
+                Self::Normal => "NORMAL",
+                Self::Full => "FULL",
+                Self::Extra => "EXTRA",
+            }
+        }
+    }
+
+    impl SqliteJournalMode {
+        fn pragma(&self) -> &'static str {
+            match self {
+                Self::Delete => "DELETE",
+                Self::Truncate => "TRUNCATE",
+                Self::Persist => "PERSIST",
+                Self::Memory => "MEMORY",
+                Self::Wal => "WAL",
+                Self::Off => "OFF",
+            }
         }
     }
+
+    fn to_values<'a>(parameters: &[DatabaseParameter<'a>]) -> Vec<SqliteValue<'a>> {
+        parameters
+            .iter()
+            .map(|parameter| match parameter {
+                DatabaseParameter::Integer(x) => SqliteValue::Integer(*x),
+                DatabaseParameter::Text(x) => SqliteValue::Text(x),
+                DatabaseParameter::Null() => SqliteValue::Null,
+            })
+            .collect()
+    }
 }
 
 #[cfg(feature = "dqlite")]
@@ -97,20 +222,33 @@ mod database_connection {
     use crate::dqlite::Connection as DqliteConnection;
     use crate::dqlite::DqliteValueWrapper;
     use crate::LldResult;
+    use lld_common::LldError;
 
-    use super::DatabaseValue;
+    use super::{DatabaseOptions, DatabaseParameter, DatabaseValue};
 
     pub struct Connection {
         connection: DqliteConnection,
     }
 
     impl Connection {
-        pub fn open(_sqlite_optimization: bool) -> LldResult<Self> {
-            info!("Connect to dqlite database");
-            let connection = DqliteConnection::open("leasings")?;
+        pub fn open(options: &DatabaseOptions) -> LldResult<Self> {
+            info!("Connect to dqlite database {}", options.dqlite_database);
+            let connection =
+                DqliteConnection::open(&options.dqlite_database, &options.dqlite_cluster_file)?;
             Ok(Self { connection })
         }
 
+        /// The cluster has servers and the leader answers a query.
+        pub fn ping(&self) -> LldResult<()> {
+            if self.connection.count_servers() == 0 {
+                return Err(LldError::DatabaseError {
+                    code: None,
+                    message: Some("The dqlite cluster has no servers".to_owned()),
+                });
+            }
+            self.connection.iterate("SELECT 1;", |_| true)
+        }
+
         pub fn execute(&self, statement: &str) -> LldResult<()> {
             self.connection.execute(statement)?;
             Ok(())
@@ -142,16 +280,81 @@ mod database_connection {
             })?;
             Ok(())
         }
+
+        /// Execute a single statement with `parameters` bound to its `?` placeholders.
+        pub fn execute_with(
+            &self,
+            statement: &'static str,
+            parameters: &[DatabaseParameter],
+        ) -> LldResult<()> {
+            self.execute(&bind_parameters(statement, parameters)?)
+        }
+
+        pub fn iterate_with<F>(
+            &self,
+            statement: &'static str,
+            parameters: &[DatabaseParameter],
+            callback: F,
+        ) -> LldResult<()>
+        where
+            F: FnMut(&[(String, DatabaseValue)]) -> bool,
+        {
+            self.iterate(bind_parameters(statement, parameters)?, callback)
+        }
+    }
+
+    /// The dqlite client sends plain statements and cannot bind values, so they replace the `?`
+    /// placeholders as literals. Text is written as a hex blob that is cast back to text, the
+    /// statement never contains any character of a value.
+    fn bind_parameters(statement: &str, parameters: &[DatabaseParameter]) -> LldResult<String> {
+        let mut parameters = parameters.iter();
+        let mut result = String::with_capacity(statement.len());
+
+        for c in statement.chars() {
+            if c != '?' {
+                result.push(c);
+                continue;
+            }
+            match parameters.next() {
+                Some(DatabaseParameter::Integer(x)) => result.push_str(&x.to_string()),
+                Some(DatabaseParameter::Text(x)) => {
+                    result.push_str("CAST(X'");
+                    for byte in x.bytes() {
+                        result.push_str(&format!("{:02X}", byte));
+                    }
+                    result.push_str("' AS TEXT)");
+                }
+                Some(DatabaseParameter::Null()) => result.push_str("NULL"),
+                None => return Err(unbound(statement)),
+            }
+        }
+        if parameters.next().is_some() {
+            return Err(unbound(statement));
+        }
+
+        Ok(result)
+    }
+
+    fn unbound(statement: &str) -> LldError {
+        LldError::DatabaseError {
+            code: None,
+            message: Some(format!(
+                "The parameters do not match the placeholders of {}",
+                statement
+            )),
+        }
     }
 }
 
-#[allow(dead_code)]
 pub enum DatabaseValue {
     Integer(i64),
     Float(f64),
     Null(),
     Text(String),
+    /// Only returned by dqlite.
+    #[cfg(feature = "dqlite")]
     Boolean(bool),
+    #[cfg(feature = "dqlite")]
     Unknown(),
 }
 
@@ -162,12 +365,25 @@ impl DatabaseValue {
             Self::Float(x) => *x as u64,
             Self::Null() => 0,
             Self::Text(x) => x.parse::<u64>().unwrap_or(0),
+            #[cfg(feature = "dqlite")]
             Self::Boolean(x) => *x as u64,
+            #[cfg(feature = "dqlite")]
             Self::Unknown() => 0,
         }
     }
 }
 
+impl DatabaseValue {
+    pub fn to_optional_string(&self) -> Option<String> {
+        match self {
+            Self::Null() => None,
+            #[cfg(feature = "dqlite")]
+            Self::Unknown() => None,
+            value => Some(value.to_string()),
+        }
+    }
+}
+
 impl fmt::Display for DatabaseValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -175,12 +391,55 @@ impl fmt::Display for DatabaseValue {
             Self::Float(x) => write!(f, "{}", x),
             Self::Null() => write!(f, ""),
             Self::Text(x) => write!(f, "{}", x),
+            #[cfg(feature = "dqlite")]
             Self::Boolean(x) => write!(f, "{}", x),
+            #[cfg(feature = "dqlite")]
             Self::Unknown() => write!(f, ""),
         }
     }
 }
 
+/// A value bound to a `?` placeholder of a statement.
+#[derive(Debug, Clone, Copy)]
+pub enum DatabaseParameter<'a> {
+    Integer(i64),
+    Text(&'a str),
+    Null(),
+}
+
+impl From<u64> for DatabaseParameter<'_> {
+    fn from(value: u64) -> Self {
+        Self::Integer(value as i64)
+    }
+}
+
+impl From<u32> for DatabaseParameter<'_> {
+    fn from(value: u32) -> Self {
+        Self::Integer(value as i64)
+    }
+}
+
+impl<'a> From<&'a String> for DatabaseParameter<'a> {
+    fn from(value: &'a String) -> Self {
+        Self::Text(value)
+    }
+}
+
+impl<'a> From<&'a str> for DatabaseParameter<'a> {
+    fn from(value: &'a str) -> Self {
+        Self::Text(value)
+    }
+}
+
+impl<'a> From<&'a Option<String>> for DatabaseParameter<'a> {
+    fn from(value: &'a Option<String>) -> Self {
+        match value {
+            Some(value) => Self::Text(value),
+            None => Self::Null(),
+        }
+    }
+}
+
 pub struct Database {
     connection: database_connection::Connection,
 }
@@ -192,37 +451,152 @@ impl fmt::Debug for Database {
 }
 
 impl Database {
-    pub fn open(sqlite_optimization: bool) -> LldResult<Self> {
-        let connection = database_connection::Connection::open(sqlite_optimization)?;
+    pub fn open(options: &DatabaseOptions) -> LldResult<Self> {
+        let connection = database_connection::Connection::open(options)?;
         Ok(Self { connection })
     }
 
-    pub fn init(&self) -> LldResult<()> {
-        self.connection
-            .execute(r#"DROP TABLE IF EXISTS leasings;"#)?;
+    /// Check that the database answers a query.
+    pub fn ping(&self) -> LldResult<()> {
+        self.connection.ping()
+    }
+
+    /// Drop all leasings and the schema, `migrate` creates empty tables afterwards.
+    ///
+    /// The epoch is kept, so that the next generation still follows the dropped one.
+    pub fn reset(&self) -> LldResult<()> {
         self.connection.execute(
-            r#"CREATE TABLE leasings (
-                application_id TEXT NOT NULL PRIMARY KEY,
-                instance_id TEXT NOT NULL,
-                validity INTEGER NOT NULL
-);"#,
-        )?;
+            r#"DROP TABLE IF EXISTS leasings;
+DROP TABLE IF EXISTS deleted_namespaces;
+DROP TABLE IF EXISTS schema_version;"#,
+        )
+    }
+
+    /// Store the epoch of a new server generation, which follows the stored one and is at least
+    /// `at_least`.
+    ///
+    /// Returns the new epoch and whether there was a stored epoch, which is not the case for a new
+    /// or lost store.
+    pub fn advance_epoch(&self, at_least: u64) -> LldResult<(u64, bool)> {
+        let mut stored = None;
+        self.connection
+            .iterate_with("SELECT number FROM epoch;", &[], |pairs| {
+                stored = Some(pairs[0].1.to_u64());
+                true
+            })?;
+
+        let epoch = stored.map_or(1, |stored| stored + 1).max(at_least);
+        self.execute_transaction(|| {
+            self.connection.execute("DELETE FROM epoch;")?;
+            self.connection
+                .execute_with("INSERT INTO epoch (number) VALUES (?);", &[epoch.into()])
+        })?;
+
+        Ok((epoch, stored.is_some()))
+    }
+
+    /// Whether any leasing is stored, including expired ones.
+    pub fn has_leasings(&self) -> LldResult<bool> {
+        let mut found = false;
+        self.connection
+            .iterate_with("SELECT 1 FROM leasings LIMIT 1;", &[], |_| {
+                found = true;
+                true
+            })?;
+        Ok(found)
+    }
 
-        self.build_cache()?;
+    /// Bring the schema of the leasings table up to date and keep the stored leasings.
+    ///
+    /// Leasings of tables without a namespace are moved to `default_namespace`.
+    pub fn migrate(&self, default_namespace: &str) -> LldResult<()> {
+        let mut version = self.schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(LldError::WrappedError(
+                "database - migrate",
+                format!(
+                    "The schema version {} is newer than the supported version {}",
+                    version, SCHEMA_VERSION
+                ),
+            ));
+        }
+
+        while version < SCHEMA_VERSION {
+            version += 1;
+            info!("Migrate database schema to version {}", version);
+            self.execute_transaction(|| {
+                self.connection.execute(migration(version))?;
+                if version == NAMESPACE_VERSION {
+                    self.connection.execute_with(
+                        "UPDATE leasings SET namespace = ?;",
+                        &[default_namespace.into()],
+                    )?;
+                }
+                self.connection.execute("DELETE FROM schema_version;")?;
+                self.connection.execute_with(
+                    "INSERT INTO schema_version (version) VALUES (?);",
+                    &[version.into()],
+                )
+            })?;
+        }
 
         Ok(())
     }
 
+    /// The version of the schema, tables of servers before the schema version was stored are
+    /// recognized by their columns.
+    fn schema_version(&self) -> LldResult<u64> {
+        self.connection
+            .execute(r#"CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL);"#)?;
+
+        let mut version = None;
+        self.connection
+            .iterate_with("SELECT version FROM schema_version;", &[], |pairs| {
+                version = Some(pairs[0].1.to_u64());
+                true
+            })?;
+        if let Some(version) = version {
+            return Ok(version);
+        }
+
+        let mut columns = Vec::new();
+        self.connection
+            .iterate_with("PRAGMA table_info(leasings);", &[], |pairs| {
+                columns.push(pairs[1].1.to_string());
+                true
+            })?;
+        let has_column = |name: &str| columns.iter().any(|column| column == name);
+
+        Ok(if columns.is_empty() {
+            0
+        } else if has_column("namespace") {
+            5
+        } else if has_column("metadata") {
+            4
+        } else if has_column("priority") {
+            3
+        } else if has_column("token") {
+            2
+        } else {
+            1
+        })
+    }
+
     pub fn build_cache(&self) -> LldResult<CacheMap> {
         let mut cache: CacheMap = HashMap::new();
 
-        self.connection.iterate(
-            "SELECT application_id, instance_id, validity FROM leasings;",
+        self.connection.iterate_with(
+            "SELECT namespace, application_id, instance_id, validity, token, priority, preempted, metadata FROM leasings;",
+            &[],
             |pairs| {
-                let application_id = pairs[0].1.to_string();
-                let instance_id = pairs[1].1.to_string();
-                let validity = pairs[2].1.to_u64();
-                cache.insert(application_id, (instance_id, validity));
+                let id = LeasingId {
+                    namespace: pairs[0].1.to_string(),
+                    application_id: pairs[1].1.to_string(),
+                };
+                cache
+                    .entry(id)
+                    .or_default()
+                    .push(Database::to_leasing(&pairs[2..]));
                 true
             },
         )?;
@@ -230,85 +604,393 @@ impl Database {
         Ok(cache)
     }
 
-    pub fn query_leasing(&self, application_id: &str) -> LldResult<Option<(String, u64)>> {
-        let mut result: Option<(String, u64)> = None;
-        self.connection.iterate(
-            format!(
-                "SELECT instance_id, validity FROM leasings WHERE application_id='{}';",
-                application_id
-            ),
+    /// The highest fencing token of every deleted namespace.
+    pub fn deleted_tokens(&self) -> LldResult<HashMap<String, u64>> {
+        let mut tokens = HashMap::new();
+        self.connection.iterate_with(
+            "SELECT namespace, token FROM deleted_namespaces;",
+            &[],
             |pairs| {
-                let instance_id = pairs[0].1.to_string();
-                let validity = pairs[1].1.to_u64();
-                result = Some((instance_id, validity));
+                tokens.insert(pairs[0].1.to_string(), pairs[1].1.to_u64());
                 true
             },
         )?;
 
-        Ok(result)
+        Ok(tokens)
     }
 
-    fn get_update_leasing_sql(application_id: &str, instance_id: &str, validity: u64) -> String {
-        format!(
-            "UPDATE leasings SET validity = {}, instance_id = '{}' WHERE application_id = '{}';",
-            validity, instance_id, application_id
-        )
+    /// The highest fencing token of `namespace` before it was deleted, if it was.
+    pub fn deleted_token(&self, namespace: &str) -> LldResult<Option<u64>> {
+        let mut token = None;
+        self.connection.iterate_with(
+            "SELECT token FROM deleted_namespaces WHERE namespace = ?;",
+            &[namespace.into()],
+            |pairs| {
+                token = Some(pairs[0].1.to_u64());
+                true
+            },
+        )?;
+
+        Ok(token)
     }
 
-    pub fn update_leasing(
-        &self,
-        application_id: &str,
-        instance_id: &str,
-        validity: u64,
-    ) -> LldResult<bool> {
-        self.connection.execute(
-            Database::get_update_leasing_sql(application_id, instance_id, validity).as_str(),
+    /// All holders of a leasing, including expired ones.
+    pub fn query_holders(&self, id: &LeasingId) -> LldResult<Vec<Leasing>> {
+        let mut result = Vec::new();
+        self.connection.iterate_with(
+            "SELECT instance_id, validity, token, priority, preempted, metadata FROM leasings WHERE namespace = ? AND application_id = ?;",
+            &[(&id.namespace).into(), (&id.application_id).into()],
+            |pairs| {
+                result.push(Database::to_leasing(pairs));
+                true
+            },
         )?;
 
-        Ok(true)
+        Ok(result)
     }
 
-    fn get_insert_leasing_sql(application_id: &str, instance_id: &str, validity: u64) -> String {
-        format!(
-            "INSERT INTO leasings (application_id, instance_id, validity) VALUES ('{}', '{}', {});",
-            application_id, instance_id, validity
-        )
+    /// Read the columns `instance_id, validity, token, priority, preempted, metadata` of a row.
+    fn to_leasing(pairs: &[(String, DatabaseValue)]) -> Leasing {
+        Leasing {
+            instance_id: pairs[0].1.to_string(),
+            validity: pairs[1].1.to_u64(),
+            token: pairs[2].1.to_u64(),
+            priority: pairs[3].1.to_u64() as u32,
+            preempted: pairs[4].1.to_u64() != 0,
+            metadata: pairs[5].1.to_optional_string(),
+        }
     }
 
-    pub fn insert_leasing(
-        &self,
-        application_id: &str,
-        instance_id: &str,
-        validity: u64,
-    ) -> LldResult<bool> {
-        self.connection.execute(
-            Database::get_insert_leasing_sql(application_id, instance_id, validity).as_str(),
-        )?;
+    fn execute_task(&self, task: &DatabaseTask) -> LldResult<()> {
+        match task {
+            DatabaseTask::Insert {
+                id,
+                instance_id,
+                validity,
+                token,
+                priority,
+                metadata,
+            } => self.connection.execute_with(
+                "INSERT INTO leasings (namespace, application_id, instance_id, validity, token, priority, metadata) VALUES (?, ?, ?, ?, ?, ?, ?);",
+                &[
+                    (&id.namespace).into(),
+                    (&id.application_id).into(),
+                    instance_id.into(),
+                    (*validity).into(),
+                    (*token).into(),
+                    (*priority).into(),
+                    metadata.into(),
+                ],
+            ),
+            DatabaseTask::Update {
+                id,
+                previous_instance_id,
+                instance_id,
+                validity,
+                token,
+                priority,
+                metadata,
+            } => self.connection.execute_with(
+                "UPDATE leasings SET validity = ?, instance_id = ?, token = ?, priority = ?, preempted = 0, metadata = ? WHERE namespace = ? AND application_id = ? AND instance_id = ?;",
+                &[
+                    (*validity).into(),
+                    instance_id.into(),
+                    (*token).into(),
+                    (*priority).into(),
+                    metadata.into(),
+                    (&id.namespace).into(),
+                    (&id.application_id).into(),
+                    previous_instance_id.into(),
+                ],
+            ),
+            DatabaseTask::Release {
+                id,
+                instance_id,
+                validity,
+            } => self.connection.execute_with(
+                "UPDATE leasings SET validity = ? WHERE namespace = ? AND application_id = ? AND instance_id = ?;",
+                &[
+                    (*validity).into(),
+                    (&id.namespace).into(),
+                    (&id.application_id).into(),
+                    instance_id.into(),
+                ],
+            ),
+            DatabaseTask::Preempt { id, instance_id } => self.connection.execute_with(
+                "UPDATE leasings SET preempted = 1 WHERE namespace = ? AND application_id = ? AND instance_id = ?;",
+                &[
+                    (&id.namespace).into(),
+                    (&id.application_id).into(),
+                    instance_id.into(),
+                ],
+            ),
+            DatabaseTask::DeleteNamespace { namespace } => {
+                // Tokens of leasings in the namespace only grow, so the new highest token is
+                // never below a stored one
+                self.connection.execute_with(
+                    "INSERT OR REPLACE INTO deleted_namespaces (namespace, token) SELECT namespace, MAX(token) FROM leasings WHERE namespace = ? GROUP BY namespace;",
+                    &[namespace.into()],
+                )?;
+                self.connection
+                    .execute_with("DELETE FROM leasings WHERE namespace = ?;", &[namespace.into()])
+            }
+        }
+    }
 
+    /// Execute all tasks in a single transaction, either all of them are stored or none.
+    pub fn execute_tasks(&self, tasks: &[DatabaseTask]) -> LldResult<bool> {
+        self.execute_transaction(|| {
+            for task in tasks {
+                self.execute_task(task)?;
+            }
+            Ok(())
+        })?;
         Ok(true)
     }
 
-    pub fn execute_tasks(&self, tasks: &[DatabaseTask]) -> LldResult<bool> {
-        let mut transaction = String::new();
-
-        for task in tasks {
-            let statement = match task {
-                DatabaseTask::Insert {
-                    application_id,
-                    instance_id,
-                    validity,
-                } => Database::get_insert_leasing_sql(application_id, instance_id, *validity),
-                DatabaseTask::Update {
-                    application_id,
-                    instance_id,
-                    validity,
-                } => Database::get_update_leasing_sql(application_id, instance_id, *validity),
-            };
+    /// Execute the statements of a transaction, which is rolled back if any of them fails.
+    fn execute_transaction<F>(&self, statements: F) -> LldResult<()>
+    where
+        F: FnOnce() -> LldResult<()>,
+    {
+        self.connection.execute("BEGIN;")?;
+        if let Err(e) = statements().and_then(|_| self.connection.execute("COMMIT;")) {
+            // A failed statement leaves the transaction open
+            if let Err(e) = self.connection.execute("ROLLBACK;") {
+                error!("Cannot rollback transaction: {:?}", e);
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+/// The schema version `migrate` brings the database to.
+const SCHEMA_VERSION: u64 = 7;
 
-            writeln!(&mut transaction, "{}", statement)?;
+/// The schema version that introduces namespaces, `migrate` moves the existing leasings to the
+/// default namespace.
+const NAMESPACE_VERSION: u64 = 5;
+
+/// The statements that bring the schema from `version - 1` to `version`.
+fn migration(version: u64) -> &'static str {
+    match version {
+        1 => {
+            r#"CREATE TABLE leasings (
+                application_id TEXT NOT NULL PRIMARY KEY,
+                instance_id TEXT NOT NULL,
+                validity INTEGER NOT NULL
+);"#
         }
+        2 => "ALTER TABLE leasings ADD COLUMN token INTEGER NOT NULL DEFAULT 0;",
+        // Counting leasings have a row per holder
+        3 => {
+            r#"CREATE TABLE leasings_migration (
+                application_id TEXT NOT NULL,
+                instance_id TEXT NOT NULL,
+                validity INTEGER NOT NULL,
+                token INTEGER NOT NULL,
+                priority INTEGER NOT NULL DEFAULT 0,
+                preempted INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (application_id, instance_id)
+);
+INSERT INTO leasings_migration (application_id, instance_id, validity, token)
+    SELECT application_id, instance_id, validity, token FROM leasings;
+DROP TABLE leasings;
+ALTER TABLE leasings_migration RENAME TO leasings;"#
+        }
+        4 => "ALTER TABLE leasings ADD COLUMN metadata TEXT;",
+        5 => {
+            r#"CREATE TABLE leasings_migration (
+                namespace TEXT NOT NULL,
+                application_id TEXT NOT NULL,
+                instance_id TEXT NOT NULL,
+                validity INTEGER NOT NULL,
+                token INTEGER NOT NULL,
+                priority INTEGER NOT NULL DEFAULT 0,
+                preempted INTEGER NOT NULL DEFAULT 0,
+                metadata TEXT,
+                PRIMARY KEY (namespace, application_id, instance_id)
+);
+INSERT INTO leasings_migration (namespace, application_id, instance_id, validity, token, priority, preempted, metadata)
+    SELECT '', application_id, instance_id, validity, token, priority, preempted, metadata FROM leasings;
+DROP TABLE leasings;
+ALTER TABLE leasings_migration RENAME TO leasings;"#
+        }
+        // A reset keeps the epoch table
+        6 => "CREATE TABLE IF NOT EXISTS epoch (number INTEGER NOT NULL);",
+        // Fencing tokens of a deleted namespace continue above the highest one
+        7 => {
+            r#"CREATE TABLE deleted_namespaces (
+                namespace TEXT NOT NULL PRIMARY KEY,
+                token INTEGER NOT NULL
+);"#
+        }
+        _ => unreachable!("No migration to schema version {}", version),
+    }
+}
 
-        self.connection.execute(transaction.as_str())?;
-        Ok(true)
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lld-{}-{}.db", std::process::id(), name));
+        path.to_str().unwrap().to_owned()
+    }
+
+    /// A database without any tables in a file of its own, `name` keeps the files of tests apart.
+    pub fn open_empty(name: &str) -> Database {
+        let _ = std::fs::remove_file(path(name));
+        reopen(name)
+    }
+
+    /// Another connection to the database of `name`, to look at the rows a context stored.
+    pub fn reopen(name: &str) -> Database {
+        Database::open(&DatabaseOptions {
+            path: path(name),
+            sqlite_optimization: false,
+            synchronous: None,
+            journal_mode: None,
+            busy_timeout: None,
+            cache_size: None,
+        })
+        .unwrap()
+    }
+
+    /// A database with the current schema.
+    pub fn open(name: &str) -> Database {
+        let db = open_empty(name);
+        db.migrate("default").unwrap();
+        db
+    }
+
+    /// A database at schema `version` with a leasing of application "a" in namespace "old" if the
+    /// schema has namespaces. Versions before 6 have no `schema_version` table yet.
+    fn open_at(version: u64) -> Database {
+        let db = open_empty(&format!("migrate-{}", version));
+        for migration_version in 1..=version {
+            db.connection.execute(migration(migration_version)).unwrap();
+        }
+
+        let insert = match version {
+            0 => return db,
+            1 => "INSERT INTO leasings (application_id, instance_id, validity) VALUES ('a', 'i1', 1000);",
+            2..=4 => "INSERT INTO leasings (application_id, instance_id, validity, token) VALUES ('a', 'i1', 1000, 3);",
+            _ => "INSERT INTO leasings (namespace, application_id, instance_id, validity, token) VALUES ('old', 'a', 'i1', 1000, 3);",
+        };
+        db.connection.execute(insert).unwrap();
+        if version >= 6 {
+            db.connection
+                .execute(&format!(
+                    "CREATE TABLE schema_version (version INTEGER NOT NULL); INSERT INTO schema_version (version) VALUES ({});",
+                    version
+                ))
+                .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn migrate_keeps_the_leasings_of_every_version() {
+        for version in 0..SCHEMA_VERSION {
+            let db = open_at(version);
+            assert_eq!(db.schema_version().unwrap(), version);
+
+            db.migrate("default").unwrap();
+            assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+            assert_eq!(db.deleted_tokens().unwrap().len(), 0);
+
+            let cache = db.build_cache().unwrap();
+            if version == 0 {
+                assert!(cache.is_empty());
+                continue;
+            }
+
+            let namespace = if version < NAMESPACE_VERSION {
+                "default"
+            } else {
+                "old"
+            };
+            let holders = &cache[&LeasingId::new(namespace, "a")];
+            assert_eq!(holders.len(), 1, "version {}", version);
+            assert_eq!(holders[0].instance_id, "i1");
+            assert_eq!(holders[0].validity, 1000);
+            assert_eq!(holders[0].token, if version == 1 { 0 } else { 3 });
+        }
+    }
+
+    #[test]
+    fn migrate_refuses_newer_schemas() {
+        let db = open("migrate-newer");
+        db.connection
+            .execute("UPDATE schema_version SET version = 100;")
+            .unwrap();
+
+        assert!(db.migrate("default").is_err());
+    }
+
+    #[test]
+    fn epoch_follows_the_stored_one() {
+        let db = open("epoch-follows");
+
+        assert_eq!(db.advance_epoch(0).unwrap(), (1, false));
+        assert_eq!(db.advance_epoch(0).unwrap(), (2, true));
+    }
+
+    #[test]
+    fn reset_keeps_the_epoch() {
+        let db = open("epoch-reset");
+        db.advance_epoch(0).unwrap();
+
+        db.reset().unwrap();
+        db.migrate("default").unwrap();
+
+        assert_eq!(db.advance_epoch(0).unwrap(), (2, true));
+    }
+
+    #[test]
+    fn values_are_bound_and_not_spliced() {
+        let db = open("bound-values");
+        let id = LeasingId::new("default", "a'; DROP TABLE leasings; --\0");
+        let instance_id = "i'1;\0".to_owned();
+        let next_instance_id = "i'2;\0'".to_owned();
+
+        db.execute_tasks(&[DatabaseTask::Insert {
+            id: id.clone(),
+            instance_id: instance_id.clone(),
+            validity: 1000,
+            token: 1,
+            priority: 0,
+            metadata: Some("m'\0".to_owned()),
+        }])
+        .unwrap();
+        let holders = db.query_holders(&id).unwrap();
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].instance_id, instance_id);
+        assert_eq!(holders[0].metadata.as_deref(), Some("m'\0"));
+
+        db.execute_tasks(&[DatabaseTask::Update {
+            id: id.clone(),
+            previous_instance_id: instance_id,
+            instance_id: next_instance_id.clone(),
+            validity: 2000,
+            token: 2,
+            priority: 0,
+            metadata: None,
+        }])
+        .unwrap();
+        let holders = db.query_holders(&id).unwrap();
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].instance_id, next_instance_id);
+        assert_eq!(holders[0].token, 2);
+
+        let cache = db.build_cache().unwrap();
+        assert_eq!(cache[&id][0].instance_id, next_instance_id);
+        assert!(db
+            .query_holders(&LeasingId::new("default", "a"))
+            .unwrap()
+            .is_empty());
     }
 }
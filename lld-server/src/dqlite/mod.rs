@@ -35,40 +35,39 @@ pub struct Connection {}
 unsafe impl Send for Connection {}
 
 impl Connection {
-    pub fn open(database_name: &str) -> LldResult<Connection> {
-        let ipc = std::fs::read_to_string("ips.csv")
-            .unwrap()
-            .split("\n")
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| s.to_owned())
-            .map(|s| CString::new(s).unwrap())
-            .collect::<Vec<CString>>();
+    /// Connect to the servers listed in `cluster_file` and open `database_name`.
+    pub fn open(database_name: &str, cluster_file: &str) -> LldResult<Connection> {
+        let ips = match std::fs::read_to_string(cluster_file) {
+            Ok(ips) => ips,
+            Err(e) => raise!(format!("Cannot read {}: {}", cluster_file, e)),
+        };
+        let mut ipc = Vec::<CString>::new();
+        for ip in ips.split('\n').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            ipc.push(str_to_cstr!(ip));
+        }
 
         unsafe {
             ffi::set_n_clients(3);
-            ffi::init_ips(str_to_cstr!("ips.csv").as_ptr());
-            println!("clients: {}", ffi::get_n_servers());
+            ffi::init_ips(str_to_cstr!(cluster_file).as_ptr());
+            info!("Connect to {} dqlite servers", ffi::get_n_servers());
 
             for i in 0..ipc.len() {
                 let ip = &ipc[i];
-                println!("Connecting to socket {:?} {:?}", ip, ip.as_ptr());
+                debug!("Connect to socket {:?}", ip);
 
                 let mut fd = 0;
                 let res = ffi::connect_socket(&mut fd as *mut c_int, ip.as_ptr());
-                println!("Connected to socket {:?}: {}", ip, res);
+                debug!("Connected to socket {:?}: {}", ip, res);
                 if res != 0 {
                     raise!("Cannot connect socket!");
                 }
 
                 let client = &mut ffi::clients[i];
-                println!("Init client {:?}", ip);
                 let res = ffi::clientInit(client as *mut Dqlite, fd as c_int);
-                println!("Init client {:?} finished: {}", ip, res);
+                debug!("Init client {:?}: {}", ip, res);
 
-                println!("Handshake to client {:?}", ip);
                 let res = ffi::clientSendHandshake(client as *mut Dqlite);
-                println!("Handshake to client {:?} finished {}", ip, res);
+                debug!("Handshake to client {:?}: {}", ip, res);
                 if res != 0 {
                     raise!("Handshake failed!");
                 }
@@ -77,15 +76,12 @@ impl Connection {
             for i in 1..ipc.len() {
                 let ip = &ipc[i];
                 let client = &mut ffi::clients[i];
-                println!("Adding server {:?} {:?}", ip, ip.as_ptr());
                 let res = ffi::addServer(client as *mut Dqlite, i as c_uint, ip.as_ptr());
-                println!("Added server {:?}: {}", ip, res);
+                debug!("Add server {:?}: {}", ip, res);
             }
 
-            println!("Send open {}", database_name);
             let res = ffi::send_open(str_to_cstr!(database_name).as_ptr());
-
-            println!("Send open {} finished: {}", database_name, res);
+            info!("Open dqlite database {}: {}", database_name, res);
             if res != 0 {
                 raise!("Cannot open database!");
             }
@@ -94,6 +90,11 @@ impl Connection {
         Ok(Connection {})
     }
 
+    /// The number of servers of the dqlite cluster.
+    pub fn count_servers(&self) -> usize {
+        unsafe { ffi::get_n_servers().max(0) as usize }
+    }
+
     /// Execute a statement without processing the resulting rows if any.
     #[inline]
     pub fn execute<T: AsRef<str>>(&self, statement: T) -> LldResult<()> {
@@ -119,7 +120,6 @@ impl Connection {
         unsafe {
             let mut rows = std::mem::MaybeUninit::<DqliteRows>::zeroed().assume_init();
 
-            println!("1");
             let res = ffi::raw_query(
                 &mut rows as *mut DqliteRows,
                 str_to_cstr!(statement.as_ref()).as_ptr(),
@@ -127,7 +127,6 @@ impl Connection {
             if res != 0 {
                 raise!("Cannot exec statement!");
             }
-            println!("2 {}", rows.column_count);
 
             let args = (0..rows.column_count)
                 .map(|i| i.to_string())
@@ -137,22 +136,16 @@ impl Connection {
                 return Ok(());
             }
 
-            println!("3");
-
             let column_count = rows.column_count;
             let mut this_row = rows.next;
 
-            println!("4");
-
             while !this_row.is_null() {
                 let mut result =
                     Vec::<(String, DqliteValueWrapper)>::with_capacity(column_count as usize);
 
-                println!("5");
                 for i in 0..column_count {
                     let union_type = (*(*this_row).values.offset(i as isize)).union_type;
 
-                    println!("6: {}: {}", i, union_type);
                     let value = match union_type {
                         1 => DqliteValueWrapper::Integer(
                             (*(*this_row).values.offset(i as isize)).union_value.integer,
@@ -164,21 +157,18 @@ impl Connection {
                         11 => DqliteValueWrapper::Boolean(
                             (*(*this_row).values.offset(i as isize)).union_value.boolean != 0,
                         ),
-                        3 => DqliteValueWrapper::Text(
-                            c_str_to_str!(
-                                (*(*this_row).values.offset(i as isize)).union_value.text
-                            )
-                            .unwrap()
-                            .to_owned(),
-                        ),
+                        3 => match c_str_to_str!(
+                            (*(*this_row).values.offset(i as isize)).union_value.text
+                        ) {
+                            Ok(text) => DqliteValueWrapper::Text(text.to_owned()),
+                            Err(e) => raise!(format!("Cannot read a text value: {}", e)),
+                        },
                         _ => DqliteValueWrapper::Unknown(),
                     };
 
                     result.push((args[i as usize].clone(), value));
                 }
 
-                println!("8");
-
                 if !callback(&result) {
                     break;
                 }
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use crate::cache::LeasingId;
+use crate::database::Database;
+use crate::policy::DurationPolicy;
+use crate::LldResult;
+
+/// Fencing tokens of an epoch start at its number shifted by this many bits.
+const TOKEN_EPOCH_SHIFT: u32 = 32;
+
+/// How long leasings are refused after the leasings of the previous generation were dropped.
+#[derive(Debug, Clone)]
+pub enum Quarantine {
+    /// The longest duration the policy grants for the application.
+    Policy(Arc<DurationPolicy>),
+    /// The same duration in ms for every application.
+    Fixed(u64),
+}
+
+/// The generation of the server, which increases with every start on the same store.
+///
+/// A server that starts without the leasings of the previous generation cannot tell which
+/// leasings are still held. It refuses to grant a leasing until the quarantine of the
+/// application has passed.
+#[derive(Debug, Clone)]
+pub struct Epoch {
+    pub number: u64,
+    /// Start of the server in ms.
+    started: u64,
+    /// Only set if the leasings of the previous generation may be lost.
+    quarantine: Option<Quarantine>,
+}
+
+impl Epoch {
+    pub fn new(number: u64, started: u64, quarantine: Option<Quarantine>) -> Self {
+        Self {
+            number,
+            started,
+            quarantine,
+        }
+    }
+
+    /// Start the next generation on `db` at `now` in ms.
+    ///
+    /// A store without an epoch or without leasings may have lost the leasings of an earlier
+    /// generation, because it is new, was reset, deleted or lived on an ephemeral volume. Such a
+    /// start is quarantined unless `skip_quarantine` is set. The epoch is at least the current
+    /// time in seconds, so that the tokens stay above the ones of a lost generation that started
+    /// earlier.
+    pub fn start(
+        db: &Database,
+        quarantine: Quarantine,
+        skip_quarantine: bool,
+        now: u64,
+    ) -> LldResult<Self> {
+        let (number, previous) = db.advance_epoch(now / 1000)?;
+        let lost = !previous || !db.has_leasings()?;
+
+        if lost && !skip_quarantine {
+            warn!(
+                "Start epoch {} without the leasings of the previous generation, acquires are refused until the quarantine has passed",
+                number
+            );
+            Ok(Self::new(number, now, Some(quarantine)))
+        } else {
+            info!("Start epoch {}", number);
+            Ok(Self::new(number, now, None))
+        }
+    }
+
+    /// The lowest fencing token of a new holder in this epoch.
+    ///
+    /// The epoch is kept in the upper bits, so tokens stay above the ones of earlier epochs even
+    /// if their leasings were dropped.
+    pub fn first_token(&self) -> u64 {
+        self.number << TOKEN_EPOCH_SHIFT
+    }
+
+    /// Time in ms until a leasing may be granted again, if it is still in quarantine.
+    pub fn retry_after(&self, id: &LeasingId, now: u64) -> Option<u64> {
+        let duration = match self.quarantine.as_ref()? {
+            Quarantine::Policy(policy) => policy.limits(id).max,
+            Quarantine::Fixed(duration) => *duration,
+        };
+        let end = self.started.saturating_add(duration);
+
+        if now < end {
+            Some(end - now)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{self, DatabaseTask};
+    use crate::policy::{DurationLimits, DurationMode};
+
+    const NOW: u64 = 1_600_000_000_000;
+
+    fn id() -> LeasingId {
+        LeasingId::new("default", "a")
+    }
+
+    #[test]
+    fn no_quarantine_without_dropped_leasings() {
+        let epoch = Epoch::new(1, 1000, None);
+        assert_eq!(epoch.retry_after(&id(), 1000), None);
+    }
+
+    #[test]
+    fn quarantine_of_the_policy() {
+        let policy = DurationPolicy::new(
+            DurationMode::Clamp,
+            DurationLimits {
+                min: 100,
+                max: 5000,
+                default: 1000,
+            },
+        )
+        .unwrap();
+        let epoch = Epoch::new(2, 1000, Some(Quarantine::Policy(Arc::new(policy))));
+
+        assert_eq!(epoch.retry_after(&id(), 1000), Some(5000));
+        assert_eq!(epoch.retry_after(&id(), 5999), Some(1));
+        assert_eq!(epoch.retry_after(&id(), 6000), None);
+    }
+
+    #[test]
+    fn fixed_quarantine() {
+        let epoch = Epoch::new(2, 1000, Some(Quarantine::Fixed(300)));
+
+        assert_eq!(epoch.retry_after(&id(), 1100), Some(200));
+        assert_eq!(epoch.retry_after(&id(), 1300), None);
+    }
+
+    #[test]
+    fn empty_stores_are_quarantined() {
+        let db = database::tests::open("epoch-empty-store");
+
+        let epoch = Epoch::start(&db, Quarantine::Fixed(300), false, NOW).unwrap();
+        assert_eq!(epoch.number, NOW / 1000);
+        assert_eq!(epoch.retry_after(&id(), NOW + 100), Some(200));
+
+        // Still without leasings on the next start
+        let epoch = Epoch::start(&db, Quarantine::Fixed(300), false, NOW).unwrap();
+        assert_eq!(epoch.number, NOW / 1000 + 1);
+        assert_eq!(epoch.retry_after(&id(), NOW), Some(300));
+
+        let epoch = Epoch::start(&db, Quarantine::Fixed(300), true, NOW).unwrap();
+        assert_eq!(epoch.retry_after(&id(), NOW), None);
+    }
+
+    #[test]
+    fn stores_with_leasings_are_not_quarantined() {
+        let db = database::tests::open("epoch-stored-leasings");
+        Epoch::start(&db, Quarantine::Fixed(300), true, NOW).unwrap();
+        db.execute_tasks(&[DatabaseTask::Insert {
+            id: id(),
+            instance_id: "i1".to_owned(),
+            validity: NOW + 1000,
+            token: 1,
+            priority: 0,
+            metadata: None,
+        }])
+        .unwrap();
+
+        let epoch = Epoch::start(&db, Quarantine::Fixed(300), false, NOW).unwrap();
+        assert_eq!(epoch.retry_after(&id(), NOW), None);
+
+        // A store that was lost starts above the epochs of earlier starts
+        let lost = database::tests::open("epoch-lost-store");
+        let restarted = Epoch::start(&lost, Quarantine::Fixed(300), false, NOW + 60_000).unwrap();
+        assert!(restarted.first_token() > epoch.first_token());
+        assert!(restarted.retry_after(&id(), NOW + 60_000).is_some());
+    }
+
+    #[test]
+    fn tokens_of_later_epochs_are_higher() {
+        let first = Epoch::new(1, 0, None);
+        let second = Epoch::new(2, 0, None);
+
+        assert!(second.first_token() > first.first_token() + u64::from(u32::MAX));
+    }
+}
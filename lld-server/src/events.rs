@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lld_common::{get_current_time, LeasingEvent, LeasingEventKind};
+use tokio::sync::broadcast;
+use tokio::time::sleep;
+
+use crate::context::LeasingResponse;
+
+const EVENT_CAPACITY: usize = 1024;
+
+/// Validity and token of the latest grant per application and holder.
+type LatestGrants = HashMap<(String, String, String), (u64, u64)>;
+
+/// Broadcasts leasing changes to all watchers.
+///
+/// Expired events are produced by a timer per holder, which is started with its grant whether
+/// anybody watches or not, so watchers that subscribe later still see the expiry. The timer
+/// follows the renewals of the holder and emits the event once the latest validity has passed,
+/// unless the leasing was released in the meantime.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<LeasingEvent>,
+    latest: Arc<Mutex<LatestGrants>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CAPACITY);
+
+        Self {
+            tx,
+            latest: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LeasingEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Emit the events of a cache result, if the change was stored successfully.
+    pub fn emit_stored(&self, events: Vec<LeasingEvent>, response: &LeasingResponse) {
+        if let LeasingResponse::Rejected(_) = response {
+            return;
+        }
+
+        for event in events {
+            self.emit(event);
+        }
+    }
+
+    pub fn emit(&self, event: LeasingEvent) {
+        match event.kind {
+            LeasingEventKind::Granted | LeasingEventKind::Renewed => {
+                let scheduled = self
+                    .latest
+                    .lock()
+                    .unwrap()
+                    .insert(key(&event), (event.validity, event.token))
+                    .is_some();
+
+                if !scheduled {
+                    self.schedule_expiry(&event);
+                }
+            }
+            LeasingEventKind::Released
+            | LeasingEventKind::Preempted
+            | LeasingEventKind::Expired => {
+                self.latest.lock().unwrap().remove(&key(&event));
+            }
+        }
+
+        // Sending only fails if nobody is watching
+        let _ = self.tx.send(event);
+    }
+
+    fn schedule_expiry(&self, event: &LeasingEvent) {
+        let bus = self.clone();
+        let event = LeasingEvent {
+            kind: LeasingEventKind::Expired,
+            ..event.clone()
+        };
+
+        tokio::spawn(async move {
+            loop {
+                let latest = bus.latest.lock().unwrap().get(&key(&event)).copied();
+                // Released, or handed to the timer of a new grant
+                let (validity, token) = match latest {
+                    Some(latest) => latest,
+                    None => return,
+                };
+
+                let now = get_current_time();
+                if validity > now {
+                    sleep(Duration::from_millis(validity - now)).await;
+                    continue;
+                }
+
+                let expired = {
+                    let mut latest = bus.latest.lock().unwrap();
+                    latest.get(&key(&event)) == Some(&(validity, token))
+                        && latest.remove(&key(&event)).is_some()
+                };
+
+                if expired {
+                    let _ = bus.tx.send(LeasingEvent {
+                        validity,
+                        token,
+                        ..event
+                    });
+                    return;
+                }
+            }
+        });
+    }
+}
+
+fn key(event: &LeasingEvent) -> (String, String, String) {
+    (
+        event.namespace.clone(),
+        event.application_id.clone(),
+        event.instance_id.clone(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    fn event(kind: LeasingEventKind, validity: u64) -> LeasingEvent {
+        LeasingEvent {
+            kind,
+            namespace: "default".to_string(),
+            application_id: "a".to_string(),
+            instance_id: "i1".to_string(),
+            validity,
+            token: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn expiry_reaches_later_watchers() {
+        let bus = EventBus::new();
+        bus.emit(event(LeasingEventKind::Granted, get_current_time() + 50));
+
+        let mut rx = bus.subscribe();
+        let expired = timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(expired.kind, LeasingEventKind::Expired));
+    }
+
+    #[tokio::test]
+    async fn renewals_postpone_the_expiry() {
+        let bus = EventBus::new();
+        let mut rx = bus.subscribe();
+        let validity = get_current_time() + 50;
+        bus.emit(event(LeasingEventKind::Granted, validity));
+        bus.emit(event(LeasingEventKind::Renewed, validity + 100));
+
+        rx.recv().await.unwrap();
+        rx.recv().await.unwrap();
+        let expired = timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(expired.kind, LeasingEventKind::Expired));
+        assert_eq!(expired.validity, validity + 100);
+        assert!(get_current_time() >= validity + 100);
+    }
+}
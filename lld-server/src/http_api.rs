@@ -1,85 +1,770 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use hyper::server::conn::Http;
+use tokio::net::TcpListener;
 use warp::Filter;
 
 use crate::context::Context;
-use crate::SslContext;
-
-pub async fn start_server(context: Context, port: u16, ssl_context: Option<SslContext>) {
-    let api = filters::leasing(context);
-    let routes = api.with(warp::log("http_api"));
-
-    if let Some(ssl_context) = ssl_context {
-        warp::serve(routes)
-            .tls()
-            .cert_path(&ssl_context.cert_file)
-            .key_path(&ssl_context.key_file)
-            .run(([0, 0, 0, 0], port))
-            .await;
-    } else {
-        warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+use crate::metrics::{Metrics, Protocol};
+use crate::shutdown::Shutdown;
+use crate::{accept_ssl, ApiOptions, SslContext};
+
+pub async fn start_server(
+    context: Context,
+    options: ApiOptions,
+    port: u16,
+    ssl_context: Option<SslContext>,
+    shutdown: Shutdown,
+) {
+    let metrics = context.metrics().clone();
+    let signal = shutdown.signal();
+    serve(
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+        ssl_context,
+        metrics,
+        shutdown,
+        move |certificate_names| {
+            filters::leasing(
+                context.clone(),
+                options.clone(),
+                certificate_names,
+                signal.clone(),
+            )
+            .with(warp::log("http_api"))
+        },
+    )
+    .await;
+}
+
+/// Serve `routes` at `addr` over http, or https if there is an ssl context.
+///
+/// `routes` builds the routes for the names of the client certificate of a connection. Warp
+/// cannot tell the client certificate, so with ssl the connections are accepted with openssl and
+/// every connection gets its own routes.
+///
+/// Once the shutdown starts no more connections are accepted, and the open ones are closed after
+/// their current requests were answered.
+pub async fn serve<R, F>(
+    addr: SocketAddr,
+    ssl_context: Option<SslContext>,
+    metrics: Metrics,
+    mut shutdown: Shutdown,
+    routes: R,
+) where
+    R: Fn(Vec<String>) -> F + Send + Sync + 'static,
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let ssl_context = match ssl_context {
+        Some(ssl_context) => ssl_context,
+        None => {
+            let mut signal = shutdown.clone();
+            let (_, server) = warp::serve(routes(Vec::new()))
+                .bind_with_graceful_shutdown(addr, async move { signal.recv().await });
+            server.await;
+            info!("Stop http server at {}", addr);
+            return;
+        }
+    };
+
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let acceptor = ssl_context.acceptor().unwrap();
+    let routes = Arc::new(routes);
+
+    info!("Start https server at {}", addr);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.recv() => break,
+        };
+        let (socket, addr) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("Cannot accept http connection: {:?}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let routes = routes.clone();
+        let metrics = metrics.clone();
+        let mut shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let (stream, certificate_names) = match accept_ssl(&acceptor, socket).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Ssl handshake with {} failed: {:?}", addr, e);
+                    metrics.count_tls_handshake_failure(Protocol::Http);
+                    return;
+                }
+            };
+
+            let connection =
+                Http::new().serve_connection(stream, warp::service(routes(certificate_names)));
+            tokio::pin!(connection);
+
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.recv() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Http connection with {} failed: {:?}", addr, e);
+            }
+        });
     }
+
+    info!("Stop https server at {}", addr);
 }
 
-mod filters {
+pub mod filters {
     use super::handlers;
+    use crate::auth::Caller;
     use crate::context::Context;
-    use lld_common::RestLeasingRequest;
+    use crate::ApiOptions;
+    use lld_common::{RestLeasingRequest, RestMultiLeasingRequest, RestReleaseRequest};
+    use serde::de::DeserializeOwned;
+    use serde::Deserialize;
+    use tokio::sync::watch;
     use warp::Filter;
 
+    /// `certificate_names` are the names of the client certificate of the connection, if any.
+    /// `shutdown` turns `true` once the server shuts down.
     pub fn leasing(
         context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+        shutdown: watch::Receiver<bool>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-        request_leasing(context)
+        request_leasing(context.clone(), options.clone(), certificate_names.clone())
+            .or(request_leasings(
+                context.clone(),
+                options.clone(),
+                certificate_names.clone(),
+            ))
+            .or(release_leasing(
+                context.clone(),
+                options.clone(),
+                certificate_names.clone(),
+            ))
+            .or(query_leasing(
+                context.clone(),
+                options.clone(),
+                certificate_names.clone(),
+            ))
+            .or(watch_leasings(
+                context.clone(),
+                options.clone(),
+                certificate_names.clone(),
+                shutdown,
+            ))
+            .or(metrics(context.clone(), certificate_names))
+            .or(health(context.clone()))
+            .or(ready(context, options))
     }
 
     pub fn request_leasing(
         context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::path!("request")
             .and(warp::post())
-            .and(json_body())
+            .and(json_body::<RestLeasingRequest>())
+            .and(caller(certificate_names))
             .and(with_context(context))
+            .and(with_options(options))
             .and_then(handlers::request_leasing)
     }
 
-    fn with_context(
+    pub fn request_leasings(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("request-all")
+            .and(warp::post())
+            .and(json_body::<RestMultiLeasingRequest>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::request_leasings)
+    }
+
+    pub fn release_leasing(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("release")
+            .and(warp::post())
+            .and(json_body::<RestReleaseRequest>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::release_leasing)
+    }
+
+    /// The current holder of a leasing, of the namespace given by `?namespace=`.
+    pub fn query_leasing(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("leases" / String)
+            .and(warp::get())
+            .and(warp::query::<NamespaceQuery>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::query_leasing)
+    }
+
+    /// Server-sent events of all leasing changes of a namespace, filtered by `?application_id=`
+    /// or `?prefix=`. The stream ends once the server shuts down.
+    pub fn watch_leasings(
+        context: Context,
+        options: ApiOptions,
+        certificate_names: Vec<String>,
+        shutdown: watch::Receiver<bool>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("watch")
+            .and(warp::get())
+            .and(warp::query::<WatchQuery>())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and(with_options(options))
+            .and(warp::any().map(move || shutdown.clone()))
+            .and_then(handlers::watch_leasings)
+    }
+
+    /// Counters and histograms of the server in the prometheus text format, only for admin keys
+    /// if authentication is required.
+    pub fn metrics(
+        context: Context,
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("metrics")
+            .and(warp::get())
+            .and(caller(certificate_names))
+            .and(with_context(context))
+            .and_then(handlers::metrics)
+    }
+
+    /// Fails once the server cannot answer requests anymore.
+    pub fn health(
+        context: Context,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("healthz")
+            .and(warp::get())
+            .and(with_context(context))
+            .and_then(handlers::health)
+    }
+
+    /// Fails while the database or the batching worker does not answer.
+    pub fn ready(
+        context: Context,
+        options: ApiOptions,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::path!("readyz")
+            .and(warp::get())
+            .and(with_context(context))
+            .and(with_options(options))
+            .and_then(handlers::ready)
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct NamespaceQuery {
+        pub namespace: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct WatchQuery {
+        pub namespace: Option<String>,
+        pub application_id: Option<String>,
+        pub prefix: Option<String>,
+    }
+
+    /// The caller of a request, by its api key sent as `Authorization: Bearer <key>` header and
+    /// the client certificate of its connection.
+    pub fn caller(
+        certificate_names: Vec<String>,
+    ) -> impl Filter<Extract = (Caller,), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("authorization").map(move |header: Option<String>| {
+            let api_key =
+                header.and_then(|header| header.strip_prefix("Bearer ").map(str::to_owned));
+            Caller::new(api_key, &certificate_names)
+        })
+    }
+
+    pub fn with_context(
         context: Context,
     ) -> impl Filter<Extract = (Context,), Error = std::convert::Infallible> + Clone {
         warp::any().map(move || context.clone())
     }
 
-    fn json_body() -> impl Filter<Extract = (RestLeasingRequest,), Error = warp::Rejection> + Clone
-    {
+    pub fn with_options(
+        options: ApiOptions,
+    ) -> impl Filter<Extract = (ApiOptions,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || options.clone())
+    }
+
+    pub fn json_body<T: DeserializeOwned + Send>(
+    ) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
         warp::body::content_length_limit(1024 * 16).and(warp::body::json())
     }
 }
 
 mod handlers {
-    use lld_common::{RestLeasingRequest, RestLeasingResponse};
+    use lld_common::{
+        LeasingEvent, LeasingRejection, LeasingStatus, LldError, RestLeasingRequest,
+        RestLeasingResponse, RestMultiLeasingRequest, RestMultiLeasingResponse, RestReleaseRequest,
+        RestReleaseResponse, RestStatusResponse, WatchFilter,
+    };
 
-    use crate::context::{Context, LeasingResponse};
+    use super::filters::{NamespaceQuery, WatchQuery};
+    use crate::auth::Caller;
+    use crate::cache::LeasingId;
+    use crate::context::{Context, LeasingResponse, MultiLeasingResponse};
+    use crate::metrics::Protocol;
+    use crate::ApiOptions;
     use std::convert::Infallible;
+    use tokio::sync::{broadcast, mpsc, watch};
+    use tokio_stream::wrappers::{ReceiverStream, WatchStream};
+    use tokio_stream::StreamExt;
+    use warp::http::StatusCode;
+    use warp::sse::Event;
+    use warp::Reply;
 
     pub async fn request_leasing(
         request: RestLeasingRequest,
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = options.leasing_id(request.namespace, request.application_id);
+        let duration = match options.effective_duration(&id, request.duration) {
+            Ok(duration) => duration,
+            Err(reason) => {
+                context.metrics().count_invalid(Protocol::Http);
+                return Ok(warp::reply::json(&RestLeasingResponse::Invalid { reason }));
+            }
+        };
+        if let Err(reason) = options.check_metadata(request.metadata.as_deref()) {
+            context.metrics().count_invalid(Protocol::Http);
+            return Ok(warp::reply::json(&RestLeasingResponse::Invalid { reason }));
+        }
+
+        let response = match request.wait {
+            Some(timeout) => {
+                context
+                    .wait_for_leasing(
+                        &caller,
+                        id,
+                        request.instance_id,
+                        duration,
+                        request.priority,
+                        request.metadata,
+                        timeout.min(options.max_wait),
+                    )
+                    .await
+            }
+            None => {
+                context
+                    .request_leasing(
+                        &caller,
+                        id,
+                        request.instance_id,
+                        duration,
+                        request.priority,
+                        request.metadata,
+                    )
+                    .await
+            }
+        };
+        context.metrics().count_leasing(Protocol::Http, &response);
+
+        Ok(match response {
+            Ok(LeasingResponse::Granted { validity, token }) => {
+                warp::reply::json(&RestLeasingResponse::Granted {
+                    validity,
+                    token,
+                    duration,
+                })
+            }
+            Ok(LeasingResponse::Rejected(rejection)) => {
+                let LeasingRejection {
+                    remaining,
+                    instance_id,
+                    metadata,
+                } = options.filter_rejection(rejection);
+                warp::reply::json(&RestLeasingResponse::Rejected {
+                    remaining,
+                    instance_id,
+                    metadata,
+                })
+            }
+            Ok(LeasingResponse::Preempted) => warp::reply::json(&RestLeasingResponse::Preempted),
+            Ok(LeasingResponse::Unavailable { retry_after }) => {
+                warp::reply::json(&RestLeasingResponse::Unavailable { retry_after })
+            }
+            Ok(LeasingResponse::Released) => warp::reply::json(&RestLeasingResponse::Error),
+            Err(LldError::Unauthorized(reason)) => {
+                warp::reply::json(&RestLeasingResponse::Unauthorized { reason })
+            }
+            Err(e) => {
+                error!("Error while waiting for database result {:?}", e);
+                warp::reply::json(&RestLeasingResponse::Error)
+            }
+        })
+    }
+
+    pub async fn request_leasings(
+        request: RestMultiLeasingRequest,
+        caller: Caller,
         context: Context,
+        options: ApiOptions,
     ) -> Result<impl warp::Reply, Infallible> {
+        let namespace = options.namespace(request.namespace);
+        let ids: Vec<LeasingId> = request
+            .application_ids
+            .into_iter()
+            .map(|application_id| LeasingId {
+                namespace: namespace.clone(),
+                application_id,
+            })
+            .collect();
+        let duration = match options.effective_duration_all(&ids, request.duration) {
+            Ok(duration) => duration,
+            Err(reason) => {
+                context.metrics().count_invalid(Protocol::Http);
+                return Ok(warp::reply::json(&RestMultiLeasingResponse::Invalid {
+                    reason,
+                }));
+            }
+        };
+
         let response = context
-            .request_leasing(
-                request.application_id.clone(),
-                request.instance_id.clone(),
-                request.duration,
-            )
+            .request_leasings(&caller, ids, request.instance_id, duration)
             .await;
+        context
+            .metrics()
+            .count_multi_leasing(Protocol::Http, &response);
 
         Ok(match response {
-            Ok(LeasingResponse::Granted { validity }) => {
-                warp::reply::json(&RestLeasingResponse::Granted { validity })
+            Ok(MultiLeasingResponse::Granted { validity, tokens }) => {
+                warp::reply::json(&RestMultiLeasingResponse::Granted {
+                    validity,
+                    duration,
+                    tokens,
+                })
+            }
+            Ok(MultiLeasingResponse::Rejected {
+                application_id,
+                rejection,
+            }) => {
+                let LeasingRejection {
+                    remaining,
+                    instance_id,
+                    metadata,
+                } = options.filter_rejection(rejection);
+                warp::reply::json(&RestMultiLeasingResponse::Rejected {
+                    application_id,
+                    remaining,
+                    instance_id,
+                    metadata,
+                })
+            }
+            Ok(MultiLeasingResponse::Unavailable { retry_after }) => {
+                warp::reply::json(&RestMultiLeasingResponse::Unavailable { retry_after })
+            }
+            Err(LldError::Unauthorized(reason)) => {
+                warp::reply::json(&RestMultiLeasingResponse::Unauthorized { reason })
             }
-            Ok(LeasingResponse::Rejected) => warp::reply::json(&RestLeasingResponse::Rejected),
             Err(e) => {
                 error!("Error while waiting for database result {:?}", e);
-                warp::reply::json(&RestLeasingResponse::Error)
+                warp::reply::json(&RestMultiLeasingResponse::Error)
+            }
+        })
+    }
+
+    pub async fn release_leasing(
+        request: RestReleaseRequest,
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = options.leasing_id(request.namespace, request.application_id);
+        let response = context
+            .release_leasing(&caller, id, request.instance_id)
+            .await;
+        context.metrics().count_leasing(Protocol::Http, &response);
+
+        Ok(match response {
+            Ok(LeasingResponse::Released) => warp::reply::json(&RestReleaseResponse::Released),
+            Ok(LeasingResponse::Rejected(_)) => warp::reply::json(&RestReleaseResponse::Rejected),
+            Ok(
+                LeasingResponse::Granted { .. }
+                | LeasingResponse::Preempted
+                | LeasingResponse::Unavailable { .. },
+            ) => warp::reply::json(&RestReleaseResponse::Error),
+            Err(LldError::Unauthorized(reason)) => {
+                warp::reply::json(&RestReleaseResponse::Unauthorized { reason })
+            }
+            Err(e) => {
+                error!("Error while waiting for database result {:?}", e);
+                warp::reply::json(&RestReleaseResponse::Error)
             }
         })
     }
+
+    pub async fn query_leasing(
+        application_id: String,
+        query: NamespaceQuery,
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let id = options.leasing_id(query.namespace, application_id);
+        let response = context.query_leasing(&caller, id).await;
+
+        Ok(match response {
+            Ok(Some(LeasingStatus {
+                instance_id,
+                validity,
+                remaining,
+                token,
+                metadata,
+            })) => warp::reply::json(&RestStatusResponse::Leased {
+                instance_id,
+                validity,
+                remaining,
+                token,
+                metadata,
+            }),
+            Ok(None) => warp::reply::json(&RestStatusResponse::Free),
+            Err(LldError::Unauthorized(reason)) => {
+                warp::reply::json(&RestStatusResponse::Unauthorized { reason })
+            }
+            Err(e) => {
+                error!("Error while querying the leasing status {:?}", e);
+                warp::reply::json(&RestStatusResponse::Error)
+            }
+        })
+    }
+
+    pub async fn watch_leasings(
+        query: WatchQuery,
+        caller: Caller,
+        context: Context,
+        options: ApiOptions,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let namespace = options.namespace(query.namespace);
+        let filter = match (query.application_id, query.prefix) {
+            (Some(application_id), _) => WatchFilter::Application(application_id),
+            (None, prefix) => WatchFilter::Prefix(prefix.unwrap_or_default()),
+        };
+
+        let receiver = match context.watch_leasings(&caller, &namespace, &filter) {
+            Ok(receiver) => receiver,
+            Err(LldError::Unauthorized(reason)) => {
+                return Ok(
+                    warp::reply::with_status(reason, StatusCode::UNAUTHORIZED).into_response()
+                );
+            }
+            Err(e) => {
+                error!("Error while watching the leasings {:?}", e);
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        tokio::spawn(forward_events(receiver, namespace, filter, tx));
+        let events = ReceiverStream::new(rx);
+        let stop = WatchStream::new(shutdown)
+            .filter(|shutdown| *shutdown)
+            .map(|_| None);
+        let events = events
+            .map(Some)
+            .merge(stop)
+            .take_while(Option::is_some)
+            .filter_map(|event| event);
+
+        Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response())
+    }
+
+    /// Events a watcher may fall behind the leasing changes before they are dropped.
+    const WATCH_BUFFER: usize = 64;
+
+    /// Send the matching events to a watcher until it goes away. A watcher that lagged behind gets
+    /// a `lagged` event with the number of missed events and its stream ends, as it cannot tell
+    /// which leasings changed.
+    async fn forward_events(
+        mut receiver: broadcast::Receiver<LeasingEvent>,
+        namespace: String,
+        filter: WatchFilter,
+        tx: mpsc::Sender<Result<Event, Infallible>>,
+    ) {
+        loop {
+            let event = tokio::select! {
+                event = receiver.recv() => event,
+                _ = tx.closed() => return,
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Watcher missed {} leasing events", missed);
+                    let lagged = Event::default().event("lagged").data(missed.to_string());
+                    let _ = tx.send(Ok(lagged)).await;
+                    return;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+
+            if event.namespace != namespace || !filter.matches(&event.application_id) {
+                continue;
+            }
+
+            let event = match Event::default().event(event.kind.name()).json_data(&event) {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("Cannot encode leasing event {:?}", e);
+                    continue;
+                }
+            };
+            if tx.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub async fn metrics(caller: Caller, context: Context) -> Result<impl warp::Reply, Infallible> {
+        if let Err(LldError::Unauthorized(reason)) = context.credentials().authorize_admin(&caller)
+        {
+            return Ok(warp::reply::with_status(reason, StatusCode::UNAUTHORIZED).into_response());
+        }
+
+        Ok(match context.encode_metrics().await {
+            Ok(metrics) => {
+                warp::reply::with_header(metrics, "content-type", "text/plain; version=0.0.4")
+                    .into_response()
+            }
+            Err(e) => {
+                error!("Cannot encode metrics {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        })
+    }
+
+    pub async fn health(context: Context) -> Result<impl warp::Reply, Infallible> {
+        Ok(if context.is_alive() {
+            warp::reply::with_status("ok".to_owned(), StatusCode::OK)
+        } else {
+            warp::reply::with_status(
+                "The batching worker stopped".to_owned(),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+        })
+    }
+
+    pub async fn ready(
+        context: Context,
+        options: ApiOptions,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match context.check_ready(options.ready_timeout).await {
+            Ok(()) => warp::reply::with_status("ok".to_owned(), StatusCode::OK),
+            Err(reason) => {
+                warn!("Server is not ready: {}", reason);
+                warp::reply::with_status(reason, StatusCode::SERVICE_UNAVAILABLE)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tokio::sync::watch;
+    use warp::http::StatusCode;
+
+    use super::filters;
+    use crate::auth::Credentials;
+    use crate::context::Context;
+    use crate::context_naive::ContextNaive;
+    use crate::database;
+    use crate::epoch::Epoch;
+    use crate::policy::{CapacityPolicy, DurationLimits, DurationMode, DurationPolicy, Preemption};
+    use crate::ApiOptions;
+
+    fn options() -> ApiOptions {
+        let limits = DurationLimits {
+            min: 1,
+            max: 60_000,
+            default: 10_000,
+        };
+        ApiOptions {
+            expose_holder: true,
+            duration_policy: Arc::new(DurationPolicy::new(DurationMode::Reject, limits).unwrap()),
+            max_wait: 0,
+            max_metadata_length: 1024,
+            default_namespace: "default".to_owned(),
+            ready_timeout: 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_need_an_admin_key_and_count_the_responses() {
+        let file = std::env::temp_dir().join(format!("lld-{}-http-metrics", std::process::id()));
+        std::fs::write(&file, "admin,*,\nuser,default,\n").unwrap();
+        let mut credentials = Credentials::disabled();
+        credentials
+            .load_keys(file.to_str().unwrap(), "default")
+            .unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        let context = Context::Naive(
+            ContextNaive::new(
+                database::tests::open("http-metrics"),
+                Arc::new(CapacityPolicy::new(1).unwrap()),
+                Arc::new(credentials),
+                Preemption::Disabled,
+                Epoch::new(1, 0, None),
+            )
+            .unwrap(),
+        );
+        let (_shutdown, shutdown) = watch::channel(false);
+        let filter = filters::leasing(context, options(), Vec::new(), shutdown);
+
+        for instance_id in ["a", "b"] {
+            warp::test::request()
+                .method("POST")
+                .path("/request")
+                .header("authorization", "Bearer user")
+                .json(&json!({ "application_id": "app", "instance_id": instance_id }))
+                .reply(&filter)
+                .await;
+        }
+
+        let metrics = |key: &str| {
+            warp::test::request()
+                .method("GET")
+                .path("/metrics")
+                .header("authorization", format!("Bearer {}", key))
+                .reply(&filter)
+        };
+
+        assert_eq!(metrics("user").await.status(), StatusCode::UNAUTHORIZED);
+
+        let response = metrics("admin").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = std::str::from_utf8(response.body()).unwrap();
+        assert!(body.contains("lld_responses_total{protocol=\"http\",result=\"granted\"} 1"));
+        assert!(body.contains("lld_responses_total{protocol=\"http\",result=\"rejected\"} 1"));
+    }
 }
@@ -1,32 +1,161 @@
 #[macro_use]
 extern crate log;
 
+mod admin_api;
+mod auth;
 mod cache;
 mod context;
 mod context_batching;
 mod context_naive;
 mod database;
+mod epoch;
+mod events;
 mod http_api;
+mod metrics;
+mod policy;
+mod shutdown;
 mod tcp_api;
+mod wait_queue;
 
 #[cfg(feature = "dqlite")]
 mod dqlite;
 #[cfg(not(feature = "dqlite"))]
 mod sqlite;
 
+use auth::Credentials;
+use cache::LeasingId;
 use clap::Parser;
 use context::Context;
 use context_batching::ContextBatching;
 use context_naive::ContextNaive;
-use database::Database;
-use lld_common::{LldMode, LldResult};
+use database::{Database, DatabaseOptions, SqliteJournalMode, SqliteSynchronous};
+use epoch::{Epoch, Quarantine};
+use lld_common::{get_current_time, LeasingRejection, LldMode, LldResult};
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use policy::{CapacityPolicy, DurationLimits, DurationMode, DurationPolicy, Preemption};
+use shutdown::ShutdownController;
 
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::spawn;
+use tokio_openssl::SslStream;
 
 #[derive(Debug, Clone)]
 pub struct SslContext {
     pub cert_file: String,
     pub key_file: String,
+    /// Clients must present a certificate signed by this CA if it is given.
+    pub client_ca_file: Option<String>,
+}
+
+impl SslContext {
+    pub fn acceptor(&self) -> LldResult<SslAcceptor> {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        acceptor.set_private_key_file(&self.key_file, SslFiletype::PEM)?;
+        acceptor.set_certificate_chain_file(&self.cert_file)?;
+        acceptor.check_private_key()?;
+
+        if let Some(ref client_ca_file) = self.client_ca_file {
+            acceptor.set_ca_file(client_ca_file)?;
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+
+        Ok(acceptor.build())
+    }
+}
+
+/// Accept a tls connection, returns the stream and the names of the client certificate if the
+/// client sent one.
+pub async fn accept_ssl(
+    acceptor: &SslAcceptor,
+    socket: TcpStream,
+) -> LldResult<(SslStream<TcpStream>, Vec<String>)> {
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, socket)?;
+    Pin::new(&mut stream).accept().await?;
+
+    let certificate_names = stream
+        .ssl()
+        .peer_certificate()
+        .map(|certificate| auth::certificate_names(&certificate))
+        .unwrap_or_default();
+    Ok((stream, certificate_names))
+}
+
+/// Settings shared by the http and the tcp endpoint.
+#[derive(Debug, Clone)]
+pub struct ApiOptions {
+    /// Tell rejected instances which instance currently holds the leasing.
+    pub expose_holder: bool,
+    pub duration_policy: Arc<DurationPolicy>,
+    /// Longest time in ms a request may wait for a leasing to become free.
+    pub max_wait: u64,
+    /// Longest metadata in bytes a request may store with a leasing.
+    pub max_metadata_length: usize,
+    /// Namespace of requests without a namespace.
+    pub default_namespace: String,
+    /// Longest time in ms the readiness check waits for the database.
+    pub ready_timeout: u64,
+}
+
+impl ApiOptions {
+    pub fn filter_rejection(&self, mut rejection: LeasingRejection) -> LeasingRejection {
+        if !self.expose_holder {
+            rejection.instance_id = None;
+        }
+        rejection
+    }
+
+    pub fn namespace(&self, namespace: Option<String>) -> String {
+        namespace
+            .filter(|namespace| !namespace.is_empty())
+            .unwrap_or_else(|| self.default_namespace.clone())
+    }
+
+    pub fn leasing_id(&self, namespace: Option<String>, application_id: String) -> LeasingId {
+        LeasingId {
+            namespace: self.namespace(namespace),
+            application_id,
+        }
+    }
+
+    pub fn effective_duration(
+        &self,
+        id: &LeasingId,
+        requested: Option<u64>,
+    ) -> Result<u64, String> {
+        self.duration_policy.effective_duration(id, requested)
+    }
+
+    pub fn check_metadata(&self, metadata: Option<&str>) -> Result<(), String> {
+        match metadata {
+            Some(metadata) if metadata.len() > self.max_metadata_length => Err(format!(
+                "Metadata of {} bytes exceeds the maximum of {} bytes",
+                metadata.len(),
+                self.max_metadata_length
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The duration granted to all leasings of a multi leasing request, which is the shortest
+    /// effective duration of its applications.
+    pub fn effective_duration_all(
+        &self,
+        ids: &[LeasingId],
+        requested: Option<u64>,
+    ) -> Result<u64, String> {
+        let mut duration = None;
+        for id in ids {
+            let effective = self.effective_duration(id, requested)?;
+            duration = Some(duration.map_or(effective, |d: u64| d.min(effective)));
+        }
+        duration.ok_or_else(|| "No application ids given".to_owned())
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -36,14 +165,125 @@ struct Args {
     http_port: u16,
     #[clap(long, default_value_t = 3040)]
     tcp_port: u16,
-    #[clap(long)]
+    /// Port of the admin endpoint, which lists and changes leasings of any instance
+    #[clap(long, default_value_t = 3050)]
+    admin_port: u16,
+    /// Address the admin endpoint listens on
+    #[clap(long, env = "LLD_ADMIN_ADDRESS", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    admin_address: IpAddr,
+    /// Trade durability for speed with the sqlite pragmas `synchronous=OFF` and
+    /// `journal_mode=WAL`
+    #[clap(long, env = "LLD_SQLITE_OPTIMIZATION")]
     sqlite_optimization: bool,
+    /// File of the sqlite database
+    #[clap(long, env = "LLD_DATABASE_PATH", default_value_t = String::from("./database.db"))]
+    database_path: String,
+    /// The sqlite `synchronous` pragma, takes precedence over `--sqlite-optimization`
+    #[clap(long, arg_enum, env = "LLD_SQLITE_SYNCHRONOUS")]
+    sqlite_synchronous: Option<SqliteSynchronous>,
+    /// The sqlite `journal_mode` pragma, takes precedence over `--sqlite-optimization`
+    #[clap(long, arg_enum, env = "LLD_SQLITE_JOURNAL_MODE")]
+    sqlite_journal_mode: Option<SqliteJournalMode>,
+    /// Time in ms sqlite waits for a lock on the database before failing
+    #[clap(long, env = "LLD_SQLITE_BUSY_TIMEOUT")]
+    sqlite_busy_timeout: Option<u64>,
+    /// The sqlite `cache_size` pragma, in pages if positive and in KiB if negative
+    #[clap(long, env = "LLD_SQLITE_CACHE_SIZE", allow_hyphen_values = true)]
+    sqlite_cache_size: Option<i64>,
+    /// Name of the dqlite database
+    #[clap(long, env = "LLD_DQLITE_DATABASE", default_value_t = String::from("leasings"))]
+    dqlite_database: String,
+    /// File with the addresses of the dqlite cluster, one per line
+    #[clap(long, env = "LLD_DQLITE_CLUSTER_FILE", default_value_t = String::from("ips.csv"))]
+    dqlite_cluster_file: String,
     #[clap(long, default_value_t=LldMode::Batching)]
     mode: LldMode,
     #[clap(long, default_value_t=String::from("certificates/lld-server.key"))]
     ssl_key_file: String,
     #[clap(long, default_value_t=String::from("certificates/lld-server.crt"))]
     ssl_cert_file: String,
+    /// Include the instance id of the current holder in rejections
+    #[clap(long)]
+    expose_holder: bool,
+    /// Shortest leasing duration in ms
+    #[clap(long, default_value_t = 100)]
+    min_duration: u64,
+    /// Longest leasing duration in ms
+    #[clap(long, default_value_t = 3_600_000)]
+    max_duration: u64,
+    /// Leasing duration in ms for requests without a duration
+    #[clap(long, default_value_t = 5000)]
+    default_duration: u64,
+    /// Clamp or reject durations outside of the allowed range
+    #[clap(long, arg_enum, default_value_t = DurationMode::Clamp)]
+    duration_mode: DurationMode,
+    /// Longest time in ms a blocking acquire may wait for a leasing
+    #[clap(long, default_value_t = 60_000)]
+    max_wait: u64,
+    /// Csv file with per application limits `application_id,min,max,default` of the default
+    /// namespace
+    #[clap(long)]
+    duration_policy_file: Option<String>,
+    /// Number of instances that may hold a leasing at the same time
+    #[clap(long, default_value_t = 1)]
+    capacity: u64,
+    /// Csv file with per application capacities `application_id,capacity` of the default
+    /// namespace
+    #[clap(long)]
+    capacity_file: Option<String>,
+    /// Whether requests with a higher priority take over leasings of lower priority holders
+    #[clap(long, arg_enum, default_value_t = Preemption::Disabled)]
+    preemption: Preemption,
+    /// Longest metadata in bytes stored with a leasing
+    #[clap(long, default_value_t = 1024)]
+    max_metadata_length: usize,
+    /// Namespace of requests without a namespace
+    #[clap(long, default_value_t = String::from("default"))]
+    default_namespace: String,
+    /// Csv file with per namespace limits `namespace,min,max,default`
+    #[clap(long)]
+    namespace_file: Option<String>,
+    /// Csv file with api keys `key,namespace,application_id`, requests without a valid key are
+    /// refused if it is given
+    #[clap(long)]
+    credentials_file: Option<String>,
+    /// CA that signs client certificates, clients without such a certificate are refused if it
+    /// is given
+    #[clap(long)]
+    ssl_client_ca_file: Option<String>,
+    /// Csv file with client certificate names `name,namespace,application_id`, requests are
+    /// refused unless the common name or a subject alternative name of their certificate, or
+    /// their api key, is valid for the leasing
+    #[clap(long)]
+    client_identity_file: Option<String>,
+    /// Longest time in ms the readiness check waits for the database and the batching worker
+    #[clap(long, default_value_t = 1000)]
+    ready_timeout: u64,
+    /// Drop all stored leasings on startup instead of reloading them, acquires are refused
+    /// until the quarantine has passed
+    #[clap(long)]
+    reset_database: bool,
+    /// Length of the quarantine in ms after a start without stored leasings, defaults to the
+    /// longest duration granted for the application
+    #[clap(long, env = "LLD_QUARANTINE_DURATION")]
+    quarantine_duration: Option<u64>,
+    /// Grant leasings right away even if the store has no leasings, only safe for a new
+    /// deployment whose clients never held a leasing
+    #[clap(long, env = "LLD_SKIP_QUARANTINE")]
+    skip_quarantine: bool,
+    /// Longest time in ms a shutdown waits for open requests to be answered
+    #[clap(long, default_value_t = 10_000)]
+    shutdown_timeout: u64,
+}
+
+/// Wait for ctrl_c or SIGTERM.
+async fn wait_for_signal() -> LldResult<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
 }
 
 #[tokio::main]
@@ -60,52 +300,222 @@ async fn main() -> LldResult<()> {
         Some(SslContext {
             cert_file: args.ssl_cert_file,
             key_file: args.ssl_key_file,
+            client_ca_file: args.ssl_client_ca_file.clone(),
         })
     } else {
         info!("Server will use plain text");
         None
     };
 
+    let mut duration_policy = DurationPolicy::new(
+        args.duration_mode,
+        DurationLimits {
+            min: args.min_duration,
+            max: args.max_duration,
+            default: args.default_duration,
+        },
+    )?;
+    if let Some(ref file) = args.namespace_file {
+        info!("Load namespaces from {}", file);
+        duration_policy.load_namespaces(file)?;
+    }
+    if let Some(ref file) = args.duration_policy_file {
+        info!("Load duration policy from {}", file);
+        duration_policy.load_applications(file, &args.default_namespace)?;
+    }
+    let duration_policy = Arc::new(duration_policy);
+
+    let mut capacities = CapacityPolicy::new(args.capacity)?;
+    if let Some(ref file) = args.capacity_file {
+        info!("Load capacities from {}", file);
+        capacities.load_applications(file, &args.default_namespace)?;
+    }
+    let capacities = Arc::new(capacities);
+
+    let mut credentials = Credentials::disabled();
+    if let Some(ref file) = args.credentials_file {
+        info!("Load credentials from {}", file);
+        credentials.load_keys(file, &args.default_namespace)?;
+    }
+    if let Some(ref file) = args.client_identity_file {
+        info!("Load client identities from {}", file);
+        credentials.load_identities(file, &args.default_namespace)?;
+    }
+    match (&args.credentials_file, &args.client_identity_file) {
+        (None, None) if args.ssl_client_ca_file.is_some() => {
+            warn!("Any client with a valid certificate may acquire any leasing")
+        }
+        (None, None) => warn!("Authentication is disabled, any client may acquire any leasing"),
+        _ => {}
+    }
+    if args.ssl_client_ca_file.is_some() && ssl_context.is_none() {
+        warn!("Client certificates are ignored, because the server uses plain text");
+    }
+    let credentials = Arc::new(credentials);
+
+    let api_options = ApiOptions {
+        expose_holder: args.expose_holder,
+        duration_policy: duration_policy.clone(),
+        max_wait: args.max_wait,
+        max_metadata_length: args.max_metadata_length,
+        default_namespace: args.default_namespace.clone(),
+        ready_timeout: args.ready_timeout,
+    };
+
     info!("Initialize database");
-    let db = Database::open(true)?;
-    db.init()?;
+    let db = Database::open(&DatabaseOptions {
+        #[cfg(not(feature = "dqlite"))]
+        path: args.database_path.clone(),
+        #[cfg(not(feature = "dqlite"))]
+        sqlite_optimization: args.sqlite_optimization,
+        #[cfg(not(feature = "dqlite"))]
+        synchronous: args.sqlite_synchronous,
+        #[cfg(not(feature = "dqlite"))]
+        journal_mode: args.sqlite_journal_mode,
+        #[cfg(not(feature = "dqlite"))]
+        busy_timeout: args.sqlite_busy_timeout,
+        #[cfg(not(feature = "dqlite"))]
+        cache_size: args.sqlite_cache_size,
+        #[cfg(feature = "dqlite")]
+        dqlite_database: args.dqlite_database.clone(),
+        #[cfg(feature = "dqlite")]
+        dqlite_cluster_file: args.dqlite_cluster_file.clone(),
+    })?;
+    if args.reset_database {
+        warn!("Reset database, all stored leasings are dropped");
+        db.reset()?;
+    }
+    db.migrate(&args.default_namespace)?;
+
+    let quarantine = match args.quarantine_duration {
+        Some(duration) => Quarantine::Fixed(duration),
+        None => Quarantine::Policy(duration_policy),
+    };
+    let epoch = Epoch::start(&db, quarantine, args.skip_quarantine, get_current_time())?;
 
     let context = match args.mode {
         LldMode::Naive => {
             info!("Naive");
-            Context::Naive(ContextNaive::new_without_cache(db)?)
+            Context::Naive(ContextNaive::new_without_cache(
+                db,
+                capacities,
+                credentials,
+                args.preemption,
+                epoch.clone(),
+            )?)
         }
         LldMode::NaiveCaching => {
             info!("NaiveCaching");
-            Context::Naive(ContextNaive::new(db)?)
+            Context::Naive(ContextNaive::new(
+                db,
+                capacities,
+                credentials,
+                args.preemption,
+                epoch.clone(),
+            )?)
         }
         LldMode::Batching => {
             info!("Batching");
-            Context::Batching(ContextBatching::new(db)?)
+            Context::Batching(ContextBatching::new(
+                db,
+                capacities,
+                credentials,
+                args.preemption,
+                epoch.clone(),
+            )?)
         }
     };
 
+    let shutdown = ShutdownController::new();
+
     info!("Start http endpoint");
     let http_api_context = context.clone();
     let http_ssl_context = ssl_context.clone();
+    let http_api_options = api_options.clone();
     let http_port = args.http_port;
+    let http_shutdown = shutdown.subscribe();
     spawn(async move {
-        http_api::start_server(http_api_context, http_port, http_ssl_context).await;
+        http_api::start_server(
+            http_api_context,
+            http_api_options,
+            http_port,
+            http_ssl_context,
+            http_shutdown,
+        )
+        .await;
     });
 
+    // The admin endpoint changes leasings of any instance, so it is only served with a key or a
+    // client certificate that grants access to it
+    if context.credentials().has_admin() {
+        info!("Start admin endpoint");
+        let admin_api_context = context.clone();
+        let admin_ssl_context = ssl_context.clone();
+        let admin_api_options = api_options.clone();
+        let admin_addr = SocketAddr::new(args.admin_address, args.admin_port);
+        let admin_shutdown = shutdown.subscribe();
+        spawn(async move {
+            admin_api::start_server(
+                admin_api_context,
+                admin_api_options,
+                admin_addr,
+                admin_ssl_context,
+                admin_shutdown,
+            )
+            .await;
+        });
+    } else {
+        warn!("The admin endpoint is disabled, it needs a key or client certificate of the namespace `*`");
+    }
+
     info!("Start tcp endpoint");
     let tcp_api_context = context.clone();
     let tcp_port = args.tcp_port;
+    let tcp_shutdown = shutdown.subscribe();
     spawn(async move {
-        tcp_api::start_server(tcp_api_context, tcp_port, ssl_context).await;
+        tcp_api::start_server(
+            tcp_api_context,
+            api_options,
+            tcp_port,
+            ssl_context,
+            tcp_shutdown,
+        )
+        .await;
     });
 
     info!("Start working queue");
-    spawn(async move {
-        context.run().await.unwrap();
+    let worker_context = context.clone();
+    let worker = spawn(async move {
+        if let Err(e) = worker_context.run().await {
+            error!("Working queue failed: {:?}", e);
+        }
     });
 
-    tokio::signal::ctrl_c().await?;
+    wait_for_signal().await?;
+
+    info!("Shut down, stop accepting connections");
+    shutdown.shutdown();
+
+    // The worker keeps storing until the open requests were answered
+    info!("Wait for open requests");
+    if !shutdown
+        .wait(Duration::from_millis(args.shutdown_timeout))
+        .await
+    {
+        warn!(
+            "Open requests were not answered within {}ms",
+            args.shutdown_timeout
+        );
+    }
+
+    info!("Drain working queue");
+    context.stop();
+    if let Err(e) = worker.await {
+        error!("Working queue failed: {:?}", e);
+    }
+
+    info!("Close database");
+    drop(context);
 
     Ok(())
 }
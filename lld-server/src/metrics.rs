@@ -0,0 +1,293 @@
+use std::time::Duration;
+
+use lld_common::{get_current_time, LldError, LldResult};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::context::{LeasingResponse, MultiLeasingResponse};
+
+/// Buckets of the number of tasks per batch.
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
+/// Buckets of the commit latency of a batch in seconds.
+const COMMIT_LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol {
+    Http,
+    Tcp,
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Tcp => "tcp",
+        }
+    }
+}
+
+/// Counters and histograms of the server, exposed in the prometheus text format at `/metrics`.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Answered leasing requests by protocol and result.
+    responses: IntCounterVec,
+    tls_handshake_failures: IntCounterVec,
+    /// Failed database operations by sqlite or dqlite error code.
+    database_errors: IntCounterVec,
+    batch_size: Histogram,
+    commit_latency: Histogram,
+    /// Time of the last commit of the batching worker in ms.
+    last_commit: IntGauge,
+    queue_depth: IntGauge,
+    cache_size: IntGauge,
+    epoch: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> LldResult<Self> {
+        let registry = Registry::new();
+
+        Ok(Self {
+            responses: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("lld_responses_total", "Answered leasing requests"),
+                    &["protocol", "result"],
+                ),
+            )?,
+            tls_handshake_failures: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("lld_tls_handshake_failures_total", "Failed tls handshakes"),
+                    &["protocol"],
+                ),
+            )?,
+            database_errors: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("lld_database_errors_total", "Failed database operations"),
+                    &["code"],
+                ),
+            )?,
+            batch_size: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new("lld_batch_size", "Tasks committed per batch")
+                        .buckets(BATCH_SIZE_BUCKETS.to_vec()),
+                ),
+            )?,
+            commit_latency: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "lld_batch_commit_seconds",
+                        "Time to commit a batch to the database",
+                    )
+                    .buckets(COMMIT_LATENCY_BUCKETS.to_vec()),
+                ),
+            )?,
+            last_commit: register(
+                &registry,
+                IntGauge::new(
+                    "lld_batch_last_commit_timestamp_ms",
+                    "Time of the last batch commit",
+                ),
+            )?,
+            queue_depth: register(
+                &registry,
+                IntGauge::new(
+                    "lld_queue_depth",
+                    "Requests waiting for the next batch commit",
+                ),
+            )?,
+            cache_size: register(
+                &registry,
+                IntGauge::new("lld_cache_size", "Holders stored in the leasing cache"),
+            )?,
+            epoch: register(
+                &registry,
+                IntGauge::new("lld_epoch", "Generation of the server on its store"),
+            )?,
+            registry,
+        })
+    }
+
+    pub fn count_leasing(&self, protocol: Protocol, response: &LldResult<LeasingResponse>) {
+        let result = match response {
+            Ok(LeasingResponse::Granted { .. }) => "granted",
+            Ok(LeasingResponse::Released) => "released",
+            Ok(LeasingResponse::Rejected(_)) => "rejected",
+            Ok(LeasingResponse::Preempted) => "preempted",
+            Ok(LeasingResponse::Unavailable { .. }) => "unavailable",
+            Err(e) => self.error_result(e),
+        };
+        self.count_response(protocol, result);
+    }
+
+    pub fn count_multi_leasing(
+        &self,
+        protocol: Protocol,
+        response: &LldResult<MultiLeasingResponse>,
+    ) {
+        let result = match response {
+            Ok(MultiLeasingResponse::Granted { .. }) => "granted",
+            Ok(MultiLeasingResponse::Rejected { .. }) => "rejected",
+            Ok(MultiLeasingResponse::Unavailable { .. }) => "unavailable",
+            Err(e) => self.error_result(e),
+        };
+        self.count_response(protocol, result);
+    }
+
+    /// A request that violates the limits of the server.
+    pub fn count_invalid(&self, protocol: Protocol) {
+        self.count_response(protocol, "invalid");
+    }
+
+    pub fn count_tls_handshake_failure(&self, protocol: Protocol) {
+        self.tls_handshake_failures
+            .with_label_values(&[protocol.name()])
+            .inc();
+    }
+
+    pub fn count_error(&self, error: &LldError) {
+        if let LldError::DatabaseError { code, .. } = error {
+            let code = code.map(|code| code.to_string()).unwrap_or_default();
+            self.database_errors.with_label_values(&[&code]).inc();
+        }
+    }
+
+    pub fn observe_commit(&self, tasks: usize, latency: Duration) {
+        self.batch_size.observe(tasks as f64);
+        self.commit_latency.observe(latency.as_secs_f64());
+        self.last_commit.set(get_current_time() as i64);
+    }
+
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.set(depth as i64);
+    }
+
+    pub fn set_cache_size(&self, size: usize) {
+        self.cache_size.set(size as i64);
+    }
+
+    pub fn set_epoch(&self, epoch: u64) {
+        self.epoch.set(epoch as i64);
+    }
+
+    /// All metrics in the prometheus text format.
+    pub fn encode(&self) -> LldResult<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(to_lld_error)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    fn count_response(&self, protocol: Protocol, result: &str) {
+        self.responses
+            .with_label_values(&[protocol.name(), result])
+            .inc();
+    }
+
+    fn error_result(&self, error: &LldError) -> &'static str {
+        self.count_error(error);
+        match error {
+            LldError::Unauthorized(_) => "unauthorized",
+            _ => "error",
+        }
+    }
+}
+
+fn register<T: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<T>,
+) -> LldResult<T> {
+    let metric = metric.map_err(to_lld_error)?;
+    registry
+        .register(Box::new(metric.clone()))
+        .map_err(to_lld_error)?;
+    Ok(metric)
+}
+
+fn to_lld_error(error: prometheus::Error) -> LldError {
+    LldError::WrappedError("prometheus error", format!("{}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use lld_common::LeasingRejection;
+
+    use super::*;
+
+    fn responses(metrics: &Metrics, protocol: Protocol, result: &str) -> u64 {
+        metrics
+            .responses
+            .with_label_values(&[protocol.name(), result])
+            .get()
+    }
+
+    #[test]
+    fn responses_are_counted_by_protocol_and_result() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics.count_leasing(
+            Protocol::Http,
+            &Ok(LeasingResponse::Granted {
+                validity: 1,
+                token: 1,
+            }),
+        );
+        metrics.count_leasing(
+            Protocol::Tcp,
+            &Ok(LeasingResponse::Rejected(LeasingRejection::default())),
+        );
+        metrics.count_multi_leasing(
+            Protocol::Tcp,
+            &Ok(MultiLeasingResponse::Rejected {
+                application_id: "app".to_owned(),
+                rejection: LeasingRejection::default(),
+            }),
+        );
+        metrics.count_invalid(Protocol::Http);
+
+        assert_eq!(responses(&metrics, Protocol::Http, "granted"), 1);
+        assert_eq!(responses(&metrics, Protocol::Tcp, "granted"), 0);
+        assert_eq!(responses(&metrics, Protocol::Tcp, "rejected"), 2);
+        assert_eq!(responses(&metrics, Protocol::Http, "invalid"), 1);
+    }
+
+    #[test]
+    fn errors_are_counted_by_database_error_code() {
+        let metrics = Metrics::new().unwrap();
+        let error = || LldError::DatabaseError {
+            code: Some(5),
+            message: Some("database is locked".to_owned()),
+        };
+
+        metrics.count_leasing(Protocol::Http, &Err(error()));
+        metrics.count_multi_leasing(Protocol::Tcp, &Err(error()));
+        metrics.count_leasing(
+            Protocol::Http,
+            &Err(LldError::Unauthorized("Unknown api key".to_owned())),
+        );
+        metrics.count_error(&LldError::WrappedError(
+            "error",
+            "not a database error".to_owned(),
+        ));
+
+        assert_eq!(responses(&metrics, Protocol::Http, "error"), 1);
+        assert_eq!(responses(&metrics, Protocol::Tcp, "error"), 1);
+        assert_eq!(responses(&metrics, Protocol::Http, "unauthorized"), 1);
+        assert_eq!(metrics.database_errors.with_label_values(&["5"]).get(), 2);
+
+        let encoded = metrics.encode().unwrap();
+        assert!(encoded.contains("lld_database_errors_total{code=\"5\"} 2"));
+        assert!(encoded.contains("lld_responses_total{protocol=\"http\",result=\"error\"} 1"));
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+
+use clap::ArgEnum;
+use lld_common::LldError;
+
+use crate::{cache::LeasingId, LldResult};
+
+/// What to do with a requested duration outside of the allowed range.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DurationMode {
+    Clamp,
+    Reject,
+}
+
+/// Whether a request with a higher priority may take over the leasing of a holder with a lower
+/// priority, if there is no free capacity.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preemption {
+    Disabled,
+    /// The holder loses the leasing right away.
+    Immediate,
+    /// The holder is told at its next renewal that it lost the leasing.
+    Renewal,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DurationLimits {
+    pub min: u64,
+    pub max: u64,
+    pub default: u64,
+}
+
+/// Limits the duration of leasings, globally, per namespace and per application.
+#[derive(Debug, Clone)]
+pub struct DurationPolicy {
+    mode: DurationMode,
+    global: DurationLimits,
+    namespaces: HashMap<String, DurationLimits>,
+    applications: HashMap<LeasingId, DurationLimits>,
+}
+
+impl DurationPolicy {
+    pub fn new(mode: DurationMode, global: DurationLimits) -> LldResult<Self> {
+        check_limits("global", &global)?;
+
+        Ok(Self {
+            mode,
+            global,
+            namespaces: HashMap::new(),
+            applications: HashMap::new(),
+        })
+    }
+
+    /// Load per namespace limits from a csv file.
+    ///
+    /// Every line has the form `namespace,min,max,default`. Empty fields fall back to the global
+    /// limits, empty lines and lines starting with `#` are ignored.
+    pub fn load_namespaces(&mut self, file: &str) -> LldResult<()> {
+        for (line, fields) in read_policy_file(file, 4)? {
+            let limits = parse_limits(file, line, &fields, &self.global)?;
+            self.namespaces.insert(fields[0].to_owned(), limits);
+        }
+
+        Ok(())
+    }
+
+    /// Load per application limits of a namespace from a csv file.
+    ///
+    /// Every line has the form `application_id,min,max,default`. Empty fields fall back to the
+    /// limits of the namespace, empty lines and lines starting with `#` are ignored.
+    pub fn load_applications(&mut self, file: &str, namespace: &str) -> LldResult<()> {
+        let fallback = *self.namespace_limits(namespace);
+
+        for (line, fields) in read_policy_file(file, 4)? {
+            let limits = parse_limits(file, line, &fields, &fallback)?;
+            self.applications
+                .insert(LeasingId::new(namespace, &fields[0]), limits);
+        }
+
+        Ok(())
+    }
+
+    /// The namespaces with their own limits.
+    pub fn namespaces(&self) -> impl Iterator<Item = &String> {
+        self.namespaces.keys()
+    }
+
+    fn namespace_limits(&self, namespace: &str) -> &DurationLimits {
+        self.namespaces.get(namespace).unwrap_or(&self.global)
+    }
+
+    pub fn limits(&self, id: &LeasingId) -> &DurationLimits {
+        self.applications
+            .get(id)
+            .unwrap_or_else(|| self.namespace_limits(&id.namespace))
+    }
+
+    /// The duration that will be granted for a request, or the reason why it is invalid.
+    ///
+    /// A request without a duration gets the default duration of the application.
+    pub fn effective_duration(
+        &self,
+        id: &LeasingId,
+        requested: Option<u64>,
+    ) -> Result<u64, String> {
+        let limits = self.limits(id);
+
+        let duration = match requested {
+            Some(duration) => duration,
+            None => return Ok(limits.default),
+        };
+
+        if duration >= limits.min && duration <= limits.max {
+            return Ok(duration);
+        }
+
+        match self.mode {
+            DurationMode::Clamp => Ok(duration.clamp(limits.min, limits.max)),
+            DurationMode::Reject => Err(format!(
+                "Duration {} ms is outside of {}..={} ms",
+                duration, limits.min, limits.max
+            )),
+        }
+    }
+}
+
+/// Number of instances that may hold the leasing of an application at the same time.
+#[derive(Debug, Clone)]
+pub struct CapacityPolicy {
+    default: u64,
+    applications: HashMap<LeasingId, u64>,
+}
+
+impl CapacityPolicy {
+    pub fn new(default: u64) -> LldResult<Self> {
+        check_capacity("global", default)?;
+
+        Ok(Self {
+            default,
+            applications: HashMap::new(),
+        })
+    }
+
+    /// Load per application capacities of a namespace from a csv file.
+    ///
+    /// Every line has the form `application_id,capacity`. An empty capacity falls back to the
+    /// default, empty lines and lines starting with `#` are ignored.
+    pub fn load_applications(&mut self, file: &str, namespace: &str) -> LldResult<()> {
+        for (line, fields) in read_policy_file(file, 2)? {
+            let capacity = parse_field(file, line, &fields[1], self.default)?;
+            check_capacity(&fields[0], capacity)?;
+
+            self.applications
+                .insert(LeasingId::new(namespace, &fields[0]), capacity);
+        }
+
+        Ok(())
+    }
+
+    pub fn capacity(&self, id: &LeasingId) -> u64 {
+        self.applications.get(id).copied().unwrap_or(self.default)
+    }
+}
+
+/// Read the non-empty, non-comment lines of a csv file with their line number.
+fn read_policy_file(file: &str, field_count: usize) -> LldResult<Vec<(usize, Vec<String>)>> {
+    let content = std::fs::read_to_string(file)?;
+    let mut lines = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<String> = line.split(',').map(|f| f.trim().to_owned()).collect();
+        if fields.len() != field_count {
+            return Err(LldError::WrappedError(
+                "policy error",
+                format!("{}:{}: expected {} fields", file, index + 1, field_count),
+            ));
+        }
+
+        lines.push((index + 1, fields));
+    }
+
+    Ok(lines)
+}
+
+fn parse_limits(
+    file: &str,
+    line: usize,
+    fields: &[String],
+    fallback: &DurationLimits,
+) -> LldResult<DurationLimits> {
+    let limits = DurationLimits {
+        min: parse_field(file, line, &fields[1], fallback.min)?,
+        max: parse_field(file, line, &fields[2], fallback.max)?,
+        default: parse_field(file, line, &fields[3], fallback.default)?,
+    };
+    check_limits(&fields[0], &limits)?;
+
+    Ok(limits)
+}
+
+fn parse_field(file: &str, line: usize, field: &str, fallback: u64) -> LldResult<u64> {
+    if field.is_empty() {
+        return Ok(fallback);
+    }
+    field.parse().map_err(|_| {
+        LldError::WrappedError(
+            "policy error",
+            format!("{}:{}: invalid number '{}'", file, line, field),
+        )
+    })
+}
+
+fn check_capacity(name: &str, capacity: u64) -> LldResult<()> {
+    if capacity == 0 {
+        return Err(LldError::WrappedError(
+            "capacity policy error",
+            format!("{}: capacity must be at least 1", name),
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_limits(name: &str, limits: &DurationLimits) -> LldResult<()> {
+    if limits.min == 0 || limits.min > limits.max {
+        return Err(LldError::WrappedError(
+            "duration policy error",
+            format!("{}: invalid range {}..={} ms", name, limits.min, limits.max),
+        ));
+    }
+
+    if limits.default < limits.min || limits.default > limits.max {
+        return Err(LldError::WrappedError(
+            "duration policy error",
+            format!(
+                "{}: default of {} ms is outside of {}..={} ms",
+                name, limits.default, limits.min, limits.max
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::ApiOptions;
+
+    const GLOBAL: DurationLimits = DurationLimits {
+        min: 100,
+        max: 10_000,
+        default: 1000,
+    };
+
+    /// A csv file of its own with `content`, `name` keeps the files of tests apart.
+    fn file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lld-{}-{}.csv", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn id(application_id: &str) -> LeasingId {
+        LeasingId::new("default", application_id)
+    }
+
+    #[test]
+    fn durations_outside_of_the_limits_are_clamped_or_rejected() {
+        let clamp = DurationPolicy::new(DurationMode::Clamp, GLOBAL).unwrap();
+        assert_eq!(clamp.effective_duration(&id("a"), Some(500)), Ok(500));
+        assert_eq!(clamp.effective_duration(&id("a"), Some(10)), Ok(100));
+        assert_eq!(clamp.effective_duration(&id("a"), Some(20_000)), Ok(10_000));
+
+        let reject = DurationPolicy::new(DurationMode::Reject, GLOBAL).unwrap();
+        assert_eq!(reject.effective_duration(&id("a"), Some(500)), Ok(500));
+        assert_eq!(reject.effective_duration(&id("a"), Some(100)), Ok(100));
+        assert!(reject.effective_duration(&id("a"), Some(10)).is_err());
+        assert!(reject.effective_duration(&id("a"), Some(20_000)).is_err());
+    }
+
+    #[test]
+    fn requests_without_a_duration_get_the_default() {
+        let policy = DurationPolicy::new(DurationMode::Reject, GLOBAL).unwrap();
+        assert_eq!(policy.effective_duration(&id("a"), None), Ok(1000));
+    }
+
+    #[test]
+    fn applications_override_the_limits_of_their_namespace() {
+        let mut policy = DurationPolicy::new(DurationMode::Clamp, GLOBAL).unwrap();
+        policy
+            .load_namespaces(&file("policy-namespaces", "default,200,,2000\n"))
+            .unwrap();
+        policy
+            .load_applications(
+                &file(
+                    "policy-applications",
+                    "# application,min,max,default\n\nshort,,500,300\nlong,1000,,\n",
+                ),
+                "default",
+            )
+            .unwrap();
+
+        assert_eq!(policy.effective_duration(&id("other"), None), Ok(2000));
+        assert_eq!(policy.effective_duration(&id("other"), Some(150)), Ok(200));
+        assert_eq!(policy.effective_duration(&id("short"), None), Ok(300));
+        assert_eq!(policy.effective_duration(&id("short"), Some(1000)), Ok(500));
+        // Empty fields fall back to the limits of the namespace
+        assert_eq!(policy.effective_duration(&id("short"), Some(150)), Ok(200));
+        assert_eq!(policy.effective_duration(&id("long"), None), Ok(2000));
+        assert_eq!(policy.effective_duration(&id("long"), Some(500)), Ok(1000));
+
+        // Other namespaces keep the global limits
+        let other = LeasingId::new("other", "short");
+        assert_eq!(policy.effective_duration(&other, None), Ok(1000));
+    }
+
+    #[test]
+    fn invalid_policy_files_are_refused() {
+        let load = |name: &str, content: &str| {
+            let mut policy = DurationPolicy::new(DurationMode::Clamp, GLOBAL).unwrap();
+            policy.load_applications(&file(name, content), "default")
+        };
+
+        assert!(load("policy-valid", "a,100,200,150\n").is_ok());
+        assert!(load("policy-fields", "a,100,200\n").is_err());
+        assert!(load("policy-number", "a,100,ten,150\n").is_err());
+        assert!(load("policy-range", "a,300,200,250\n").is_err());
+        assert!(load("policy-default", "a,100,200,300\n").is_err());
+        assert!(load("policy-zero", "a,0,200,150\n").is_err());
+
+        let mut policy = DurationPolicy::new(DurationMode::Clamp, GLOBAL).unwrap();
+        assert!(policy.load_namespaces("/nonexistent/policy.csv").is_err());
+
+        let mut capacities = CapacityPolicy::new(1).unwrap();
+        assert!(capacities
+            .load_applications(&file("capacity-zero", "a,0\n"), "default")
+            .is_err());
+        assert!(CapacityPolicy::new(0).is_err());
+    }
+
+    #[test]
+    fn capacities_fall_back_to_the_default() {
+        let mut capacities = CapacityPolicy::new(2).unwrap();
+        capacities
+            .load_applications(&file("capacities", "a,5\nb,\n"), "default")
+            .unwrap();
+
+        assert_eq!(capacities.capacity(&id("a")), 5);
+        assert_eq!(capacities.capacity(&id("b")), 2);
+        assert_eq!(capacities.capacity(&id("c")), 2);
+        assert_eq!(capacities.capacity(&LeasingId::new("other", "a")), 2);
+    }
+
+    #[test]
+    fn multi_leasings_get_the_shortest_duration() {
+        let mut policy = DurationPolicy::new(DurationMode::Clamp, GLOBAL).unwrap();
+        policy
+            .load_applications(&file("policy-all", "short,,500,300\n"), "default")
+            .unwrap();
+        let options = ApiOptions {
+            expose_holder: false,
+            duration_policy: Arc::new(policy),
+            max_wait: 0,
+            max_metadata_length: 0,
+            default_namespace: "default".to_owned(),
+            ready_timeout: 0,
+        };
+
+        let ids = [id("a"), id("short"), id("b")];
+        assert_eq!(options.effective_duration_all(&ids, None), Ok(300));
+        assert_eq!(options.effective_duration_all(&ids, Some(2000)), Ok(500));
+        assert_eq!(
+            options.effective_duration_all(&ids[..1], Some(2000)),
+            Ok(2000)
+        );
+        assert!(options.effective_duration_all(&[], Some(2000)).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+
+/// Tells the endpoints to stop and waits until all of them and their connections are done.
+///
+/// Every endpoint and connection holds a `Shutdown`, the controller knows that all of them
+/// finished once every `Shutdown` is dropped.
+#[derive(Debug)]
+pub struct ShutdownController {
+    signal: watch::Sender<bool>,
+    shutdown: Shutdown,
+    done: mpsc::Receiver<()>,
+}
+
+/// A handle on the shutdown signal, which is held as long as its owner has work to finish.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    signal: watch::Receiver<bool>,
+    _done: mpsc::Sender<()>,
+}
+
+impl ShutdownController {
+    pub fn new() -> Self {
+        let (signal, signal_rx) = watch::channel(false);
+        let (done_tx, done) = mpsc::channel(1);
+
+        Self {
+            signal,
+            shutdown: Shutdown {
+                signal: signal_rx,
+                _done: done_tx,
+            },
+            done,
+        }
+    }
+
+    pub fn subscribe(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Tell all endpoints to stop accepting connections and requests.
+    pub fn shutdown(&self) {
+        let _ = self.signal.send(true);
+    }
+
+    /// Wait up to `deadline` for all endpoints and connections to finish, returns whether they
+    /// did in time.
+    pub async fn wait(self, deadline: Duration) -> bool {
+        let Self {
+            shutdown, mut done, ..
+        } = self;
+        drop(shutdown);
+
+        timeout(deadline, done.recv()).await.is_ok()
+    }
+}
+
+impl Shutdown {
+    pub fn is_shutdown(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    /// Wait until the shutdown is signaled.
+    pub async fn recv(&mut self) {
+        while !self.is_shutdown() {
+            if self.signal.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// The signal, which turns `true` once the shutdown starts.
+    pub fn signal(&self) -> watch::Receiver<bool> {
+        self.signal.clone()
+    }
+}
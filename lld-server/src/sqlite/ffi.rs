@@ -1,9 +1,21 @@
-use libc::{c_char, c_int, c_void};
+use libc::{c_char, c_double, c_int, c_uchar, c_void};
 
 pub const SQLITE_OK: c_int = 0;
+pub const SQLITE_ROW: c_int = 100;
+pub const SQLITE_DONE: c_int = 101;
+
+pub const SQLITE_INTEGER: c_int = 1;
+pub const SQLITE_FLOAT: c_int = 2;
+pub const SQLITE_TEXT: c_int = 3;
+pub const SQLITE_NULL: c_int = 5;
+
+/// Destructor of a bound value, which tells sqlite to copy the value.
+pub const SQLITE_TRANSIENT: isize = -1;
 
 pub enum Sqlite3 {}
 
+pub enum Sqlite3Stmt {}
+
 pub type Sqlite3ExecCallback =
     extern "C" fn(*mut c_void, c_int, *mut *mut c_char, *mut *mut c_char) -> c_int;
 
@@ -26,4 +38,54 @@ extern "C" {
         arg: *mut c_void,
         errmsg: *mut *mut c_char,
     ) -> c_int;
+
+    pub fn sqlite3_errmsg(sqlite3: *mut Sqlite3) -> *const c_char;
+
+    pub fn sqlite3_busy_timeout(sqlite3: *mut Sqlite3, ms: c_int) -> c_int;
+
+    pub fn sqlite3_prepare_v2(
+        sqlite3: *mut Sqlite3,
+        sql: *const c_char,
+        n_byte: c_int,
+        pp_stmt: *mut *mut Sqlite3Stmt,
+        pz_tail: *mut *const c_char,
+    ) -> c_int;
+
+    pub fn sqlite3_finalize(stmt: *mut Sqlite3Stmt) -> c_int;
+
+    pub fn sqlite3_reset(stmt: *mut Sqlite3Stmt) -> c_int;
+
+    pub fn sqlite3_clear_bindings(stmt: *mut Sqlite3Stmt) -> c_int;
+
+    pub fn sqlite3_step(stmt: *mut Sqlite3Stmt) -> c_int;
+
+    pub fn sqlite3_bind_parameter_count(stmt: *mut Sqlite3Stmt) -> c_int;
+
+    pub fn sqlite3_bind_int64(stmt: *mut Sqlite3Stmt, index: c_int, value: i64) -> c_int;
+
+    pub fn sqlite3_bind_double(stmt: *mut Sqlite3Stmt, index: c_int, value: c_double) -> c_int;
+
+    pub fn sqlite3_bind_text(
+        stmt: *mut Sqlite3Stmt,
+        index: c_int,
+        value: *const c_char,
+        n: c_int,
+        destructor: isize,
+    ) -> c_int;
+
+    pub fn sqlite3_bind_null(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
+
+    pub fn sqlite3_column_count(stmt: *mut Sqlite3Stmt) -> c_int;
+
+    pub fn sqlite3_column_name(stmt: *mut Sqlite3Stmt, index: c_int) -> *const c_char;
+
+    pub fn sqlite3_column_type(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
+
+    pub fn sqlite3_column_int64(stmt: *mut Sqlite3Stmt, index: c_int) -> i64;
+
+    pub fn sqlite3_column_double(stmt: *mut Sqlite3Stmt, index: c_int) -> c_double;
+
+    pub fn sqlite3_column_text(stmt: *mut Sqlite3Stmt, index: c_int) -> *const c_uchar;
+
+    pub fn sqlite3_column_bytes(stmt: *mut Sqlite3Stmt, index: c_int) -> c_int;
 }
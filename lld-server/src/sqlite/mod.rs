@@ -2,6 +2,9 @@ mod ffi;
 
 use libc::{c_char, c_int, c_void};
 use lld_common::{LldError, LldResult};
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::path::Path;
 
@@ -18,7 +21,7 @@ macro_rules! error(
     ($connection:expr, $code:expr) => (
         return Err(LldError::DatabaseError {
             code: Some($code as isize),
-            message: None,
+            message: last_error($connection),
         })
     );
 );
@@ -68,12 +71,28 @@ macro_rules! ok(
     );
 );
 
+/// A value bound to a parameter of a prepared statement or read from a column of a row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Integer(i64),
+    Float(f64),
+    Text(&'a str),
+    Null,
+}
+
 /// A database connection.
 pub struct Connection {
     raw: *mut ffi::Sqlite3,
+    /// Prepared statements by their sql, which are reset after every execution.
+    statements: RefCell<HashMap<&'static str, Statement>>,
     phantom: PhantomData<ffi::Sqlite3>,
 }
 
+/// A compiled statement, which is finalized when dropped.
+struct Statement {
+    raw: *mut ffi::Sqlite3Stmt,
+}
+
 unsafe impl Send for Connection {}
 
 impl Connection {
@@ -100,6 +119,7 @@ impl Connection {
         }
         Ok(Connection {
             raw,
+            statements: RefCell::new(HashMap::new()),
             phantom: PhantomData,
         })
     }
@@ -132,6 +152,17 @@ impl Connection {
         Ok(())
     }
 
+    /// Wait up to `timeout` ms for a lock on the database instead of failing right away.
+    pub fn set_busy_timeout(&self, timeout: u64) -> LldResult<()> {
+        unsafe {
+            ok!(
+                self.raw,
+                ffi::sqlite3_busy_timeout(self.raw, timeout.min(c_int::MAX as u64) as c_int)
+            );
+        }
+        Ok(())
+    }
+
     /// Execute a statement without processing the resulting rows if any.
     #[inline]
     pub fn execute<T: AsRef<str>>(&self, statement: T) -> LldResult<()> {
@@ -175,16 +206,178 @@ impl Connection {
         }
         Ok(())
     }
+
+    /// Execute a prepared statement with `values` bound to its parameters.
+    #[inline]
+    pub fn execute_prepared(&self, statement: &'static str, values: &[Value]) -> LldResult<()> {
+        self.iterate_prepared(statement, values, |_| true)
+    }
+
+    /// Execute a prepared statement with `values` bound to its parameters and process the
+    /// resulting rows with typed values.
+    ///
+    /// The statement is compiled at its first execution and cached afterwards. Only static sql
+    /// is accepted, so client data can only reach the database as a bound value. The callback
+    /// is triggered for each row. If the callback returns `false`, no more rows will be
+    /// processed.
+    pub fn iterate_prepared<F>(
+        &self,
+        statement: &'static str,
+        values: &[Value],
+        mut callback: F,
+    ) -> LldResult<()>
+    where
+        F: FnMut(&[(&str, Value)]) -> bool,
+    {
+        let mut statements = self.statements.borrow_mut();
+        let prepared = match statements.entry(statement) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(self.prepare(statement)?),
+        };
+
+        let result = self.run(prepared, values, &mut callback);
+        unsafe {
+            // The error of a failed step is returned again by the reset
+            ffi::sqlite3_reset(prepared.raw);
+            ffi::sqlite3_clear_bindings(prepared.raw);
+        }
+        result
+    }
+
+    fn prepare(&self, statement: &str) -> LldResult<Statement> {
+        let mut raw = std::ptr::null_mut();
+        unsafe {
+            ok!(
+                self.raw,
+                ffi::sqlite3_prepare_v2(
+                    self.raw,
+                    str_to_cstr!(statement).as_ptr(),
+                    -1,
+                    &mut raw,
+                    std::ptr::null_mut(),
+                )
+            );
+        }
+        Ok(Statement { raw })
+    }
+
+    fn run<F>(&self, statement: &Statement, values: &[Value], callback: &mut F) -> LldResult<()>
+    where
+        F: FnMut(&[(&str, Value)]) -> bool,
+    {
+        unsafe {
+            let parameters = ffi::sqlite3_bind_parameter_count(statement.raw) as usize;
+            if parameters != values.len() {
+                raise!(format!(
+                    "statement has {} parameters, but {} values were bound",
+                    parameters,
+                    values.len()
+                ));
+            }
+            for (i, value) in values.iter().enumerate() {
+                ok!(self.raw, statement.bind(i as c_int + 1, value));
+            }
+
+            let columns = ffi::sqlite3_column_count(statement.raw);
+            loop {
+                match ffi::sqlite3_step(statement.raw) {
+                    ffi::SQLITE_ROW => {
+                        let mut row = Vec::with_capacity(columns as usize);
+                        for i in 0..columns {
+                            row.push((statement.column_name(i)?, statement.read(i)?));
+                        }
+                        if !callback(&row) {
+                            break;
+                        }
+                    }
+                    ffi::SQLITE_DONE => break,
+                    code => error!(self.raw, code),
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Drop for Connection {
     #[inline]
     #[allow(unused_must_use)]
     fn drop(&mut self) {
+        // Open statements keep the connection from closing
+        self.statements.get_mut().clear();
         unsafe { ffi::sqlite3_close(self.raw) };
     }
 }
 
+impl Statement {
+    unsafe fn bind(&self, index: c_int, value: &Value) -> c_int {
+        match value {
+            Value::Integer(value) => ffi::sqlite3_bind_int64(self.raw, index, *value),
+            Value::Float(value) => ffi::sqlite3_bind_double(self.raw, index, *value),
+            Value::Text(value) => ffi::sqlite3_bind_text(
+                self.raw,
+                index,
+                value.as_ptr() as *const c_char,
+                value.len() as c_int,
+                ffi::SQLITE_TRANSIENT,
+            ),
+            Value::Null => ffi::sqlite3_bind_null(self.raw, index),
+        }
+    }
+
+    unsafe fn column_name(&self, index: c_int) -> LldResult<&str> {
+        let pointer = ffi::sqlite3_column_name(self.raw, index);
+        if pointer.is_null() {
+            raise!("failed to read a column name");
+        }
+        match c_str_to_str!(pointer) {
+            Ok(name) => Ok(name),
+            _ => raise!("failed to read a column name"),
+        }
+    }
+
+    /// The value of a column of the current row, which is valid until the next step.
+    unsafe fn read(&self, index: c_int) -> LldResult<Value<'_>> {
+        Ok(match ffi::sqlite3_column_type(self.raw, index) {
+            ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_column_int64(self.raw, index)),
+            ffi::SQLITE_FLOAT => Value::Float(ffi::sqlite3_column_double(self.raw, index)),
+            ffi::SQLITE_TEXT => {
+                let pointer = ffi::sqlite3_column_text(self.raw, index);
+                let length = ffi::sqlite3_column_bytes(self.raw, index) as usize;
+                if pointer.is_null() || length == 0 {
+                    return Ok(Value::Text(""));
+                }
+                match std::str::from_utf8(std::slice::from_raw_parts(pointer, length)) {
+                    Ok(text) => Value::Text(text),
+                    _ => raise!("failed to read a text column"),
+                }
+            }
+            ffi::SQLITE_NULL => Value::Null,
+            _ => raise!("failed to read a blob column"),
+        })
+    }
+}
+
+impl Drop for Statement {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { ffi::sqlite3_finalize(self.raw) };
+    }
+}
+
+/// The message of the last failed call on the connection.
+fn last_error(raw: *mut ffi::Sqlite3) -> Option<String> {
+    unsafe {
+        let pointer = ffi::sqlite3_errmsg(raw);
+        if pointer.is_null() {
+            return None;
+        }
+        c_str_to_str!(pointer)
+            .ok()
+            .map(|message| message.to_owned())
+    }
+}
+
 extern "C" fn process_callback<F>(
     callback: *mut c_void,
     count: c_int,
@@ -1,84 +1,560 @@
-use std::pin::Pin;
-use std::{net::SocketAddr, time::Instant};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 
-use lld_common::unpack_tcp_packet;
-use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use lld_common::{
+    get_current_time, read_tcp_request, LeasingEvent, LldError, TcpRequest, TcpResponse,
+    WatchFilter, TCP_DURATION_DEFAULT, TCP_PROTOCOL_VERSION,
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, Semaphore};
 use tokio::task;
-use tokio_openssl::SslStream;
 
-use crate::context::{Context, LeasingResponse};
-use crate::SslContext;
+use crate::auth::Caller;
+use crate::cache::LeasingId;
+use crate::context::{Context, LeasingResponse, MultiLeasingResponse};
+use crate::metrics::Protocol;
+use crate::shutdown::Shutdown;
+use crate::{accept_ssl, ApiOptions, SslContext};
 
-pub async fn start_server(context: Context, port: u16, ssl_context: Option<SslContext>) {
+/// Requests of a connection that are answered at the same time, further requests are read once
+/// one of them is answered.
+const MAX_REQUESTS_IN_FLIGHT: usize = 64;
+
+/// Responses of a connection that wait to be written, requests and events wait for a free slot.
+const MAX_PENDING_RESPONSES: usize = 256;
+
+/// Subscriptions of a connection, further ones are refused.
+const MAX_SUBSCRIPTIONS: usize = 16;
+
+/// Requests of a connection that wait for a leasing at the same time, further ones are refused.
+/// They do not take the slots of other requests, as they may wait for as long as `max_wait`.
+const MAX_WAITING_REQUESTS: usize = 16;
+
+pub async fn start_server(
+    context: Context,
+    options: ApiOptions,
+    port: u16,
+    ssl_context: Option<SslContext>,
+    mut shutdown: Shutdown,
+) {
     let listener = TcpListener::bind(SocketAddr::new("0.0.0.0".parse().unwrap(), port))
         .await
         .unwrap();
+    let acceptor = ssl_context.map(|ssl_context| ssl_context.acceptor().unwrap());
 
     info!("Start tcp server at 0.0.0.0:{}", port);
     loop {
-        let (socket, addr) = listener.accept().await.unwrap();
+        let (socket, addr) = tokio::select! {
+            connection = listener.accept() => connection.unwrap(),
+            _ = shutdown.recv() => break,
+        };
+        let socket_context = context.clone();
+        let socket_options = options.clone();
+        let socket_shutdown = shutdown.clone();
 
-        if let Some(ref ssl_context) = ssl_context {
-            let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
-            acceptor
-                .set_private_key_file(&ssl_context.key_file, SslFiletype::PEM)
-                .unwrap();
-            acceptor
-                .set_certificate_chain_file(&ssl_context.cert_file)
-                .unwrap();
-            acceptor.check_private_key().unwrap();
-            let acceptor = acceptor.build();
-
-            let ssl = Ssl::new(acceptor.context()).unwrap();
-            let mut stream = SslStream::new(ssl, socket).unwrap();
-
-            Pin::new(&mut stream).accept().await.unwrap();
-
-            let socket_context = context.clone();
+        if let Some(ref acceptor) = acceptor {
+            let acceptor = acceptor.clone();
+            let metrics = context.metrics().clone();
             task::spawn(async move {
-                process_socket_request(stream, addr, socket_context).await;
+                let (stream, certificate_names) = match accept_ssl(&acceptor, socket).await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        warn!("Ssl handshake with {} failed: {:?}", addr, e);
+                        metrics.count_tls_handshake_failure(Protocol::Tcp);
+                        return;
+                    }
+                };
+                process_socket_request(
+                    stream,
+                    addr,
+                    certificate_names,
+                    socket_context,
+                    socket_options,
+                    socket_shutdown,
+                )
+                .await;
             });
         } else {
-            let socket_context = context.clone();
             task::spawn(async move {
-                process_socket_request(socket, addr, socket_context).await;
+                process_socket_request(
+                    socket,
+                    addr,
+                    Vec::new(),
+                    socket_context,
+                    socket_options,
+                    socket_shutdown,
+                )
+                .await;
             });
         }
     }
+
+    info!("Stop tcp server at 0.0.0.0:{}", port);
 }
 
-async fn process_socket_request<T>(mut socket: T, addr: SocketAddr, context: Context)
-where
-    T: AsyncRead + AsyncWrite + Unpin,
+/// Answer the requests of a connection until the client closes it or the server shuts down.
+/// All pending responses are written before the connection is closed.
+async fn process_socket_request<T>(
+    socket: T,
+    addr: SocketAddr,
+    certificate_names: Vec<String>,
+    context: Context,
+    options: ApiOptions,
+    mut shutdown: Shutdown,
+) where
+    T: AsyncRead + AsyncWrite + Send + 'static,
 {
-    let start = Instant::now();
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MAX_PENDING_RESPONSES);
+    let in_flight = Arc::new(Semaphore::new(MAX_REQUESTS_IN_FLIGHT));
+    let waiting = Arc::new(Semaphore::new(MAX_WAITING_REQUESTS));
+
+    // Responses are written in completion order, clients match them by request id
+    let writer_task = task::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            if let Err(e) = writer.write_all(&packet).await {
+                error!("Cannot send tcp response: {:?}", e);
+                break;
+            }
+        }
+    });
+
+    let mut subscriptions = Vec::new();
+
+    loop {
+        let read = tokio::select! {
+            read = read_tcp_request(&mut reader) => read,
+            _ = shutdown.recv() => break,
+        };
+        let (header, request) = match read {
+            Ok(Some(result)) => result,
+            Ok(None) => break,
+            Err(e) => {
+                error!("Cannot receive tcp request: {:?}", e);
+                break;
+            }
+        };
+        let start = Instant::now();
+
+        if let Ok(TcpRequest::Subscribe {
+            filter,
+            namespace,
+            api_key,
+        }) = request
+        {
+            if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                let response = TcpResponse::Invalid(format!(
+                    "A connection may hold at most {} subscriptions",
+                    MAX_SUBSCRIPTIONS
+                ));
+                let _ = tx
+                    .send(pack(&response, header.version, header.request_id))
+                    .await;
+                continue;
+            }
+
+            let namespace = options.namespace(namespace);
+            info!("{} subscribe {:?} in {}", addr, filter, namespace);
+            let receiver = match context.watch_leasings(
+                &Caller::new(api_key, &certificate_names),
+                &namespace,
+                &filter,
+            ) {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    let response = to_error_response(e);
+                    let _ = tx
+                        .send(pack(&response, header.version, header.request_id))
+                        .await;
+                    continue;
+                }
+            };
+            subscriptions.push(task::spawn(stream_events(
+                receiver,
+                filter,
+                namespace,
+                header.request_id,
+                tx.clone(),
+            )));
+            continue;
+        }
 
-    let mut packet = [0u8; 24];
-    if let Err(e) = socket.read_exact(&mut packet).await {
-        error!("Cannot receive tcp request: {:?}", e);
+        let permit = if let Ok(TcpRequest::AcquireWait { .. }) = request {
+            match waiting.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let response = TcpResponse::Invalid(format!(
+                        "A connection may wait for at most {} leasings at the same time",
+                        MAX_WAITING_REQUESTS
+                    ));
+                    let _ = tx
+                        .send(pack(&response, header.version, header.request_id))
+                        .await;
+                    continue;
+                }
+            }
+        } else {
+            // Stop reading until a request is answered, instead of piling up tasks
+            match in_flight.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => break,
+            }
+        };
+        let request_context = context.clone();
+        let request_options = options.clone();
+        let request_tx = tx.clone();
+        let request_certificate_names = certificate_names.clone();
+        task::spawn(async move {
+            let response = match request {
+                Ok(request) => {
+                    process_request(
+                        &request_context,
+                        &request_options,
+                        &request_certificate_names,
+                        request,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    error!("Cannot decode tcp request: {:?}", e);
+                    TcpResponse::Error
+                }
+            };
+
+            let duration = start.elapsed();
+            info!("{} {:?} {}ms", addr, response, duration.as_millis());
+
+            let _ = request_tx
+                .send(pack(&response, header.version, header.request_id))
+                .await;
+            drop(permit);
+        });
+
+        // Legacy clients send a single request per connection
+        if header.version == 0 {
+            break;
+        }
+    }
+
+    for subscription in subscriptions {
+        subscription.abort();
+    }
+
+    drop(tx);
+    let _ = writer_task.await;
+}
+
+/// Encode a response, one that does not fit into a frame is answered with an error instead.
+fn pack(response: &TcpResponse, version: u8, request_id: u32) -> Vec<u8> {
+    response.pack(version, request_id).unwrap_or_else(|e| {
+        error!("Cannot encode tcp response: {:?}", e);
+        TcpResponse::Error
+            .pack(version, request_id)
+            .expect("Cannot encode an error response!")
+    })
+}
+
+async fn stream_events(
+    mut receiver: broadcast::Receiver<LeasingEvent>,
+    filter: WatchFilter,
+    namespace: String,
+    request_id: u32,
+    tx: mpsc::Sender<Vec<u8>>,
+) {
+    let packet = pack(&TcpResponse::Subscribed, TCP_PROTOCOL_VERSION, request_id);
+    if tx.send(packet).await.is_err() {
         return;
     }
-    let (application_id, instance_id, duration) = unpack_tcp_packet(packet);
 
-    let response = context
-        .request_leasing(application_id.clone(), instance_id.clone(), duration)
-        .await;
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            // The watcher cannot tell which leasings changed, so it has to start over
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Watcher missed {} leasing events", missed);
+                let packet = pack(
+                    &TcpResponse::Lagged { missed },
+                    TCP_PROTOCOL_VERSION,
+                    request_id,
+                );
+                let _ = tx.send(packet).await;
+                return;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
 
-    let duration = start.elapsed();
-    info!("{} {:?} {}ms", addr, response, duration.as_millis());
+        if event.namespace != namespace || !filter.matches(&event.application_id) {
+            continue;
+        }
 
-    let send_result = match response {
-        Ok(LeasingResponse::Granted { .. }) => socket.write_u8(48).await,
-        Ok(LeasingResponse::Rejected) => socket.write_u8(49).await,
-        Err(e) => {
-            error!("Error while waiting for database result {:?}", e);
-            socket.write_u8(50).await
+        let packet = pack(&TcpResponse::Event(event), TCP_PROTOCOL_VERSION, request_id);
+        if tx.send(packet).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn process_request(
+    context: &Context,
+    options: &ApiOptions,
+    certificate_names: &[String],
+    request: TcpRequest,
+) -> TcpResponse {
+    let (response, duration) = match request {
+        TcpRequest::Acquire {
+            application_id,
+            instance_id,
+            duration,
+            priority,
+            metadata,
+            namespace,
+            api_key,
+        } => {
+            let id = options.leasing_id(namespace, application_id);
+            let duration = match options.effective_duration(&id, requested(duration)) {
+                Ok(duration) => duration,
+                Err(reason) => {
+                    context.metrics().count_invalid(Protocol::Tcp);
+                    return TcpResponse::Invalid(reason);
+                }
+            };
+            if let Err(reason) = options.check_metadata(metadata.as_deref()) {
+                context.metrics().count_invalid(Protocol::Tcp);
+                return TcpResponse::Invalid(reason);
+            }
+            let response = context
+                .request_leasing(
+                    &Caller::new(api_key, certificate_names),
+                    id,
+                    instance_id,
+                    duration,
+                    priority,
+                    metadata,
+                )
+                .await;
+            (response, duration)
+        }
+        TcpRequest::Renew {
+            application_id,
+            instance_id,
+            duration,
+            namespace,
+            api_key,
+        } => {
+            let id = options.leasing_id(namespace, application_id);
+            let duration = match options.effective_duration(&id, requested(duration)) {
+                Ok(duration) => duration,
+                Err(reason) => {
+                    context.metrics().count_invalid(Protocol::Tcp);
+                    return TcpResponse::Invalid(reason);
+                }
+            };
+            let response = context
+                .renew_leasing(
+                    &Caller::new(api_key, certificate_names),
+                    id,
+                    instance_id,
+                    duration,
+                )
+                .await;
+            (response, duration)
+        }
+        TcpRequest::AcquireWait {
+            application_id,
+            instance_id,
+            duration,
+            timeout,
+            priority,
+            metadata,
+            namespace,
+            api_key,
+        } => {
+            let id = options.leasing_id(namespace, application_id);
+            let duration = match options.effective_duration(&id, requested(duration)) {
+                Ok(duration) => duration,
+                Err(reason) => {
+                    context.metrics().count_invalid(Protocol::Tcp);
+                    return TcpResponse::Invalid(reason);
+                }
+            };
+            if let Err(reason) = options.check_metadata(metadata.as_deref()) {
+                context.metrics().count_invalid(Protocol::Tcp);
+                return TcpResponse::Invalid(reason);
+            }
+            let response = context
+                .wait_for_leasing(
+                    &Caller::new(api_key, certificate_names),
+                    id,
+                    instance_id,
+                    duration,
+                    priority,
+                    metadata,
+                    timeout.min(options.max_wait),
+                )
+                .await;
+            (response, duration)
+        }
+        TcpRequest::Release {
+            application_id,
+            instance_id,
+            namespace,
+            api_key,
+        } => {
+            let id = options.leasing_id(namespace, application_id);
+            (
+                context
+                    .release_leasing(&Caller::new(api_key, certificate_names), id, instance_id)
+                    .await,
+                0,
+            )
+        }
+        TcpRequest::AcquireAll {
+            application_ids,
+            instance_id,
+            duration,
+            namespace,
+            api_key,
+        } => {
+            let namespace = options.namespace(namespace);
+            let ids: Vec<LeasingId> = application_ids
+                .into_iter()
+                .map(|application_id| LeasingId {
+                    namespace: namespace.clone(),
+                    application_id,
+                })
+                .collect();
+            let duration = match options.effective_duration_all(&ids, requested(duration)) {
+                Ok(duration) => duration,
+                Err(reason) => {
+                    context.metrics().count_invalid(Protocol::Tcp);
+                    return TcpResponse::Invalid(reason);
+                }
+            };
+            let response = context
+                .request_leasings(
+                    &Caller::new(api_key, certificate_names),
+                    ids,
+                    instance_id,
+                    duration,
+                )
+                .await;
+            context
+                .metrics()
+                .count_multi_leasing(Protocol::Tcp, &response);
+            return match response {
+                Ok(MultiLeasingResponse::Granted { validity, tokens }) => TcpResponse::GrantedAll {
+                    validity,
+                    remaining: validity.saturating_sub(get_current_time()),
+                    duration,
+                    tokens,
+                },
+                Ok(MultiLeasingResponse::Rejected {
+                    application_id,
+                    rejection,
+                }) => TcpResponse::RejectedAll {
+                    application_id,
+                    rejection: options.filter_rejection(rejection),
+                },
+                Ok(MultiLeasingResponse::Unavailable { retry_after }) => {
+                    TcpResponse::Unavailable { retry_after }
+                }
+                Err(e) => to_error_response(e),
+            };
+        }
+        TcpRequest::Subscribe { .. } => {
+            error!("Subscriptions are handled by the connection");
+            return TcpResponse::Error;
+        }
+        TcpRequest::Status {
+            application_id,
+            namespace,
+            api_key,
+        } => {
+            let id = options.leasing_id(namespace, application_id);
+            return match context
+                .query_leasing(&Caller::new(api_key, certificate_names), id)
+                .await
+            {
+                Ok(Some(status)) => TcpResponse::Leased(status),
+                Ok(None) => TcpResponse::Free,
+                Err(e) => to_error_response(e),
+            };
         }
     };
+    context.metrics().count_leasing(Protocol::Tcp, &response);
+
+    match response {
+        Ok(LeasingResponse::Granted { validity, token }) => TcpResponse::Granted {
+            validity,
+            remaining: validity.saturating_sub(get_current_time()),
+            token,
+            duration,
+        },
+        Ok(LeasingResponse::Released) => TcpResponse::Released,
+        Ok(LeasingResponse::Preempted) => TcpResponse::Preempted,
+        Ok(LeasingResponse::Unavailable { retry_after }) => {
+            TcpResponse::Unavailable { retry_after }
+        }
+        Ok(LeasingResponse::Rejected(rejection)) => {
+            TcpResponse::Rejected(options.filter_rejection(rejection))
+        }
+        Err(e) => to_error_response(e),
+    }
+}
 
-    if let Err(e) = send_result {
-        error!("Cannot send tcp response: {:?}", e);
+fn to_error_response(error: LldError) -> TcpResponse {
+    match error {
+        LldError::Unauthorized(reason) => TcpResponse::Unauthorized(reason),
+        e => {
+            error!("Error while processing the request {:?}", e);
+            TcpResponse::Error
+        }
+    }
+}
+
+fn requested(duration: u64) -> Option<u64> {
+    if duration == TCP_DURATION_DEFAULT {
+        None
+    } else {
+        Some(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lld_common::{read_tcp_response, LeasingEventKind};
+
+    fn event(application_id: &str) -> LeasingEvent {
+        LeasingEvent {
+            kind: LeasingEventKind::Granted,
+            namespace: "default".to_owned(),
+            application_id: application_id.to_owned(),
+            instance_id: "i".to_owned(),
+            validity: 2000,
+            token: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_watchers_are_told_and_unsubscribed() {
+        let (events, receiver) = broadcast::channel(2);
+        for application_id in ["a", "b", "c", "d"] {
+            events.send(event(application_id)).unwrap();
+        }
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let filter = WatchFilter::Prefix(String::new());
+        stream_events(receiver, filter, "default".to_owned(), 7, tx).await;
+
+        let mut responses = Vec::new();
+        while let Some(packet) = rx.recv().await {
+            let (header, response) = read_tcp_response(&mut packet.as_slice()).await.unwrap();
+            assert_eq!(header.request_id, 7);
+            responses.push(response);
+        }
+        assert_eq!(responses.len(), 2);
+        assert!(matches!(responses[0], TcpResponse::Subscribed));
+        assert!(matches!(responses[1], TcpResponse::Lagged { missed: 2 }));
     }
 }
@@ -0,0 +1,184 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::cache::LeasingId;
+
+#[derive(Debug)]
+struct Waiter {
+    id: u64,
+    notify: Notify,
+}
+
+type WaiterMap = HashMap<LeasingId, VecDeque<Arc<Waiter>>>;
+
+/// First-come-first-served queues of instances waiting for a leasing, per application.
+///
+/// Only the head of a queue competes for the leasing, all other waiters sleep until they are
+/// notified that they moved to the head.
+#[derive(Debug, Clone, Default)]
+pub struct WaitQueue {
+    waiters: Arc<Mutex<WaiterMap>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// A place in the wait queue of an application, which is left when the ticket is dropped.
+#[derive(Debug)]
+pub struct WaitTicket {
+    queue: WaitQueue,
+    id: LeasingId,
+    waiter: Arc<Waiter>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enter(&self, id: &LeasingId) -> WaitTicket {
+        let waiter = Arc::new(Waiter {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            notify: Notify::new(),
+        });
+
+        self.waiters
+            .lock()
+            .unwrap()
+            .entry(id.clone())
+            .or_default()
+            .push_back(waiter.clone());
+
+        WaitTicket {
+            queue: self.clone(),
+            id: id.clone(),
+            waiter,
+        }
+    }
+
+    /// Wake up the head of the queue, e.g. after the leasing was released.
+    pub fn notify(&self, id: &LeasingId) {
+        let waiters = self.waiters.lock().unwrap();
+        if let Some(head) = waiters.get(id).and_then(VecDeque::front) {
+            head.notify.notify_one();
+        }
+    }
+
+    /// Wake up the heads of all queues of a namespace, e.g. after it was deleted.
+    pub fn notify_namespace(&self, namespace: &str) {
+        let waiters = self.waiters.lock().unwrap();
+        for (_, queue) in waiters.iter().filter(|(id, _)| id.namespace == namespace) {
+            if let Some(head) = queue.front() {
+                head.notify.notify_one();
+            }
+        }
+    }
+
+    fn leave(&self, leasing_id: &LeasingId, id: u64) {
+        let mut waiters = self.waiters.lock().unwrap();
+
+        if let Some(queue) = waiters.get_mut(leasing_id) {
+            let was_head = queue.front().map(|head| head.id) == Some(id);
+            queue.retain(|waiter| waiter.id != id);
+
+            if queue.is_empty() {
+                waiters.remove(leasing_id);
+            } else if was_head {
+                if let Some(head) = queue.front() {
+                    head.notify.notify_one();
+                }
+            }
+        }
+    }
+}
+
+impl WaitTicket {
+    pub fn is_head(&self) -> bool {
+        let waiters = self.queue.waiters.lock().unwrap();
+        waiters
+            .get(&self.id)
+            .and_then(VecDeque::front)
+            .map(|head| head.id)
+            == Some(self.waiter.id)
+    }
+
+    /// Wait until the ticket is notified. A notification sent before this call is not lost.
+    pub async fn notified(&self) {
+        self.waiter.notify.notified().await
+    }
+}
+
+impl Drop for WaitTicket {
+    fn drop(&mut self) {
+        self.queue.leave(&self.id, self.waiter.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn id() -> LeasingId {
+        LeasingId::new("default", "a")
+    }
+
+    #[tokio::test]
+    async fn waiters_move_to_the_head_in_order_of_arrival() {
+        let queue = WaitQueue::new();
+        let first = queue.enter(&id());
+        let second = queue.enter(&id());
+        let third = queue.enter(&id());
+        let other = queue.enter(&LeasingId::new("default", "b"));
+
+        assert!(first.is_head());
+        assert!(!second.is_head());
+        assert!(!third.is_head());
+        assert!(other.is_head());
+
+        drop(first);
+        assert!(second.is_head());
+        assert!(!third.is_head());
+        // The new head is woken up when the former head leaves
+        timeout(Duration::from_secs(1), second.notified())
+            .await
+            .unwrap();
+
+        drop(second);
+        assert!(third.is_head());
+    }
+
+    #[tokio::test]
+    async fn notifications_wake_up_the_head_only() {
+        let queue = WaitQueue::new();
+        let first = queue.enter(&id());
+        let second = queue.enter(&id());
+
+        queue.notify(&id());
+        timeout(Duration::from_secs(1), first.notified())
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_millis(50), second.notified())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn cancelled_waiters_leave_the_queue() {
+        let queue = WaitQueue::new();
+        let first = queue.enter(&id());
+        let second = queue.enter(&id());
+        let third = queue.enter(&id());
+
+        drop(second);
+        drop(first);
+        assert!(third.is_head());
+
+        drop(third);
+        assert!(queue.waiters.lock().unwrap().is_empty());
+    }
+}